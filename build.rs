@@ -0,0 +1,4 @@
+fn main() {
+    // embedded by sqlx::migrate!, rebuild when a migration is added
+    println!("cargo:rerun-if-changed=migrations");
+}
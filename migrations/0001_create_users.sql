@@ -0,0 +1,15 @@
+-- Databases created before migrations existed already have these tables,
+-- so the baseline migrations only add what is missing.
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    username VARCHAR(255) NOT NULL UNIQUE,
+    email VARCHAR(255) NOT NULL UNIQUE,
+    password VARCHAR(255) NOT NULL,
+    verified BOOLEAN NOT NULL DEFAULT FALSE,
+    profile_picture TEXT UNIQUE,
+    biography VARCHAR(200)
+);
+
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS last_seen TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN IF NOT EXISTS session_version INTEGER NOT NULL DEFAULT 0;
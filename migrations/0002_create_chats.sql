@@ -0,0 +1,49 @@
+CREATE TABLE IF NOT EXISTS chats (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100),
+    is_group BOOLEAN NOT NULL DEFAULT FALSE,
+    owner VARCHAR(255) REFERENCES users(username),
+    direct_key VARCHAR(511) UNIQUE,
+    last_update TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE chats
+    ADD COLUMN IF NOT EXISTS name VARCHAR(100),
+    ADD COLUMN IF NOT EXISTS is_group BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN IF NOT EXISTS owner VARCHAR(255) REFERENCES users(username),
+    ADD COLUMN IF NOT EXISTS direct_key VARCHAR(511) UNIQUE;
+
+CREATE TABLE IF NOT EXISTS chat_members (
+    chat_id INTEGER NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    username VARCHAR(255) NOT NULL REFERENCES users(username),
+    joined_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, username)
+);
+
+ALTER TABLE chat_members ADD COLUMN IF NOT EXISTS last_read_message_id INTEGER;
+
+-- chats used to be hard-coded pairs (first_user_name/second_user_name kept
+-- ordered by a trigger), move those into chat_members and drop the old columns
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'chats' AND column_name = 'first_user_name'
+    ) THEN
+        INSERT INTO chat_members (chat_id, username)
+            SELECT id, first_user_name FROM chats
+            UNION
+            SELECT id, second_user_name FROM chats
+        ON CONFLICT DO NOTHING;
+
+        UPDATE chats
+        SET direct_key = LEAST(first_user_name, second_user_name) || ':' || GREATEST(first_user_name, second_user_name)
+        WHERE direct_key IS NULL AND NOT is_group;
+
+        DROP TRIGGER IF EXISTS enforce_chat_order_trigger ON chats;
+        ALTER TABLE chats DROP COLUMN first_user_name, DROP COLUMN second_user_name;
+    END IF;
+END
+$$;
+
+DROP FUNCTION IF EXISTS enforce_chat_order();
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS messages (
+    id SERIAL PRIMARY KEY,
+    chat_id INTEGER NOT NULL REFERENCES chats(id),
+    email VARCHAR(255) NOT NULL REFERENCES users(email),
+    username VARCHAR(255) NOT NULL REFERENCES users(username),
+    message TEXT NOT NULL,
+    replied_user TEXT,
+    replied_message TEXT,
+    time TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    edited BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS messages_chat_id_idx ON messages (chat_id, id);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS friends (
+    id SERIAL PRIMARY KEY,
+    sender_username VARCHAR(255) NOT NULL REFERENCES users(username),
+    receiver_username VARCHAR(255) NOT NULL REFERENCES users(username),
+    status VARCHAR(255) NOT NULL
+);
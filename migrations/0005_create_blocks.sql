@@ -0,0 +1,8 @@
+CREATE TABLE blocks (
+    blocker_username VARCHAR(255) NOT NULL REFERENCES users(username),
+    blocked_username VARCHAR(255) NOT NULL REFERENCES users(username),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (blocker_username, blocked_username)
+);
+
+CREATE INDEX blocks_blocked_username_idx ON blocks (blocked_username);
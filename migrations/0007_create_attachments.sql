@@ -0,0 +1,14 @@
+CREATE TABLE attachments (
+    id SERIAL PRIMARY KEY,
+    -- content address of the file under ./uploads/attachments
+    sha256 CHAR(64) NOT NULL,
+    uploader VARCHAR(255) NOT NULL REFERENCES users(username),
+    filename VARCHAR(255) NOT NULL,
+    mime_type VARCHAR(255) NOT NULL,
+    size BIGINT NOT NULL,
+    -- NULL until the uploader sends a message referencing it
+    message_id INTEGER REFERENCES messages(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX attachments_message_id_idx ON attachments (message_id);
@@ -0,0 +1,6 @@
+-- 'simple' doesn't stem or drop stop words, chats aren't all in one language
+ALTER TABLE messages
+    ADD COLUMN search_vector TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('simple', message)) STORED;
+
+CREATE INDEX messages_search_vector_idx ON messages USING GIN (search_vector);
@@ -0,0 +1,17 @@
+-- one row per login (device), access tokens carry its id
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    username VARCHAR(255) NOT NULL REFERENCES users(username),
+    refresh_token_hash CHAR(64) NOT NULL UNIQUE,
+    -- the token it was rotated from, presenting it again means it was stolen
+    previous_refresh_token_hash CHAR(64),
+    user_agent TEXT,
+    ip VARCHAR(64),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX sessions_username_idx ON sessions (username);
+CREATE INDEX sessions_previous_refresh_token_hash_idx ON sessions (previous_refresh_token_hash);
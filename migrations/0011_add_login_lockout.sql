@@ -0,0 +1,5 @@
+-- consecutive failed logins, past a threshold the account is locked with
+-- an exponential backoff
+ALTER TABLE users
+    ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN locked_until TIMESTAMP WITH TIME ZONE;
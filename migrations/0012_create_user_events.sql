@@ -0,0 +1,14 @@
+-- per user log of the chat events worth replaying to a reconnecting socket,
+-- `seq` counts up per user from users.last_event_seq
+ALTER TABLE users
+    ADD COLUMN last_event_seq BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE user_events (
+    username VARCHAR(255) NOT NULL REFERENCES users(username),
+    seq BIGINT NOT NULL,
+    event JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (username, seq)
+);
+
+CREATE INDEX user_events_created_at_idx ON user_events (created_at);
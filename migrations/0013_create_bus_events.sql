@@ -0,0 +1,9 @@
+-- events published on the postgres bus that don't fit in a NOTIFY payload,
+-- the notification only carries the id, rows are pruned after a minute
+CREATE TABLE bus_events (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX bus_events_created_at_idx ON bus_events (created_at);
@@ -0,0 +1,18 @@
+-- every node checks in here, the rows of a node that stopped doing so are
+-- dropped by the others
+CREATE TABLE nodes (
+    id BIGINT PRIMARY KEY,
+    seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- open chat sessions of each user per node, and how many of them are idle,
+-- a user is online as long as any node counts one
+CREATE TABLE presence_sessions (
+    node_id BIGINT NOT NULL REFERENCES nodes(id) ON DELETE CASCADE,
+    username VARCHAR(255) NOT NULL REFERENCES users(username),
+    sessions INT NOT NULL,
+    away INT NOT NULL,
+    PRIMARY KEY (node_id, username)
+);
+
+CREATE INDEX presence_sessions_username_idx ON presence_sessions (username);
@@ -0,0 +1,103 @@
+use crate::RegexValidator;
+use crate::config;
+use crate::hub::Hub;
+use crate::mailer::MailService;
+use crate::middlewares;
+use crate::rate_limit::{self, RateLimits};
+use crate::routes;
+use crate::routes::chat::AppState;
+use crate::routes::friend::FriendAppState;
+use crate::routes::socket::Event;
+use actix_files as fs;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{App, Error, middleware, web};
+use sqlx::PgPool;
+use std::sync::Arc;
+
+/// State shared by every worker of the server, built once and cloned into
+/// each `App`.
+#[derive(Clone)]
+pub struct AppData {
+    pub pool: PgPool,
+    pub chat_state: Arc<AppState>,
+    pub friend_state: Arc<FriendAppState>,
+    pub regex_validator: RegexValidator,
+    pub rate_limits: web::Data<RateLimits>,
+    pub mail: web::Data<MailService>,
+}
+
+impl AppData {
+    /// `hub` routes to every socket of the process, whatever events it
+    /// carries.
+    pub fn new(
+        pool: PgPool,
+        hub: Arc<Hub<Event>>,
+        rate_limits: RateLimits,
+        mail: MailService,
+    ) -> Self {
+        Self {
+            chat_state: Arc::new(AppState::new(pool.clone(), hub.clone())),
+            friend_state: Arc::new(FriendAppState::new(pool.clone(), hub)),
+            regex_validator: RegexValidator::new(),
+            rate_limits: web::Data::new(rate_limits),
+            mail: web::Data::new(mail),
+            pool,
+        }
+    }
+}
+
+/// The whole application, used by `main` for every worker and by the
+/// integration tests.
+pub fn build_app(
+    data: AppData,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let app = App::new()
+        .app_data(web::Data::new(data.pool))
+        .app_data(web::Data::new(data.chat_state))
+        .app_data(web::Data::new(data.friend_state))
+        .app_data(web::Data::new(data.regex_validator))
+        .app_data(data.rate_limits)
+        .app_data(data.mail)
+        .wrap(middleware::from_fn(rate_limit::limit_auth_requests))
+        .wrap(middlewares::cors(&config::get().cors));
+    app.service(routes::auth::register)
+        .service(routes::auth::login)
+        .service(routes::auth::verify_user)
+        .service(routes::auth::login_two_factor)
+        .service(routes::socket::ws_handler)
+        .service(routes::chat::ws_handler)
+        .service(routes::chat::get_chats)
+        .service(routes::chat::get_chat_reads)
+        .service(routes::chat::get_chat_messages)
+        .service(routes::chat::get_user)
+        .service(routes::presence::get_presence)
+        .service(routes::search::search_messages)
+        .service(routes::friend::ws_handler)
+        .service(routes::friend::get_friend_req)
+        .service(routes::friend::get_blocks)
+        .service(routes::auth::upload_avatar)
+        .service(routes::auth::verify_email)
+        .service(routes::auth::logout)
+        .service(routes::auth::forgot_password)
+        .service(routes::auth::reset_password)
+        .service(routes::sessions::refresh)
+        .service(routes::sessions::get_sessions)
+        .service(routes::sessions::revoke_session)
+        .service(routes::two_factor::setup)
+        .service(routes::two_factor::enable)
+        .service(routes::two_factor::disable)
+        .service(routes::two_factor::regenerate_recovery_codes)
+        .service(routes::attachments::upload_attachment)
+        .service(routes::attachments::get_attachment)
+        .service(routes::auth::get_upload)
+        .service(fs::Files::new("/", "./static").index_file("index.html"))
+}
@@ -0,0 +1,404 @@
+//! Fan-out of hub traffic between kutter nodes.
+//!
+//! Every node routes events to its own sockets through its `Hub`, which also
+//! publishes what it did on the bus. The other nodes replay it on their hub,
+//! so a message sent through one node reaches the members of the chat
+//! connected to any of them.
+
+use crate::config::{BusBackend, BusConfig};
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// Identifies the hub an envelope was published by, it skips its own.
+pub type NodeId = u64;
+
+/// A hub operation, applied as-is by the hub of every node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Broadcast<E> {
+    ToUsers {
+        usernames: Vec<String>,
+        event: E,
+        /// The `seq` each user's copy is stamped with, for logged events.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        seqs: HashMap<String, i64>,
+    },
+    ToChat {
+        chat_id: i32,
+        except: Option<String>,
+        event: E,
+    },
+    AddMember {
+        chat_id: i32,
+        username: String,
+    },
+    RemoveMember {
+        chat_id: i32,
+        username: String,
+    },
+    DisconnectUser {
+        username: String,
+    },
+    DisconnectLogin {
+        username: String,
+        login: i32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<E> {
+    pub origin: NodeId,
+    pub broadcast: Broadcast<E>,
+}
+
+/// What a subscription yields.
+#[derive(Debug)]
+pub enum Delivery<E> {
+    Envelope(Envelope<E>),
+    /// Envelopes were lost on the way, e.g. while the bus reconnected.
+    Lost,
+}
+
+/// Something that carries envelopes to every subscribed hub, the publishing
+/// one included.
+pub trait EventBus<E>: Send + Sync {
+    fn publish<'a>(&'a self, envelope: &'a Envelope<E>) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Commits `tx` and publishes `envelope` with it, nothing is published
+    /// when the commit fails.
+    fn publish_on_commit<'a>(
+        &'a self,
+        tx: Transaction<'static, Postgres>,
+        envelope: &'a Envelope<E>,
+    ) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Every envelope published from now on, until the bus goes away.
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::UnboundedReceiver<Delivery<E>>, String>>;
+
+    /// Ends every subscription and lets go of the connections they hold,
+    /// before the database or the runtime goes away.
+    fn close(&self) -> BoxFuture<'_, ()>;
+}
+
+pub fn from_config<E>(config: &BusConfig, pool: &PgPool) -> Arc<dyn EventBus<E>>
+where
+    E: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    match config.backend {
+        BusBackend::Local => Arc::new(InProcessBus::new()),
+        BusBackend::Postgres => Arc::new(PgEventBus::new(pool.clone())),
+    }
+}
+
+/// Hubs of the same process, a single node unless several hubs share it.
+pub struct InProcessBus<E> {
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Delivery<E>>>>,
+}
+
+impl<E> InProcessBus<E> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<E> Default for InProcessBus<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Clone + Send + Sync> EventBus<E> for InProcessBus<E> {
+    fn publish<'a>(&'a self, envelope: &'a Envelope<E>) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut subscribers = self
+                .subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            subscribers.retain(|tx| tx.send(Delivery::Envelope(envelope.clone())).is_ok());
+            Ok(())
+        })
+    }
+
+    fn publish_on_commit<'a>(
+        &'a self,
+        tx: Transaction<'static, Postgres>,
+        envelope: &'a Envelope<E>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            tx.commit()
+                .await
+                .map_err(|e| format!("Failed to commit event: {}", e))?;
+            self.publish(envelope).await
+        })
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::UnboundedReceiver<Delivery<E>>, String>> {
+        Box::pin(async move {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(tx);
+            Ok(rx)
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        })
+    }
+}
+
+const CHANNEL: &str = "kutter_events";
+
+/// NOTIFY payloads must be shorter than this, bigger envelopes go through
+/// `bus_events` and only their id is sent.
+const MAX_NOTIFY_PAYLOAD: usize = 8000;
+
+/// Every node connected to the same database, over `LISTEN`/`NOTIFY`.
+///
+/// Notifications are delivered in commit order, so envelopes published one
+/// after the other by a node reach the others in that order. Whatever is
+/// published while a listener reconnects is lost to it, its subscriber gets
+/// `Delivery::Lost` once it listens again.
+pub struct PgEventBus<E> {
+    pool: PgPool,
+    // flipped by `close`, the listener tasks stop on it
+    closed: watch::Sender<bool>,
+    listeners: Mutex<Vec<JoinHandle<()>>>,
+    events: PhantomData<fn() -> E>,
+}
+
+impl<E> PgEventBus<E> {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            closed: watch::Sender::new(false),
+            listeners: Mutex::new(Vec::new()),
+            events: PhantomData,
+        }
+    }
+}
+
+impl<E> EventBus<E> for PgEventBus<E>
+where
+    E: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn publish<'a>(&'a self, envelope: &'a Envelope<E>) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut conn = self
+                .pool
+                .acquire()
+                .await
+                .map_err(|e| format!("Failed to publish event: {}", e))?;
+            notify(&mut conn, envelope).await
+        })
+    }
+
+    fn publish_on_commit<'a>(
+        &'a self,
+        mut tx: Transaction<'static, Postgres>,
+        envelope: &'a Envelope<E>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            // NOTIFY is transactional, listeners only get it once `tx`
+            // commits and never when it rolls back
+            notify(&mut tx, envelope).await?;
+            tx.commit()
+                .await
+                .map_err(|e| format!("Failed to commit event: {}", e))
+        })
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::UnboundedReceiver<Delivery<E>>, String>> {
+        Box::pin(async move {
+            let mut listener = PgListener::connect_with(&self.pool)
+                .await
+                .map_err(|e| format!("Failed to connect the event listener: {}", e))?;
+            listener
+                .listen(CHANNEL)
+                .await
+                .map_err(|e| format!("Failed to listen for events: {}", e))?;
+
+            let (tx, rx) = mpsc::unbounded_channel();
+            let pool = self.pool.clone();
+            let mut closed = self.closed.subscribe();
+            let task = actix_rt::spawn(async move {
+                let mut lost = false;
+                loop {
+                    let notification = tokio::select! {
+                        notification = listener.try_recv() => notification,
+                        _ = tx.closed() => break,
+                        _ = closed.changed() => break,
+                    };
+                    let notification = match notification {
+                        Ok(notification) => notification,
+                        Err(sqlx::Error::PoolClosed) => return,
+                        Err(e) => {
+                            // the listener reconnects on the next `try_recv`
+                            eprintln!("Event listener error: {}", e);
+                            lost = true;
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    };
+                    // `None` once a lost connection was set up again
+                    if lost || notification.is_none() {
+                        lost = false;
+                        if tx.send(Delivery::Lost).is_err() {
+                            break;
+                        }
+                    }
+                    let Some(notification) = notification else {
+                        continue;
+                    };
+
+                    let envelope = match read_envelope(&pool, notification.payload()).await {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        }
+                    };
+                    if tx.send(Delivery::Envelope(envelope)).is_err() {
+                        break;
+                    }
+                }
+                // dropped here, sqlx can't release it from a runtime that is
+                // shutting down
+                if let Err(e) = listener.unlisten_all().await {
+                    eprintln!("Failed to stop listening for events: {}", e);
+                }
+            });
+            self.listeners
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(task);
+            Ok(rx)
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.closed.send_replace(true);
+            let listeners = std::mem::take(
+                &mut *self
+                    .listeners
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            for listener in listeners {
+                let _ = listener.await;
+            }
+        })
+    }
+}
+
+/// Sends `envelope` to the listeners on `conn`, storing it in `bus_events`
+/// when it's too big for a notification.
+async fn notify<E: Serialize>(
+    conn: &mut PgConnection,
+    envelope: &Envelope<E>,
+) -> Result<(), String> {
+    let mut payload =
+        serde_json::to_string(envelope).map_err(|e| format!("Failed to serialize event: {}", e))?;
+    if payload.len() >= MAX_NOTIFY_PAYLOAD {
+        let id = sqlx::query_scalar::<_, i64>(
+            r#"
+            WITH pruned AS (
+                DELETE FROM bus_events
+                WHERE created_at < CURRENT_TIMESTAMP - INTERVAL '1 minute'
+            )
+            INSERT INTO bus_events (payload) VALUES ($1)
+            RETURNING id
+            "#,
+        )
+        .bind(&payload)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| format!("Failed to store event: {}", e))?;
+        payload = id.to_string();
+    }
+
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(&payload)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| format!("Failed to publish event: {}", e))?;
+    Ok(())
+}
+
+/// The envelope a notification carries, inline or stored in `bus_events`.
+async fn read_envelope<E: DeserializeOwned>(
+    pool: &PgPool,
+    payload: &str,
+) -> Result<Envelope<E>, String> {
+    let stored;
+    let payload = match payload.parse::<i64>() {
+        Ok(id) => {
+            stored =
+                sqlx::query_scalar::<_, String>("SELECT payload FROM bus_events WHERE id = $1")
+                    .bind(id)
+                    .fetch_optional(pool)
+                    .await
+                    .map_err(|e| format!("Failed to load event {}: {}", id, e))?
+                    .ok_or_else(|| format!("Event {} was already pruned", id))?;
+            stored.as_str()
+        }
+        Err(_) => payload,
+    };
+    serde_json::from_str(payload).map_err(|e| format!("Invalid event on the bus: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::chat::{ChatEvent, OutgoingMessage};
+    use crate::routes::socket::Event;
+
+    #[test]
+    fn envelopes_survive_serialization() {
+        let event = Event::Chat(ChatEvent {
+            seq: Some(4),
+            event: OutgoingMessage::Delete {
+                message_id: 10,
+                chat_id: 1,
+            },
+        });
+        let frame = event.frame(false);
+        let envelope = Envelope {
+            origin: 3,
+            broadcast: Broadcast::ToChat {
+                chat_id: 1,
+                except: Some("alice".to_string()),
+                event,
+            },
+        };
+
+        let payload = serde_json::to_string(&envelope).unwrap();
+        let Envelope { origin, broadcast } =
+            serde_json::from_str::<Envelope<Event>>(&payload).unwrap();
+        assert_eq!(origin, 3);
+        let Broadcast::ToChat { except, event, .. } = broadcast else {
+            panic!("not routed to a chat: {:?}", broadcast);
+        };
+        assert_eq!(except.as_deref(), Some("alice"));
+        assert_eq!(event.frame(false), frame);
+    }
+}
@@ -0,0 +1,660 @@
+use crate::rate_limit::Limit;
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use time::Duration;
+
+/// Read when `KUTTER_CONFIG` isn't set, it's fine for it not to exist.
+const DEFAULT_CONFIG_PATH: &str = "kutter.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Installs the configuration loaded at startup. Later calls keep the first
+/// configuration.
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+pub fn get() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("the configuration is loaded at startup")
+}
+
+/// Every setting of the server. Each section can be left out of the TOML
+/// file, missing values fall back to their defaults and environment
+/// variables override both, see `apply_overrides`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cors: CorsConfig,
+    pub auth: AuthConfig,
+    pub uploads: UploadConfig,
+    pub mail: MailConfig,
+    pub rate_limits: RateLimitConfig,
+    pub websocket: WebSocketConfig,
+    pub bus: BusConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub max_lifetime_secs: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            max_connections: 200,
+            min_connections: 40,
+            acquire_timeout_secs: 5,
+            idle_timeout_secs: 30 * 60,
+            max_lifetime_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![
+                "http://localhost:8080".to_string(),
+                "http://localhost:1230".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub jwt_secret: String,
+    pub access_token_ttl_secs: i64,
+    pub refresh_token_ttl_secs: i64,
+    pub email_verification_ttl_secs: i64,
+    pub password_reset_ttl_secs: i64,
+    pub mfa_pending_ttl_secs: i64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_secret: String::new(),
+            access_token_ttl_secs: 15 * 60,
+            refresh_token_ttl_secs: 30 * 24 * 60 * 60,
+            email_verification_ttl_secs: 60 * 60,
+            password_reset_ttl_secs: 15 * 60,
+            mfa_pending_ttl_secs: 5 * 60,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn access_token_ttl(&self) -> Duration {
+        Duration::seconds(self.access_token_ttl_secs)
+    }
+
+    pub fn refresh_token_ttl(&self) -> Duration {
+        Duration::seconds(self.refresh_token_ttl_secs)
+    }
+
+    pub fn email_verification_ttl(&self) -> Duration {
+        Duration::seconds(self.email_verification_ttl_secs)
+    }
+
+    pub fn password_reset_ttl(&self) -> Duration {
+        Duration::seconds(self.password_reset_ttl_secs)
+    }
+
+    pub fn mfa_pending_ttl(&self) -> Duration {
+        Duration::seconds(self.mfa_pending_ttl_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Avatars live at the top level, attachments in `attachments/`.
+    pub dir: PathBuf,
+    pub max_avatar_size: usize,
+    pub max_attachment_size: usize,
+    pub max_attachments_per_message: usize,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./uploads"),
+            max_avatar_size: 5 * 1024 * 1024,
+            max_attachment_size: 10 * 1024 * 1024,
+            max_attachments_per_message: 10,
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn attachments_dir(&self) -> PathBuf {
+        self.dir.join("attachments")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    Resend,
+    File,
+    Stdout,
+}
+
+impl FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "smtp" => Ok(Self::Smtp),
+            "resend" => Ok(Self::Resend),
+            "file" => Ok(Self::File),
+            "stdout" => Ok(Self::Stdout),
+            other => Err(format!("unknown mail transport {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    /// Without it, SMTP is used when `smtp_user` is set and emails are
+    /// printed otherwise.
+    pub transport: Option<MailTransport>,
+    /// Sender address, `smtp_user` by default.
+    pub from: Option<String>,
+    /// Links in emails point there.
+    pub base_url: String,
+    pub smtp_host: String,
+    pub smtp_user: Option<String>,
+    pub smtp_password: Option<String>,
+    pub resend_api_key: Option<String>,
+    /// Where the `file` transport drops its emails.
+    pub dir: PathBuf,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: None,
+            from: None,
+            base_url: "https://kutter.ryterm.xyz".to_string(),
+            smtp_host: "smtp.gmail.com".to_string(),
+            smtp_user: None,
+            smtp_password: None,
+            resend_api_key: None,
+            dir: PathBuf::from("./mail"),
+        }
+    }
+}
+
+impl MailConfig {
+    pub fn transport(&self) -> MailTransport {
+        match self.transport {
+            Some(transport) => transport,
+            None if self.smtp_user.is_some() => MailTransport::Smtp,
+            None => MailTransport::Stdout,
+        }
+    }
+
+    pub fn from_address(&self) -> &str {
+        self.from
+            .as_deref()
+            .or(self.smtp_user.as_deref())
+            .unwrap_or("noreply@localhost")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Per client IP, on the auth endpoints.
+    pub auth: Limit,
+    /// Per account, on login attempts.
+    pub logins: Limit,
+    /// Per account, on verification and password reset emails.
+    pub emails: Limit,
+    /// Per user, on every websocket action.
+    pub ws_actions: Limit,
+    /// Per user, on `new_message`.
+    pub ws_messages: Limit,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            auth: Limit::new(30, 60),
+            logins: Limit::new(10, 60),
+            emails: Limit::new(3, 600),
+            ws_actions: Limit::new(30, 5),
+            ws_messages: Limit::new(10, 10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// How often the server pings each socket.
+    pub ping_interval_secs: u64,
+    /// Sockets silent for this long, pongs included, are dropped.
+    pub pong_timeout_secs: u64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 15,
+            pong_timeout_secs: 45,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn ping_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn pong_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.pong_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusBackend {
+    Local,
+    Postgres,
+}
+
+impl FromStr for BusBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "local" => Ok(Self::Local),
+            "postgres" => Ok(Self::Postgres),
+            other => Err(format!("unknown event bus {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BusConfig {
+    /// `local` for a single node, `postgres` when several nodes share the
+    /// database.
+    pub backend: BusBackend,
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        Self {
+            backend: BusBackend::Local,
+        }
+    }
+}
+
+fn set<T>(target: &mut T, var: &impl Fn(&str) -> Option<String>, name: &str) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        *target = value
+            .parse()
+            .map_err(|e| format!("invalid {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+fn set_option(target: &mut Option<String>, var: &impl Fn(&str) -> Option<String>, name: &str) {
+    if let Some(value) = var(name) {
+        *target = Some(value);
+    }
+}
+
+impl Config {
+    /// Reads the TOML file (`KUTTER_CONFIG`, else `kutter.toml` when there
+    /// is one), applies the environment on top and validates the result.
+    pub fn load() -> Result<Self, String> {
+        let mut config = match env::var("KUTTER_CONFIG") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Self::default(),
+        };
+        config.apply_overrides(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    /// Environment variables win over the file. The names the server has
+    /// always read (`DATABASE_URL`, `JWT_SECRET`, `SMTP_USER`, ...) keep
+    /// working.
+    pub fn apply_overrides(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        set(&mut self.server.host, &var, "HOST")?;
+        set(&mut self.server.port, &var, "PORT")?;
+
+        set(&mut self.database.url, &var, "DATABASE_URL")?;
+        set(
+            &mut self.database.max_connections,
+            &var,
+            "DATABASE_MAX_CONNECTIONS",
+        )?;
+        set(
+            &mut self.database.min_connections,
+            &var,
+            "DATABASE_MIN_CONNECTIONS",
+        )?;
+
+        if let Some(origins) = var("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        set(&mut self.auth.jwt_secret, &var, "JWT_SECRET")?;
+        set(
+            &mut self.auth.access_token_ttl_secs,
+            &var,
+            "ACCESS_TOKEN_TTL_SECS",
+        )?;
+        set(
+            &mut self.auth.refresh_token_ttl_secs,
+            &var,
+            "REFRESH_TOKEN_TTL_SECS",
+        )?;
+
+        set(&mut self.uploads.dir, &var, "UPLOAD_DIR")?;
+        set(&mut self.uploads.max_avatar_size, &var, "MAX_AVATAR_SIZE")?;
+        set(
+            &mut self.uploads.max_attachment_size,
+            &var,
+            "MAX_ATTACHMENT_SIZE",
+        )?;
+
+        if let Some(transport) = var("MAIL_TRANSPORT") {
+            self.mail.transport = Some(
+                transport
+                    .parse()
+                    .map_err(|e| format!("invalid MAIL_TRANSPORT: {}", e))?,
+            );
+        }
+        set_option(&mut self.mail.from, &var, "MAIL_FROM");
+        set(&mut self.mail.base_url, &var, "BASE_URL")?;
+        set(&mut self.mail.smtp_host, &var, "SMTP_HOST")?;
+        set_option(&mut self.mail.smtp_user, &var, "SMTP_USER");
+        set_option(&mut self.mail.smtp_password, &var, "SMTP_PSSWRD");
+        set_option(&mut self.mail.resend_api_key, &var, "RESEND_API_KEY");
+        set(&mut self.mail.dir, &var, "MAIL_DIR")?;
+
+        set(&mut self.rate_limits.auth, &var, "RATE_LIMIT_AUTH")?;
+        set(&mut self.rate_limits.logins, &var, "RATE_LIMIT_LOGINS")?;
+        set(&mut self.rate_limits.emails, &var, "RATE_LIMIT_EMAILS")?;
+        set(
+            &mut self.rate_limits.ws_actions,
+            &var,
+            "RATE_LIMIT_WS_ACTIONS",
+        )?;
+        set(
+            &mut self.rate_limits.ws_messages,
+            &var,
+            "RATE_LIMIT_WS_MESSAGES",
+        )?;
+
+        set(
+            &mut self.websocket.ping_interval_secs,
+            &var,
+            "WS_PING_INTERVAL_SECS",
+        )?;
+        set(
+            &mut self.websocket.pong_timeout_secs,
+            &var,
+            "WS_PONG_TIMEOUT_SECS",
+        )?;
+
+        set(&mut self.bus.backend, &var, "EVENT_BUS")?;
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.host.is_empty() {
+            return Err("server.host can't be empty".to_string());
+        }
+
+        if self.database.url.is_empty() {
+            return Err("database.url (or DATABASE_URL) must be set".to_string());
+        }
+        if self.database.max_connections == 0
+            || self.database.min_connections > self.database.max_connections
+        {
+            return Err(
+                "database.max_connections must be positive and at least min_connections"
+                    .to_string(),
+            );
+        }
+
+        if let Some(origin) = self
+            .cors
+            .allowed_origins
+            .iter()
+            .find(|origin| !is_http_url(origin))
+        {
+            return Err(format!("invalid cors origin {}", origin));
+        }
+
+        if self.auth.jwt_secret.is_empty() {
+            return Err("auth.jwt_secret (or JWT_SECRET) must be set".to_string());
+        }
+        let ttls = [
+            self.auth.access_token_ttl_secs,
+            self.auth.refresh_token_ttl_secs,
+            self.auth.email_verification_ttl_secs,
+            self.auth.password_reset_ttl_secs,
+            self.auth.mfa_pending_ttl_secs,
+        ];
+        if ttls.iter().any(|&ttl| ttl <= 0) {
+            return Err("token lifetimes must be positive".to_string());
+        }
+        if self.auth.access_token_ttl_secs >= self.auth.refresh_token_ttl_secs {
+            return Err("access tokens must expire before refresh tokens".to_string());
+        }
+
+        if self.uploads.max_avatar_size == 0
+            || self.uploads.max_attachment_size == 0
+            || self.uploads.max_attachments_per_message == 0
+        {
+            return Err("upload limits must be positive".to_string());
+        }
+
+        if !is_http_url(&self.mail.base_url) {
+            return Err(format!("invalid mail.base_url {}", self.mail.base_url));
+        }
+        match self.mail.transport() {
+            MailTransport::Smtp
+                if self.mail.smtp_user.is_none() || self.mail.smtp_password.is_none() =>
+            {
+                return Err(
+                    "the smtp transport needs mail.smtp_user and mail.smtp_password".to_string(),
+                );
+            }
+            MailTransport::Resend if self.mail.resend_api_key.is_none() => {
+                return Err("the resend transport needs mail.resend_api_key".to_string());
+            }
+            _ => {}
+        }
+
+        if self.websocket.ping_interval_secs == 0
+            || self.websocket.pong_timeout_secs <= self.websocket.ping_interval_secs
+        {
+            return Err(
+                "websocket.ping_interval_secs must be positive and below pong_timeout_secs"
+                    .to_string(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn is_http_url(value: &str) -> bool {
+    let rest = value
+        .strip_prefix("https://")
+        .or_else(|| value.strip_prefix("http://"));
+    rest.is_some_and(|host| !host.is_empty())
+}
+
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Config {
+    init(Config {
+        database: DatabaseConfig {
+            url: "postgres://localhost/kutter_test".to_string(),
+            ..DatabaseConfig::default()
+        },
+        auth: AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            ..AuthConfig::default()
+        },
+        ..Config::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn missing_settings_fall_back_to_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 9000
+
+            [rate_limits]
+            logins = "5/60"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.rate_limits.logins, Limit::new(5, 60));
+        assert_eq!(config.auth.access_token_ttl_secs, 15 * 60);
+        assert!(toml::from_str::<Config>("[server]\nprot = 9000").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [database]
+            url = "postgres://file/kutter"
+
+            [auth]
+            jwt_secret = "from-file"
+            "#,
+        )
+        .unwrap();
+        config
+            .apply_overrides(env(&[
+                ("JWT_SECRET", "from-env"),
+                ("PORT", "9001"),
+                ("CORS_ALLOWED_ORIGINS", "https://a.dev, https://b.dev"),
+                ("EVENT_BUS", "postgres"),
+            ]))
+            .unwrap();
+
+        assert_eq!(config.auth.jwt_secret, "from-env");
+        assert_eq!(config.database.url, "postgres://file/kutter");
+        assert_eq!(config.server.port, 9001);
+        assert_eq!(config.bus.backend, BusBackend::Postgres);
+        assert_eq!(
+            config.cors.allowed_origins,
+            ["https://a.dev", "https://b.dev"]
+        );
+        assert!(config.validate().is_ok());
+
+        assert!(config.apply_overrides(env(&[("PORT", "http")])).is_err());
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let mut config = Config::default();
+        config.database.url = "postgres://localhost/kutter".to_string();
+        assert!(config.validate().is_err(), "no jwt secret");
+
+        config.auth.jwt_secret = "secret".to_string();
+        assert!(config.validate().is_ok());
+
+        config.mail.transport = Some(MailTransport::Resend);
+        assert!(config.validate().is_err(), "no resend api key");
+        config.mail.transport = None;
+
+        config.websocket.pong_timeout_secs = config.websocket.ping_interval_secs;
+        assert!(config.validate().is_err(), "pong timeout within one ping");
+        config.websocket = WebSocketConfig::default();
+
+        config.auth.access_token_ttl_secs = config.auth.refresh_token_ttl_secs;
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,15 +1,52 @@
+use crate::config::DatabaseConfig;
+use sqlx::PgPool;
+use sqlx::migrate::Migrator;
 use sqlx::postgres::PgPoolOptions;
-use std::env;
+use std::time::Duration;
 
-pub async fn create_pool() -> sqlx::Pool<sqlx::Postgres> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+/// Ordered up-migrations from `./migrations`, applied by `kutter migrate`.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+pub async fn create_pool(config: &DatabaseConfig) -> sqlx::Pool<sqlx::Postgres> {
     PgPoolOptions::new()
-        .max_connections(200)
-        .min_connections(40)
-        .acquire_timeout(std::time::Duration::from_secs(5))
-        .idle_timeout(std::time::Duration::from_secs(30 * 60))
-        .max_lifetime(std::time::Duration::from_secs(60 * 60))
-        .connect(&database_url)
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+        .idle_timeout(Duration::from_secs(config.idle_timeout_secs))
+        .max_lifetime(Duration::from_secs(config.max_lifetime_secs))
+        .connect(&config.url)
         .await
         .expect("Failed to create database connection pool")
 }
+
+/// Versions of the embedded migrations that were not applied to `pool` yet,
+/// every one of them when the database was never migrated.
+pub async fn pending_migrations(pool: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    let tracked =
+        sqlx::query_scalar::<_, bool>("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+            .fetch_one(pool)
+            .await?;
+
+    let applied = if tracked {
+        sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await?
+    } else {
+        vec![]
+    };
+
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
+/// A pool that never connects, for unit tests of state that holds one but
+/// is exercised without touching the database.
+#[cfg(test)]
+pub fn pool_for_tests() -> PgPool {
+    PgPoolOptions::new()
+        .connect_lazy("postgres://localhost/kutter_test")
+        .unwrap()
+}
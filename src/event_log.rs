@@ -0,0 +1,143 @@
+use serde_json::Value;
+use sqlx::{FromRow, PgConnection, PgPool};
+use std::time::Duration;
+
+/// Events older than this are pruned, a client offline for longer resyncs.
+pub const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Past this many missed events replaying is slower than refetching.
+pub const MAX_REPLAY: i64 = 1000;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, FromRow)]
+pub struct LoggedEvent {
+    pub username: String,
+    pub seq: i64,
+    pub event: Value,
+}
+
+impl LoggedEvent {
+    /// The event as sent on the socket, with its `seq`.
+    pub fn frame(&self) -> Value {
+        let mut frame = self.event.clone();
+        if let Some(object) = frame.as_object_mut() {
+            object.insert("seq".to_string(), self.seq.into());
+        }
+        frame
+    }
+}
+
+/// What a client that saw everything up to some `seq` missed.
+#[derive(Debug)]
+pub enum Replay {
+    Events(Vec<LoggedEvent>),
+    /// Part of the range was pruned or it's too long, the client has to
+    /// refetch its chats.
+    Gap,
+}
+
+/// Appends `event` to the log of each of `usernames`, returning the `seq`
+/// it got for every one of them. Bumping `last_event_seq` locks the user
+/// row until `conn`'s transaction ends, so the sequence of a user has no
+/// holes or duplicates.
+pub async fn append(
+    conn: &mut PgConnection,
+    usernames: &[String],
+    event: &Value,
+) -> Result<Vec<LoggedEvent>, sqlx::Error> {
+    sqlx::query_as::<_, LoggedEvent>(
+        r#"
+        WITH recipients AS (
+            UPDATE users SET last_event_seq = last_event_seq + 1
+            WHERE username = ANY($1)
+            RETURNING username, last_event_seq
+        )
+        INSERT INTO user_events (username, seq, event)
+        SELECT username, last_event_seq, $2 FROM recipients
+        RETURNING username, seq, event
+        "#,
+    )
+    .bind(usernames)
+    .bind(event)
+    .fetch_all(conn)
+    .await
+}
+
+/// Waits for the events being delivered to `username` to be committed, so a
+/// session connected before reading it gets them either live or replayed.
+pub async fn last_seq(pool: &PgPool, username: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT last_event_seq FROM users WHERE username = $1 FOR SHARE")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .map(Option::unwrap_or_default)
+}
+
+/// The events of `username` after `after`, up to and including `until`.
+pub async fn replay(
+    pool: &PgPool,
+    username: &str,
+    after: i64,
+    until: i64,
+) -> Result<Replay, sqlx::Error> {
+    if after >= until {
+        return Ok(Replay::Events(vec![]));
+    }
+    if until - after > MAX_REPLAY {
+        return Ok(Replay::Gap);
+    }
+
+    let events = sqlx::query_as::<_, LoggedEvent>(
+        "SELECT username, seq, event FROM user_events WHERE username = $1 AND seq > $2 AND seq <= $3 ORDER BY seq",
+    )
+    .bind(username)
+    .bind(after)
+    .bind(until)
+    .fetch_all(pool)
+    .await?;
+
+    // sequences have no holes, anything missing was pruned
+    if events.len() as i64 != until - after {
+        return Ok(Replay::Gap);
+    }
+    Ok(Replay::Events(events))
+}
+
+pub async fn prune(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    sqlx::query("DELETE FROM user_events WHERE created_at < NOW() - make_interval(secs => $1)")
+        .bind(RETENTION.as_secs_f64())
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+/// Prunes the log every `PRUNE_INTERVAL` for as long as the server runs.
+pub async fn prune_periodically(pool: PgPool) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = prune(&pool).await {
+            eprintln!("Error pruning event log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn frames_carry_the_sequence_number() {
+        let event = LoggedEvent {
+            username: "alice".to_string(),
+            seq: 42,
+            event: json!({"action": "delete", "message_id": 10, "chat_id": 1}),
+        };
+
+        assert_eq!(
+            event.frame(),
+            json!({"action": "delete", "message_id": 10, "chat_id": 1, "seq": 42})
+        );
+    }
+}
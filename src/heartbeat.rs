@@ -0,0 +1,79 @@
+//! Liveness of websocket clients.
+//!
+//! The server pings every socket each `ping_interval`, browsers answer on
+//! their own. A socket that sent nothing, pongs included, for `pong_timeout`
+//! is closed, which catches half-open TCP connections the kernel would keep
+//! around for hours. So is a socket whose access token expired, checked on
+//! every ping, the client reconnects with a refreshed one.
+
+use crate::config::WebSocketConfig;
+use actix_ws::{Message, MessageStream, Session};
+use chrono::Utc;
+use futures_util::StreamExt as _;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+pub struct Heartbeat {
+    session: Session,
+    interval: Interval,
+    timeout: Duration,
+    last_seen: Instant,
+    // unix time, from the `exp` of the access token
+    token_expires: i64,
+}
+
+impl Heartbeat {
+    pub fn new(session: Session, config: &WebSocketConfig, token_expires: usize) -> Self {
+        let period = config.ping_interval();
+        let mut interval = time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            session,
+            interval,
+            timeout: config.pong_timeout(),
+            last_seen: Instant::now(),
+            token_expires: token_expires as i64,
+        }
+    }
+
+    /// The next text frame of `stream`, pinging the client and answering its
+    /// pings meanwhile. Binary frames are ignored. `None` once the client
+    /// closed the socket, went silent for `timeout` or its token expired.
+    pub async fn next_text(&mut self, stream: &mut MessageStream) -> Option<String> {
+        loop {
+            let msg = tokio::select! {
+                msg = stream.next() => msg,
+                _ = time::sleep_until(self.last_seen + self.timeout) => {
+                    let _ = self.session.clone().close(None).await;
+                    return None;
+                }
+                _ = self.interval.tick() => {
+                    if Utc::now().timestamp() >= self.token_expires {
+                        let _ = self.session.clone().close(None).await;
+                        return None;
+                    }
+                    if self.session.ping(b"").await.is_err() {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+
+            let Some(Ok(msg)) = msg else {
+                return None;
+            };
+            self.last_seen = Instant::now();
+            match msg {
+                Message::Text(text) => return Some(text.to_string()),
+                Message::Ping(bytes) => {
+                    if self.session.pong(&bytes).await.is_err() {
+                        return None;
+                    }
+                }
+                Message::Close(_) => return None,
+                Message::Pong(_) | Message::Binary(_) | Message::Continuation(_) | Message::Nop => {
+                }
+            }
+        }
+    }
+}
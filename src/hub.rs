@@ -0,0 +1,550 @@
+use crate::bus::{Broadcast, Delivery, Envelope, EventBus, InProcessBus, NodeId};
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{RwLock, mpsc};
+
+/// What the hub needs from the events it routes.
+pub trait HubEvent {
+    /// This event stamped with the `seq` it got in a user's event log.
+    fn with_seq(&self, seq: i64) -> Self;
+
+    /// Tells a session that events routed to it may have been lost.
+    fn resync() -> Self;
+}
+
+/// Identifies one websocket connection, a user has one per open tab/device.
+pub type SessionId = u64;
+
+/// Registry of the live websocket sessions of this process.
+///
+/// Events are pushed straight into the sessions of the users (or the online
+/// members of a chat) they concern, instead of going through a broadcast
+/// channel that every socket subscribes to and filters. Whatever is routed
+/// or changed here is also published on the bus, for the hubs of the other
+/// nodes, see `relay`.
+pub struct Hub<E> {
+    inner: RwLock<Inner<E>>,
+    next_session_id: AtomicU64,
+    node_id: NodeId,
+    bus: Arc<dyn EventBus<E>>,
+}
+
+/// One open socket, `login` being the `sessions` row of the access token it
+/// was opened with.
+struct Socket<E> {
+    login: i32,
+    tx: mpsc::UnboundedSender<E>,
+}
+
+struct Inner<E> {
+    sessions: HashMap<String, HashMap<SessionId, Socket<E>>>,
+    // chat id -> connected members, and the reverse index to clean it up
+    chats: HashMap<i32, HashSet<String>>,
+    user_chats: HashMap<String, HashSet<i32>>,
+}
+
+impl<E: HubEvent + Clone + Send + Sync + 'static> Hub<E> {
+    /// A hub on its own, for a single node.
+    pub fn new() -> Self {
+        Self::with_bus(Arc::new(InProcessBus::new()))
+    }
+
+    pub fn with_bus(bus: Arc<dyn EventBus<E>>) -> Self {
+        Self {
+            inner: RwLock::new(Inner {
+                sessions: HashMap::new(),
+                chats: HashMap::new(),
+                user_chats: HashMap::new(),
+            }),
+            next_session_id: AtomicU64::new(1),
+            node_id: rand::random(),
+            bus,
+        }
+    }
+
+    /// The id this hub publishes under, it also names the node in the
+    /// tables shared by every node.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Subscribes to the bus and applies what the other nodes publish to
+    /// the sessions of this one, until the bus goes away.
+    pub async fn relay(self: &Arc<Self>) -> Result<(), String> {
+        let mut rx = self.bus.subscribe().await?;
+        let hub = self.clone();
+        actix_rt::spawn(async move {
+            while let Some(delivery) = rx.recv().await {
+                match delivery {
+                    Delivery::Envelope(envelope) if envelope.origin != hub.node_id => {
+                        hub.apply(&envelope.broadcast).await;
+                    }
+                    Delivery::Envelope(_) => {}
+                    Delivery::Lost => hub.resync().await,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Sends `E::resync()` to every session of this node, after the bus
+    /// lost what the other nodes published.
+    async fn resync(&self) {
+        let inner = self.inner.read().await;
+        for socket in inner.sessions.values().flat_map(|s| s.values()) {
+            let _ = socket.tx.send(E::resync());
+        }
+    }
+
+    /// Stops relaying and closes the bus, before the database goes away.
+    pub async fn close(&self) {
+        self.bus.close().await;
+    }
+
+    /// Applies `broadcast` here, then hands it to the other nodes.
+    async fn broadcast(&self, broadcast: Broadcast<E>) {
+        self.apply(&broadcast).await;
+        let envelope = Envelope {
+            origin: self.node_id,
+            broadcast,
+        };
+        if let Err(e) = self.bus.publish(&envelope).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    async fn apply(&self, broadcast: &Broadcast<E>) {
+        match broadcast {
+            Broadcast::ToUsers {
+                usernames,
+                event,
+                seqs,
+            } => {
+                let inner = self.inner.read().await;
+                for username in usernames {
+                    match seqs.get(username) {
+                        Some(seq) => inner.send(username, &event.with_seq(*seq)),
+                        None => inner.send(username, event),
+                    }
+                }
+            }
+            Broadcast::ToChat {
+                chat_id,
+                except,
+                event,
+            } => {
+                let inner = self.inner.read().await;
+                let Some(members) = inner.chats.get(chat_id) else {
+                    return;
+                };
+                for username in members
+                    .iter()
+                    .filter(|username| Some(*username) != except.as_ref())
+                {
+                    inner.send(username, event);
+                }
+            }
+            Broadcast::AddMember { chat_id, username } => {
+                let mut inner = self.inner.write().await;
+                if !inner.sessions.contains_key(username) {
+                    return;
+                }
+
+                inner
+                    .chats
+                    .entry(*chat_id)
+                    .or_default()
+                    .insert(username.to_string());
+                inner
+                    .user_chats
+                    .entry(username.to_string())
+                    .or_default()
+                    .insert(*chat_id);
+            }
+            Broadcast::RemoveMember { chat_id, username } => {
+                let mut inner = self.inner.write().await;
+
+                if let Some(members) = inner.chats.get_mut(chat_id) {
+                    members.remove(username);
+                    if members.is_empty() {
+                        inner.chats.remove(chat_id);
+                    }
+                }
+                if let Some(chats) = inner.user_chats.get_mut(username) {
+                    chats.remove(chat_id);
+                }
+            }
+            Broadcast::DisconnectUser { username } => {
+                let mut inner = self.inner.write().await;
+                inner.sessions.remove(username);
+                inner.forget_if_gone(username);
+            }
+            Broadcast::DisconnectLogin { username, login } => {
+                let mut inner = self.inner.write().await;
+                if let Some(sessions) = inner.sessions.get_mut(username) {
+                    sessions.retain(|_, socket| socket.login != *login);
+                }
+                inner.forget_if_gone(username);
+            }
+        }
+    }
+
+    /// Registers a new session of `username`, opened with the access token of
+    /// `login`, `chats` being the ids of the chats they are a member of. The
+    /// returned receiver yields every event routed to the user until
+    /// `disconnect` is called with the returned id.
+    pub async fn connect(
+        &self,
+        username: &str,
+        login: i32,
+        chats: &[i32],
+    ) -> (SessionId, mpsc::UnboundedReceiver<E>) {
+        let session_id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.inner.write().await;
+
+        inner
+            .sessions
+            .entry(username.to_string())
+            .or_default()
+            .insert(session_id, Socket { login, tx });
+        for chat_id in chats {
+            inner
+                .chats
+                .entry(*chat_id)
+                .or_default()
+                .insert(username.to_string());
+        }
+        inner
+            .user_chats
+            .entry(username.to_string())
+            .or_default()
+            .extend(chats.iter().copied());
+        (session_id, rx)
+    }
+
+    /// Drops one session of `username`, the user stays routable as long as
+    /// another of their sessions is open.
+    pub async fn disconnect(&self, username: &str, session_id: SessionId) {
+        let mut inner = self.inner.write().await;
+        if let Some(sessions) = inner.sessions.get_mut(username) {
+            sessions.remove(&session_id);
+        }
+        inner.forget_if_gone(username);
+    }
+
+    /// Drops every session of `username` on every node, their sockets close
+    /// once the receivers returned by `connect` run dry.
+    pub async fn disconnect_user(&self, username: &str) {
+        self.broadcast(Broadcast::DisconnectUser {
+            username: username.to_string(),
+        })
+        .await;
+    }
+
+    /// Drops the sessions opened with the access tokens of `login` on every
+    /// node, once it was revoked.
+    pub async fn disconnect_login(&self, username: &str, login: i32) {
+        self.broadcast(Broadcast::DisconnectLogin {
+            username: username.to_string(),
+            login,
+        })
+        .await;
+    }
+
+    /// Adds `username` to the routing table of `chat_id`, users that are not
+    /// connected are ignored since `connect` loads their chats anyway.
+    pub async fn add_member(&self, chat_id: i32, username: &str) {
+        self.broadcast(Broadcast::AddMember {
+            chat_id,
+            username: username.to_string(),
+        })
+        .await;
+    }
+
+    pub async fn remove_member(&self, chat_id: i32, username: &str) {
+        self.broadcast(Broadcast::RemoveMember {
+            chat_id,
+            username: username.to_string(),
+        })
+        .await;
+    }
+
+    pub async fn send_to_user(&self, username: &str, event: E) {
+        self.broadcast(Broadcast::ToUsers {
+            usernames: vec![username.to_string()],
+            event,
+            seqs: HashMap::new(),
+        })
+        .await;
+    }
+
+    pub async fn send_to_users(&self, usernames: &[String], event: E) {
+        self.broadcast(Broadcast::ToUsers {
+            usernames: usernames.to_vec(),
+            event,
+            seqs: HashMap::new(),
+        })
+        .await;
+    }
+
+    /// Commits `tx`, which logged `event` for every user in `seqs`, and
+    /// sends each of them a copy stamped with its `seq`. Nothing goes out
+    /// unless the commit succeeds.
+    pub async fn send_logged(
+        &self,
+        tx: Transaction<'static, Postgres>,
+        seqs: HashMap<String, i64>,
+        event: E,
+    ) -> Result<(), String> {
+        let envelope = Envelope {
+            origin: self.node_id,
+            broadcast: Broadcast::ToUsers {
+                usernames: seqs.keys().cloned().collect(),
+                event,
+                seqs,
+            },
+        };
+        self.bus.publish_on_commit(tx, &envelope).await?;
+        self.apply(&envelope.broadcast).await;
+        Ok(())
+    }
+
+    pub async fn sessions_of(&self, username: &str) -> Vec<SessionId> {
+        let inner = self.inner.read().await;
+        inner
+            .sessions
+            .get(username)
+            .map(|sessions| sessions.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether `username` is a member of `chat_id`, only meaningful for
+    /// users connected to this node since the hub does not track the others.
+    pub async fn in_chat(&self, chat_id: i32, username: &str) -> bool {
+        let inner = self.inner.read().await;
+        inner
+            .chats
+            .get(&chat_id)
+            .is_some_and(|members| members.contains(username))
+    }
+
+    pub async fn send_to_chat(&self, chat_id: i32, event: E) {
+        self.broadcast(Broadcast::ToChat {
+            chat_id,
+            except: None,
+            event,
+        })
+        .await;
+    }
+
+    /// Same as `send_to_chat` but skips every session of `except`.
+    pub async fn send_to_chat_except(&self, chat_id: i32, except: &str, event: E) {
+        self.broadcast(Broadcast::ToChat {
+            chat_id,
+            except: Some(except.to_string()),
+            event,
+        })
+        .await;
+    }
+}
+
+impl<E: Clone> Inner<E> {
+    fn send(&self, username: &str, event: &E) {
+        for socket in self
+            .sessions
+            .get(username)
+            .into_iter()
+            .flat_map(|s| s.values())
+        {
+            let _ = socket.tx.send(event.clone());
+        }
+    }
+
+    /// Removes `username` from the routing tables once none of their
+    /// sessions is left.
+    fn forget_if_gone(&mut self, username: &str) {
+        if self
+            .sessions
+            .get(username)
+            .is_some_and(|sessions| !sessions.is_empty())
+        {
+            return;
+        }
+
+        self.sessions.remove(username);
+        for chat_id in self.user_chats.remove(username).unwrap_or_default() {
+            if let Some(members) = self.chats.get_mut(&chat_id) {
+                members.remove(username);
+                if members.is_empty() {
+                    self.chats.remove(&chat_id);
+                }
+            }
+        }
+    }
+}
+
+impl<E: HubEvent + Clone + Send + Sync + 'static> Default for Hub<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::BoxFuture;
+
+    impl HubEvent for i32 {
+        fn with_seq(&self, seq: i64) -> Self {
+            seq as i32
+        }
+
+        fn resync() -> Self {
+            -1
+        }
+    }
+
+    #[actix_rt::test]
+    async fn every_session_of_a_user_receives_events() {
+        let hub = Hub::<i32>::new();
+        let (_, mut laptop) = hub.connect("alice", 1, &[1]).await;
+        let (_, mut phone) = hub.connect("alice", 2, &[1]).await;
+
+        hub.send_to_chat(1, 7).await;
+
+        assert_eq!(laptop.try_recv(), Ok(7));
+        assert_eq!(phone.try_recv(), Ok(7));
+    }
+
+    #[actix_rt::test]
+    async fn closing_one_session_keeps_the_others_live() {
+        let hub = Hub::<i32>::new();
+        let (laptop_id, mut laptop) = hub.connect("alice", 1, &[1]).await;
+        let (_, mut phone) = hub.connect("alice", 2, &[1]).await;
+
+        hub.disconnect("alice", laptop_id).await;
+        hub.send_to_chat(1, 7).await;
+        hub.send_to_user("alice", 8).await;
+
+        assert!(laptop.try_recv().is_err());
+        assert_eq!(phone.try_recv(), Ok(7));
+        assert_eq!(phone.try_recv(), Ok(8));
+    }
+
+    #[actix_rt::test]
+    async fn logged_events_carry_each_users_seq() {
+        let hub = Hub::<i32>::new();
+        let (_, mut alice) = hub.connect("alice", 1, &[]).await;
+        let (_, mut bob) = hub.connect("bob", 2, &[]).await;
+
+        hub.apply(&Broadcast::ToUsers {
+            usernames: vec!["alice".to_string(), "bob".to_string()],
+            event: 0,
+            seqs: HashMap::from([("alice".to_string(), 4), ("bob".to_string(), 9)]),
+        })
+        .await;
+
+        assert_eq!(alice.try_recv(), Ok(4));
+        assert_eq!(bob.try_recv(), Ok(9));
+    }
+
+    #[actix_rt::test]
+    async fn disconnecting_a_user_closes_all_their_sessions() {
+        let hub = Hub::<i32>::new();
+        let (_, mut laptop) = hub.connect("alice", 1, &[1]).await;
+        let (_, mut phone) = hub.connect("alice", 2, &[1]).await;
+
+        hub.disconnect_user("alice").await;
+
+        assert_eq!(laptop.recv().await, None);
+        assert_eq!(phone.recv().await, None);
+        assert!(!hub.in_chat(1, "alice").await);
+    }
+
+    #[actix_rt::test]
+    async fn revoking_a_login_closes_only_its_sessions() {
+        let hub = Hub::<i32>::new();
+        let (_, mut laptop) = hub.connect("alice", 1, &[1]).await;
+        let (_, mut phone) = hub.connect("alice", 2, &[1]).await;
+
+        hub.disconnect_login("alice", 1).await;
+        hub.send_to_chat(1, 7).await;
+
+        assert_eq!(laptop.recv().await, None);
+        assert_eq!(phone.try_recv(), Ok(7));
+        assert!(hub.in_chat(1, "alice").await);
+
+        hub.disconnect_login("alice", 2).await;
+        assert_eq!(phone.recv().await, None);
+        assert!(!hub.in_chat(1, "alice").await);
+    }
+
+    /// Loses every envelope, like a listener that keeps reconnecting.
+    struct LossyBus;
+
+    impl EventBus<i32> for LossyBus {
+        fn publish<'a>(&'a self, _: &'a Envelope<i32>) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn publish_on_commit<'a>(
+            &'a self,
+            tx: Transaction<'static, Postgres>,
+            _: &'a Envelope<i32>,
+        ) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async { tx.commit().await.map_err(|e| e.to_string()) })
+        }
+
+        fn close(&self) -> BoxFuture<'_, ()> {
+            Box::pin(async {})
+        }
+
+        fn subscribe(
+            &self,
+        ) -> BoxFuture<'_, Result<mpsc::UnboundedReceiver<Delivery<i32>>, String>> {
+            Box::pin(async {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tx.send(Delivery::Lost).unwrap();
+                Ok(rx)
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn lost_envelopes_make_every_session_resync() {
+        let hub = Arc::new(Hub::with_bus(Arc::new(LossyBus)));
+        let (_, mut alice) = hub.connect("alice", 1, &[]).await;
+        let (_, mut bob) = hub.connect("bob", 2, &[]).await;
+
+        hub.relay().await.unwrap();
+
+        assert_eq!(alice.recv().await, Some(-1));
+        assert_eq!(bob.recv().await, Some(-1));
+    }
+
+    #[actix_rt::test]
+    async fn hubs_sharing_a_bus_route_to_each_others_sessions() {
+        let bus: Arc<dyn EventBus<i32>> = Arc::new(InProcessBus::new());
+        let first = Arc::new(Hub::with_bus(bus.clone()));
+        let second = Arc::new(Hub::with_bus(bus));
+        first.relay().await.unwrap();
+        second.relay().await.unwrap();
+        let (_, mut alice) = first.connect("alice", 1, &[]).await;
+        let (_, mut bob) = second.connect("bob", 1, &[]).await;
+
+        // membership changes are replayed too, `bob` only joined on `first`
+        first.add_member(1, "bob").await;
+        first.send_to_chat(1, 7).await;
+        second.send_to_user("alice", 8).await;
+
+        assert_eq!(bob.recv().await, Some(7));
+        assert_eq!(alice.recv().await, Some(8));
+        // delivered once, the publishing hub skips its own envelopes
+        assert!(alice.try_recv().is_err());
+        assert!(second.in_chat(1, "bob").await);
+        assert!(!first.in_chat(1, "bob").await);
+    }
+}
@@ -0,0 +1,45 @@
+use regex::Regex;
+
+pub mod app;
+pub mod bus;
+pub mod config;
+pub mod db;
+pub mod event_log;
+pub mod heartbeat;
+pub mod hub;
+pub mod mailer;
+pub mod middlewares;
+pub mod protocol;
+pub mod rate_limit;
+pub mod routes;
+
+#[derive(Clone)]
+pub struct RegexValidator {
+    pub email: Regex,
+    pub username: Regex,
+    pub password: Regex,
+}
+
+impl Default for RegexValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegexValidator {
+    pub fn new() -> Self {
+        Self {
+            email: Regex::new(r"^[\w\.-]+@[\w\.-]+\.\w{2,}$").unwrap(),
+            username: Regex::new(r"^[a-z0-9_-]{2,20}$").unwrap(),
+            password: Regex::new(r"^.{6,}$").unwrap(),
+        }
+    }
+
+    pub fn validate_password(&self, password: &str) -> bool {
+        if !self.password.is_match(password) {
+            return false;
+        }
+
+        true
+    }
+}
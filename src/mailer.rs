@@ -0,0 +1,272 @@
+use crate::config::{self, MailConfig, MailTransport};
+use futures_util::future::BoxFuture;
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use resend_rs::Resend;
+use resend_rs::types::CreateEmailBaseOptions;
+use std::path::PathBuf;
+
+const SENDER_NAME: &str = "Kutter";
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub to_name: String,
+    pub subject: String,
+    pub html: String,
+}
+
+/// Something that delivers emails.
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>>;
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, String> {
+    let to_address = email
+        .to
+        .parse()
+        .map_err(|e| format!("Invalid recipient email format: {}", e))?;
+
+    Message::builder()
+        .from(from.clone())
+        .to(Mailbox::new(Some(email.to_name.clone()), to_address))
+        .subject(email.subject.clone())
+        .header(ContentType::TEXT_HTML)
+        .body(email.html.clone())
+        .map_err(|e| format!("Failed to build email: {}", e))
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, user: String, password: String, from: Mailbox) -> Result<Self, String> {
+        let transport = SmtpTransport::relay(host)
+            .map_err(|e| format!("Failed to create mailer: {}", e))?
+            .credentials(Credentials::new(user, password))
+            .build();
+        Ok(Self { from, transport })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let message = build_message(&self.from, email)?;
+            let transport = self.transport.clone();
+            // lettre's SMTP transport blocks, keep it off the request threads
+            tokio::task::spawn_blocking(move || transport.send(&message))
+                .await
+                .map_err(|e| format!("Failed to send email: {}", e))?
+                .map_err(|e| format!("Failed to send email: {}", e))?;
+            Ok(())
+        })
+    }
+}
+
+pub struct ResendMailer {
+    from: Mailbox,
+    client: Resend,
+}
+
+impl ResendMailer {
+    pub fn new(api_key: &str, from: Mailbox) -> Self {
+        Self {
+            from,
+            client: Resend::new(api_key),
+        }
+    }
+}
+
+impl Mailer for ResendMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let options =
+                CreateEmailBaseOptions::new(self.from.to_string(), [&email.to], &email.subject)
+                    .with_html(&email.html);
+            self.client
+                .emails
+                .send(options)
+                .await
+                .map_err(|e| format!("Failed to send email: {}", e))?;
+            Ok(())
+        })
+    }
+}
+
+/// Drops every email as an `.eml` file, for development and tests.
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf, from: Mailbox) -> Self {
+        Self { from, dir }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let message = build_message(&self.from, email)?;
+            tokio::fs::create_dir_all(&self.dir)
+                .await
+                .map_err(|e| format!("Failed to create mail directory: {}", e))?;
+            let name = format!(
+                "{}-{:08x}.eml",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+                rand::random::<u32>()
+            );
+            tokio::fs::write(self.dir.join(name), message.formatted())
+                .await
+                .map_err(|e| format!("Failed to write email: {}", e))
+        })
+    }
+}
+
+/// Prints every email, for development.
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            println!(
+                "To: {} <{}>\nSubject: {}\n\n{}\n",
+                email.to_name, email.to, email.subject, email.html
+            );
+            Ok(())
+        })
+    }
+}
+
+/// The app's emails, rendered against `base_url` and handed to the
+/// configured transport.
+pub struct MailService {
+    mailer: Box<dyn Mailer>,
+    base_url: String,
+}
+
+impl MailService {
+    pub fn new(mailer: Box<dyn Mailer>, base_url: &str) -> Self {
+        Self {
+            mailer,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Builds the transport `config` selects, `Config::validate` already
+    /// made sure it has what it needs.
+    pub fn from_config(config: &MailConfig) -> Result<Self, String> {
+        let from = || -> Result<Mailbox, String> {
+            let address = config
+                .from_address()
+                .parse()
+                .map_err(|e| format!("Invalid sender email format: {}", e))?;
+            Ok(Mailbox::new(Some(SENDER_NAME.to_owned()), address))
+        };
+        let missing = |name: &str| format!("Missing mail.{}", name);
+
+        let mailer: Box<dyn Mailer> = match config.transport() {
+            MailTransport::Smtp => Box::new(SmtpMailer::new(
+                &config.smtp_host,
+                config
+                    .smtp_user
+                    .clone()
+                    .ok_or_else(|| missing("smtp_user"))?,
+                config
+                    .smtp_password
+                    .clone()
+                    .ok_or_else(|| missing("smtp_password"))?,
+                from()?,
+            )?),
+            MailTransport::Resend => Box::new(ResendMailer::new(
+                config
+                    .resend_api_key
+                    .as_deref()
+                    .ok_or_else(|| missing("resend_api_key"))?,
+                from()?,
+            )),
+            MailTransport::File => Box::new(FileMailer::new(config.dir.clone(), from()?)),
+            MailTransport::Stdout => Box::new(StdoutMailer),
+        };
+
+        Ok(Self::new(mailer, &config.base_url))
+    }
+
+    pub fn verification_email(&self, email: &str, username: &str, token: &str) -> Email {
+        Email {
+            to: email.to_string(),
+            to_name: username.to_string(),
+            subject: "Verify your account!".to_string(),
+            html: format!(
+                "Hey {}, <a href=\"{}/verify_email?token={}\">click here</a> to verify your email :3",
+                username, self.base_url, token
+            ),
+        }
+    }
+
+    pub fn password_reset_email(&self, email: &str, username: &str, token: &str) -> Email {
+        Email {
+            to: email.to_string(),
+            to_name: username.to_string(),
+            subject: "Reset your password".to_string(),
+            html: format!(
+                "Hey {}, <a href=\"{}/reset_password.html?token={}\">click here</a> to choose a new password. The link expires in {} minutes, ignore this email if you didn't ask for it.",
+                username,
+                self.base_url,
+                token,
+                config::get().auth.password_reset_ttl().whole_minutes()
+            ),
+        }
+    }
+
+    pub async fn send(&self, email: &Email) -> Result<(), String> {
+        self.mailer.send(email).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_use_the_configured_base_url() {
+        config::init_for_tests();
+        let mail = MailService::new(Box::new(StdoutMailer), "http://localhost:8080/");
+        let email = mail.verification_email("a@kutter.dev", "alice", "abc");
+        assert!(
+            email
+                .html
+                .contains("href=\"http://localhost:8080/verify_email?token=abc\"")
+        );
+
+        let email = mail.password_reset_email("a@kutter.dev", "alice", "abc");
+        assert!(
+            email
+                .html
+                .contains("href=\"http://localhost:8080/reset_password.html?token=abc\"")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn file_mailer_drops_eml_files() {
+        let dir = std::env::temp_dir().join(format!("kutter-mail-{}", rand::random::<u64>()));
+        let from = "noreply@localhost".parse().unwrap();
+        let mail = MailService::new(Box::new(FileMailer::new(dir.clone(), from)), "http://x");
+
+        let email = mail.verification_email("a@kutter.dev", "alice", "abc");
+        mail.send(&email).await.unwrap();
+
+        let mut entries = std::fs::read_dir(&dir).unwrap();
+        let path = entries.next().unwrap().unwrap().path();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("Subject: Verify your account!"));
+        assert!(content.contains("To: alice <a@kutter.dev>"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
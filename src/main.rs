@@ -1,87 +1,73 @@
-use actix_files as fs;
-use actix_web::{App, HttpServer, web};
+use actix_web::HttpServer;
 use dotenv::dotenv;
-use regex::Regex;
+use kutter::app::{self, AppData};
+use kutter::hub::Hub;
+use kutter::routes::presence;
+use kutter::{bus, config, db, event_log, mailer, rate_limit};
 use std::sync::Arc;
-pub mod db;
-pub mod middlewares;
-pub mod routes;
-
-#[derive(Clone)]
-pub struct RegexValidator {
-    pub email: Regex,
-    pub username: Regex,
-    pub password: Regex,
-}
-
-impl RegexValidator {
-    pub fn new() -> Self {
-        Self {
-            email: Regex::new(r"^[\w\.-]+@[\w\.-]+\.\w{2,}$").unwrap(),
-            username: Regex::new(r"^[a-z0-9_-]{2,20}$").unwrap(),
-            password: Regex::new(r"^.{6,}$").unwrap(),
-        }
-    }
-
-    pub fn validate_password(&self, password: &str) -> bool {
-        if !self.password.is_match(password) {
-            return false;
-        }
-
-        true
-    }
-}
+use std::{env, process};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let pool = db::create_pool().await;
+    let config = match config::Config::load() {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    let pool = db::create_pool(&config.database).await;
 
-    let regex_validator = RegexValidator::new();
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        db::MIGRATOR
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations");
+        println!("Database schema is up to date");
+        return Ok(());
+    }
 
-    let chat_state = Arc::new(routes::chat::AppState::new(pool.clone()));
+    let pending = db::pending_migrations(&pool)
+        .await
+        .expect("Failed to check migrations");
+    if !pending.is_empty() {
+        eprintln!(
+            "Database schema is out of date ({} pending migrations), run `kutter migrate` first",
+            pending.len()
+        );
+        process::exit(1);
+    }
 
-    let friend_state = Arc::new(routes::friend::FriendAppState::new(pool.clone()));
+    actix_rt::spawn(event_log::prune_periodically(pool.clone()));
 
-    middlewares::create_user_table(&pool)
-        .await
-        .expect("Failed to create table");
+    let rate_limits = rate_limit::RateLimits::new(&config.rate_limits);
 
-    routes::chat::chats(&pool)
-        .await
-        .expect("Failed to create table");
+    let mail = match mailer::MailService::from_config(&config.mail) {
+        Ok(mail) => mail,
+        Err(e) => {
+            eprintln!("Invalid mail configuration: {}", e);
+            process::exit(1);
+        }
+    };
 
-    routes::chat::create_table(&pool)
-        .await
-        .expect("Failed to create table");
+    let hub = Arc::new(Hub::with_bus(bus::from_config(&config.bus, &pool)));
+    if let Err(e) = hub.relay().await {
+        eprintln!("Failed to subscribe to the event bus: {}", e);
+        process::exit(1);
+    }
 
-    routes::friend::friend_table(&pool)
-        .await
-        .expect("Failed to create table");
+    let data = AppData::new(pool, hub, rate_limits, mail);
+    if let Err(e) = presence::start(data.chat_state.clone()).await {
+        eprintln!("Failed to register the node: {}", e);
+        process::exit(1);
+    }
 
-    HttpServer::new(move || {
-        let app = App::new()
-            .app_data(web::Data::new(pool.clone()))
-            .app_data(web::Data::new(chat_state.clone()))
-            .app_data(web::Data::new(friend_state.clone()))
-            .app_data(web::Data::new(regex_validator.clone()))
-            .wrap(middlewares::cors());
-        app.service(routes::auth::register)
-            .service(routes::auth::login)
-            .service(routes::auth::verify_user)
-            .service(routes::chat::ws_handler)
-            .service(routes::chat::get_chats)
-            .service(routes::chat::get_chat_messages)
-            .service(routes::chat::get_user)
-            .service(routes::friend::ws_handler)
-            .service(routes::friend::get_friend_req)
-            .service(routes::auth::upload_avatar)
-            .service(routes::auth::verify_email)
-            .service(routes::auth::logout)
-            .service(fs::Files::new("/uploads", "./uploads"))
-            .service(fs::Files::new("/", "./static").index_file("index.html"))
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    let hub = data.chat_state.hub.clone();
+    let served = HttpServer::new(move || app::build_app(data.clone()))
+        .bind((config.server.host.as_str(), config.server.port))?
+        .run()
+        .await;
+    hub.close().await;
+    served
 }
@@ -1,16 +1,18 @@
+use crate::config::{self, CorsConfig};
 use actix_cors::Cors;
 use actix_web::http::header;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-use std::env;
-use time::{Duration, OffsetDateTime};
+use time::OffsetDateTime;
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
     pub email: String,
+    // the sessions row this access token was issued for
+    pub sid: i32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,68 +22,110 @@ pub struct EmailVerify {
     pub email: String,
 }
 
-pub async fn create_user_table(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            username VARCHAR(255) NOT NULL UNIQUE,
-            email VARCHAR(255) NOT NULL UNIQUE,
-            password VARCHAR(255) NOT NULL,
-            verified BOOLEAN NOT NULL DEFAULT FALSE,
-            profile_picture TEXT UNIQUE,
-            biography VARCHAR(200)
-        )",
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
+const PASSWORD_RESET_PURPOSE: &str = "password_reset";
+
+/// Reset tokens carry the session version they were issued for, the reset
+/// bumps it so a token can only ever be redeemed once.
+#[derive(Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub sub: String,
+    pub exp: usize,
+    pub ver: i32,
+    pub purpose: String,
+}
+
+const MFA_PENDING_PURPOSE: &str = "mfa_pending";
+
+/// Handed out by `login` when the password was right but the account has
+/// two-factor enabled, it's only good for `/login/2fa`.
+#[derive(Serialize, Deserialize)]
+pub struct MfaPending {
+    pub sub: String,
+    pub exp: usize,
+    pub purpose: String,
 }
 
-pub fn generate_token(username: String, email: String) -> String {
-    let expiration = OffsetDateTime::now_utc() + Duration::days(1);
-    let key = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+pub fn generate_token(username: String, email: String, sid: i32) -> String {
+    // access tokens are short-lived, clients renew them through `/refresh`
+    // with the refresh token of their session
+    let expiration = OffsetDateTime::now_utc() + config::get().auth.access_token_ttl();
+    let key = &config::get().auth.jwt_secret;
 
     let claims = Claims {
         sub: username,
         exp: expiration.unix_timestamp() as usize,
-        email: email,
+        email,
+        sid,
     };
 
-    let token = encode(
+    encode(
         &Header::default(),
         &claims,
         &EncodingKey::from_secret(key.as_ref()),
     )
-    .unwrap();
-    token
+    .unwrap()
 }
 
 pub fn generate_verify_email_token(username: String, email: String) -> String {
-    let expiration = OffsetDateTime::now_utc() + Duration::hours(1);
-    let key = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let expiration = OffsetDateTime::now_utc() + config::get().auth.email_verification_ttl();
+    let key = &config::get().auth.jwt_secret;
 
     let email_verify = EmailVerify {
         sub: username,
         exp: expiration.unix_timestamp() as usize,
-        email: email,
+        email,
     };
 
-    let token = encode(
+    encode(
         &Header::default(),
         &email_verify,
         &EncodingKey::from_secret(key.as_ref()),
     )
-    .unwrap();
-    token
+    .unwrap()
 }
 
-pub fn verify_token(token: String) -> Result<Claims, String> {
-    let key = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let mut validation = Validation::default();
-    validation.required_spec_claims.remove("verified");
+pub fn generate_password_reset_token(email: String, ver: i32) -> String {
+    let expiration = OffsetDateTime::now_utc() + config::get().auth.password_reset_ttl();
+    let key = &config::get().auth.jwt_secret;
+
+    let password_reset = PasswordReset {
+        sub: email,
+        exp: expiration.unix_timestamp() as usize,
+        ver,
+        purpose: PASSWORD_RESET_PURPOSE.to_string(),
+    };
+
+    encode(
+        &Header::default(),
+        &password_reset,
+        &EncodingKey::from_secret(key.as_ref()),
+    )
+    .unwrap()
+}
+
+pub fn generate_mfa_pending_token(email: String) -> String {
+    let expiration = OffsetDateTime::now_utc() + config::get().auth.mfa_pending_ttl();
+    let key = &config::get().auth.jwt_secret;
+
+    let mfa_pending = MfaPending {
+        sub: email,
+        exp: expiration.unix_timestamp() as usize,
+        purpose: MFA_PENDING_PURPOSE.to_string(),
+    };
+
+    encode(
+        &Header::default(),
+        &mfa_pending,
+        &EncodingKey::from_secret(key.as_ref()),
+    )
+    .unwrap()
+}
+
+pub fn decode_token(token: &str) -> Result<Claims, String> {
+    let key = &config::get().auth.jwt_secret;
 
     match decode::<Claims>(
-        &token,
+        token,
         &DecodingKey::from_secret(key.as_ref()),
         &Validation::default(),
     ) {
@@ -93,8 +137,29 @@ pub fn verify_token(token: String) -> Result<Claims, String> {
     }
 }
 
+/// Decodes an access token and rejects it once its session was revoked
+/// (logout, `/sessions` or a password reset) or expired.
+pub async fn verify_token(pool: &PgPool, token: String) -> Result<Claims, String> {
+    let claims = decode_token(&token)?;
+
+    match sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM sessions WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW())",
+    )
+    .bind(claims.sid)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(true) => Ok(claims),
+        Ok(false) => Err("Invalid token".to_string()),
+        Err(e) => {
+            eprintln!("Error checking session: {}", e);
+            Err("Invalid token".to_string())
+        }
+    }
+}
+
 pub fn verify_email_confirmation_token(token: String) -> Result<EmailVerify, String> {
-    let key = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let key = &config::get().auth.jwt_secret;
     let mut validation = Validation::default();
     validation.required_spec_claims.remove("verified");
 
@@ -111,12 +176,80 @@ pub fn verify_email_confirmation_token(token: String) -> Result<EmailVerify, Str
     }
 }
 
-pub fn cors() -> Cors {
-    Cors::default()
-        .allowed_origin("http://localhost:8080")
-        .allowed_origin("http://localhost:1230")
+pub fn verify_password_reset_token(token: String) -> Result<PasswordReset, String> {
+    let key = &config::get().auth.jwt_secret;
+
+    match decode::<PasswordReset>(
+        &token,
+        &DecodingKey::from_secret(key.as_ref()),
+        &Validation::default(),
+    ) {
+        Ok(token_data) if token_data.claims.purpose == PASSWORD_RESET_PURPOSE => {
+            Ok(token_data.claims)
+        }
+        Ok(_) => Err("Invalid token".to_string()),
+        Err(e) => {
+            eprintln!("Token verification error: {:?}", e);
+            Err("Invalid token".to_string())
+        }
+    }
+}
+
+pub fn verify_mfa_pending_token(token: &str) -> Result<MfaPending, String> {
+    let key = &config::get().auth.jwt_secret;
+
+    match decode::<MfaPending>(
+        token,
+        &DecodingKey::from_secret(key.as_ref()),
+        &Validation::default(),
+    ) {
+        Ok(token_data) if token_data.claims.purpose == MFA_PENDING_PURPOSE => Ok(token_data.claims),
+        Ok(_) => Err("Invalid token".to_string()),
+        Err(e) => {
+            eprintln!("Token verification error: {:?}", e);
+            Err("Invalid token".to_string())
+        }
+    }
+}
+
+pub fn cors(config: &CorsConfig) -> Cors {
+    config
+        .allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
         .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
         .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
         .allowed_header(header::CONTENT_TYPE)
         .max_age(3600)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_tokens_cannot_reset_passwords() {
+        config::init_for_tests();
+        let session = generate_token("a@kutter.dev".to_string(), "alice".to_string(), 0);
+        assert!(verify_password_reset_token(session).is_err());
+
+        let reset = generate_password_reset_token("a@kutter.dev".to_string(), 0);
+        assert!(decode_token(&reset).is_err());
+        assert_eq!(verify_password_reset_token(reset).unwrap().ver, 0);
+    }
+
+    #[test]
+    fn mfa_pending_tokens_are_not_sessions() {
+        config::init_for_tests();
+        let pending = generate_mfa_pending_token("a@kutter.dev".to_string());
+        assert!(decode_token(&pending).is_err());
+        assert!(verify_password_reset_token(pending.clone()).is_err());
+        assert_eq!(
+            verify_mfa_pending_token(&pending).unwrap().sub,
+            "a@kutter.dev"
+        );
+
+        let session = generate_token("a@kutter.dev".to_string(), "alice".to_string(), 0);
+        assert!(verify_mfa_pending_token(&session).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Framing shared by the websocket endpoints.
+//!
+//! Clients pick a version by offering `kutter.v<N>` subprotocols when they
+//! connect. From version 1 on every request is `{id, action, payload}` and
+//! the server answers each one with an `ack` or an `error` frame carrying the
+//! same `id`. Clients that offer no version get `LEGACY`: no acks and errors
+//! shaped `{action: "error", payload: {message}}`.
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_ws::Session;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+pub const LEGACY: u32 = 0;
+pub const CURRENT: u32 = 1;
+pub const SUPPORTED: [u32; 1] = [CURRENT];
+
+const SUBPROTOCOL_PREFIX: &str = "kutter.v";
+
+/// The correlation id of a request, chosen by the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame isn't a valid request, nothing was done.
+    BadRequest,
+    RateLimited,
+    /// The request was understood but refused or failed, see the message.
+    Rejected,
+}
+
+/// An incoming frame, `A` being the `#[serde(tag = "action", content =
+/// "payload")]` enum of the endpoint.
+#[derive(Debug)]
+pub struct Request<A> {
+    pub id: Option<RequestId>,
+    /// The raw `action`, for rate limiting.
+    pub name: String,
+    pub action: A,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    id: Option<RequestId>,
+    action: String,
+    #[serde(default)]
+    payload: Value,
+}
+
+#[derive(Debug)]
+pub struct Rejection {
+    pub id: Option<RequestId>,
+    pub message: String,
+}
+
+/// Picks the highest version both sides speak. Clients that offered
+/// versions, none of which is supported, are turned away since browsers
+/// drop a socket whose subprotocol wasn't accepted anyway.
+pub fn negotiate(req: &HttpRequest) -> Result<u32, HttpResponse> {
+    let offered: Vec<u32> = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|protocol| {
+            protocol
+                .trim()
+                .strip_prefix(SUBPROTOCOL_PREFIX)?
+                .parse()
+                .ok()
+        })
+        .collect();
+
+    if offered.is_empty() {
+        return Ok(LEGACY);
+    }
+    offered
+        .into_iter()
+        .filter(|version| SUPPORTED.contains(version))
+        .max()
+        .ok_or_else(|| {
+            HttpResponse::BadRequest().json(format!(
+                "Unsupported protocol version, this server speaks {}{}",
+                SUBPROTOCOL_PREFIX, CURRENT
+            ))
+        })
+}
+
+/// Confirms the negotiated version in the handshake response.
+pub fn accept(response: &mut HttpResponse, version: u32) {
+    if version == LEGACY {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("{}{}", SUBPROTOCOL_PREFIX, version)) {
+        response
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+    }
+}
+
+pub fn parse<A: DeserializeOwned>(text: &str, version: u32) -> Result<Request<A>, Rejection> {
+    let envelope = serde_json::from_str::<Envelope>(text).map_err(|e| Rejection {
+        id: None,
+        message: format!("Invalid frame: {}", e),
+    })?;
+
+    if version >= 1 && envelope.id.is_none() {
+        return Err(Rejection {
+            id: None,
+            message: "Every request needs an id".to_string(),
+        });
+    }
+
+    let action = json!({"action": envelope.action, "payload": envelope.payload});
+    match serde_json::from_value::<A>(action) {
+        Ok(action) => Ok(Request {
+            id: envelope.id,
+            name: envelope.action,
+            action,
+        }),
+        Err(e) => Err(Rejection {
+            id: envelope.id,
+            message: format!("Invalid {} request: {}", envelope.action, e),
+        }),
+    }
+}
+
+/// Answers one request, see the module docs.
+pub struct Reply {
+    session: Session,
+    version: u32,
+    id: Option<RequestId>,
+    failed: bool,
+}
+
+impl Reply {
+    pub fn new(session: Session, version: u32, id: Option<RequestId>) -> Self {
+        Self {
+            session,
+            version,
+            id,
+            failed: false,
+        }
+    }
+
+    /// Answers a frame `parse` refused.
+    pub async fn reject(session: Session, version: u32, rejection: Rejection) {
+        Self::new(session, version, rejection.id)
+            .fail(ErrorCode::BadRequest, &rejection.message)
+            .await;
+    }
+
+    pub async fn error(&mut self, message: &str) {
+        self.fail(ErrorCode::Rejected, message).await;
+    }
+
+    pub async fn fail(&mut self, code: ErrorCode, message: &str) {
+        self.failed = true;
+        let frame = if self.version == LEGACY {
+            json!({"action": "error", "payload": {"message": message}})
+        } else {
+            json!({"action": "error", "id": self.id, "code": code, "message": message})
+        };
+        let _ = self.session.text(frame.to_string()).await;
+    }
+
+    /// Acknowledges the request unless an error was already sent for it.
+    pub async fn ack(mut self) {
+        if self.failed || self.version == LEGACY {
+            return;
+        }
+        let frame = json!({"action": "ack", "id": self.id});
+        let _ = self.session.text(frame.to_string()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "action", content = "payload", rename_all = "snake_case")]
+    enum Action {
+        Ping { n: i32 },
+    }
+
+    #[test]
+    fn requests_are_parsed_into_the_action_enum() {
+        let request =
+            parse::<Action>(r#"{"id": 7, "action": "ping", "payload": {"n": 1}}"#, 1).unwrap();
+        assert_eq!(request.id, Some(RequestId::Number(7)));
+        assert_eq!(request.name, "ping");
+        assert!(matches!(request.action, Action::Ping { n: 1 }));
+
+        let rejection =
+            parse::<Action>(r#"{"id": "a", "action": "ping", "payload": {}}"#, 1).unwrap_err();
+        assert_eq!(rejection.id, Some(RequestId::Text("a".to_string())));
+
+        assert!(parse::<Action>(r#"{"id": 1, "action": "pong", "payload": {}}"#, 1).is_err());
+        assert!(parse::<Action>(r#"{"action": "ping", "payload": {"n": 1}}"#, 1).is_err());
+        assert!(parse::<Action>(r#"{"action": "ping", "payload": {"n": 1}}"#, LEGACY).is_ok());
+    }
+
+    #[test]
+    fn the_highest_common_version_is_picked() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(negotiate(&req).unwrap(), LEGACY);
+
+        let req = TestRequest::default()
+            .insert_header((header::SEC_WEBSOCKET_PROTOCOL, "chat, kutter.v1"))
+            .to_http_request();
+        assert_eq!(negotiate(&req).unwrap(), 1);
+
+        let req = TestRequest::default()
+            .insert_header((header::SEC_WEBSOCKET_PROTOCOL, "kutter.v99"))
+            .to_http_request();
+        assert!(negotiate(&req).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::RateLimitConfig;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse, http::header, web};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Paths throttled per client IP by `limit_auth_requests`.
+const AUTH_PATHS: [&str; 7] = [
+    "/login",
+    "/login/2fa",
+    "/register",
+    "/verify",
+    "/forgot_password",
+    "/reset_password",
+    "/refresh",
+];
+
+/// Past this many tracked keys, full buckets are dropped on the next check,
+/// they behave exactly like a missing one.
+const MAX_BUCKETS: usize = 10_000;
+
+/// `requests` tokens per `period`, which is also the burst size. Written
+/// `<requests>/<seconds>` in the configuration, e.g. `30/60`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Limit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    pub const fn new(requests: u32, seconds: u64) -> Self {
+        Self {
+            requests,
+            period: Duration::from_secs(seconds),
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let (requests, seconds) = value.trim().split_once('/')?;
+        let requests = requests.trim().parse().ok().filter(|&r| r > 0)?;
+        let seconds = seconds.trim().parse().ok().filter(|&s| s > 0)?;
+        Some(Self::new(requests, seconds))
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value)
+            .ok_or_else(|| format!("{} doesn't look like <requests>/<seconds>", value))
+    }
+}
+
+impl TryFrom<String> for Limit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per key (IP, account, ...).
+pub struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or says how long until the next
+    /// one is available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let capacity = self.limit.requests as f64;
+        let rate = self.limit.tokens_per_second();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Every limiter of the app, shared through `web::Data`.
+pub struct RateLimits {
+    /// Per client IP, on `AUTH_PATHS`.
+    pub auth: RateLimiter,
+    /// Per account, on login attempts.
+    pub logins: RateLimiter,
+    /// Per account, on verification and password reset emails.
+    pub emails: RateLimiter,
+    /// Per user, on every websocket action.
+    pub ws_actions: RateLimiter,
+    /// Per user, on `new_message` (`chat.new_message` on `/socket`).
+    pub ws_messages: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            auth: RateLimiter::new(config.auth),
+            logins: RateLimiter::new(config.logins),
+            emails: RateLimiter::new(config.emails),
+            ws_actions: RateLimiter::new(config.ws_actions),
+            ws_messages: RateLimiter::new(config.ws_messages),
+        }
+    }
+
+    pub fn check_ws_action(&self, username: &str, action: &str) -> Result<(), Duration> {
+        self.ws_actions.check(username)?;
+        if action.strip_prefix("chat.").unwrap_or(action) == "new_message" {
+            self.ws_messages.check(username)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn too_many_requests(retry_after: Duration, message: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            header::RETRY_AFTER,
+            retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+        ))
+        .json(json!({
+            "status": "error",
+            "message": message,
+        }))
+}
+
+/// Throttles `AUTH_PATHS` per client IP. The peer address is used rather
+/// than forwarding headers, which the client controls.
+pub async fn limit_auth_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if AUTH_PATHS.contains(&req.path())
+        && let Some(limits) = req.app_data::<web::Data<RateLimits>>()
+        && let Some(peer) = req.peer_addr()
+        && let Err(retry_after) = limits.auth.check(&peer.ip().to_string())
+    {
+        return Ok(req
+            .into_response(too_many_requests(
+                retry_after,
+                "too many requests, try again later",
+            ))
+            .map_into_right_body());
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_parse_from_requests_per_seconds() {
+        assert_eq!(Limit::parse("30/60"), Some(Limit::new(30, 60)));
+        assert_eq!(Limit::parse(" 5 / 1 "), Some(Limit::new(5, 1)));
+        assert_eq!(Limit::parse("0/60"), None);
+        assert_eq!(Limit::parse("30"), None);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(Limit::new(2, 10));
+        let start = Instant::now();
+
+        assert!(limiter.check_at("alice", start).is_ok());
+        assert!(limiter.check_at("alice", start).is_ok());
+        let retry_after = limiter.check_at("alice", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(5));
+
+        // other keys have their own bucket
+        assert!(limiter.check_at("bob", start).is_ok());
+
+        assert!(
+            limiter
+                .check_at("alice", start + Duration::from_secs(5))
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .check_at("alice", start + Duration::from_secs(5))
+                .is_err()
+        );
+    }
+}
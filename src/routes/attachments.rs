@@ -0,0 +1,293 @@
+use crate::config;
+use crate::middlewares::verify_token;
+use crate::routes::chat::AppState;
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{Error, HttpRequest, HttpResponse, get, mime, post, web};
+use futures_util::StreamExt;
+use sanitize_filename::sanitize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgConnection, PgPool};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const INLINE_MIME_PREFIXES: [&str; 3] = ["image/", "video/", "audio/"];
+const ALLOWED_MIME_TYPES: [&str; 3] = ["application/pdf", "application/zip", "text/plain"];
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Attachment {
+    pub id: i32,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct MessageAttachment {
+    message_id: i32,
+    #[sqlx(flatten)]
+    attachment: Attachment,
+}
+
+#[derive(Debug, FromRow)]
+struct StoredAttachment {
+    sha256: String,
+    filename: String,
+    mime_type: String,
+}
+
+/// Detects the type from the file's magic bytes, the name and the declared
+/// content type are never trusted. `None` for types we don't accept.
+fn sniff_mime_type(data: &[u8]) -> Option<String> {
+    let mime_type = match infer::get(data) {
+        Some(kind) => kind.mime_type().to_string(),
+        None if std::str::from_utf8(data).is_ok() => "text/plain".to_string(),
+        None => return None,
+    };
+
+    let allowed = INLINE_MIME_PREFIXES
+        .iter()
+        .any(|prefix| mime_type.starts_with(prefix))
+        || ALLOWED_MIME_TYPES.contains(&mime_type.as_str());
+    allowed.then_some(mime_type)
+}
+
+/// Files are stored once per content hash, outside of what `get_upload`
+/// serves publicly.
+fn attachment_path(sha256: &str) -> PathBuf {
+    config::get().uploads.attachments_dir().join(sha256)
+}
+
+/// Attachments of each of `message_ids`, in upload order.
+pub async fn fetch_attachments(
+    pool: &PgPool,
+    message_ids: &[i32],
+) -> Result<HashMap<i32, Vec<Attachment>>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, MessageAttachment>(
+        "SELECT message_id, id, filename, mime_type, size FROM attachments WHERE message_id = ANY($1) ORDER BY id",
+    )
+    .bind(message_ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut attachments: HashMap<i32, Vec<Attachment>> = HashMap::new();
+    for row in rows {
+        attachments
+            .entry(row.message_id)
+            .or_default()
+            .push(row.attachment);
+    }
+    Ok(attachments)
+}
+
+/// Links the uploads of `username` among `ids` that aren't part of a message
+/// yet to `message_id`, returning the ones it claimed.
+pub async fn attach(
+    conn: &mut PgConnection,
+    message_id: i32,
+    username: &str,
+    ids: &[i32],
+) -> Result<Vec<Attachment>, sqlx::Error> {
+    let mut attachments = sqlx::query_as::<_, Attachment>(
+        "UPDATE attachments SET message_id = $1 WHERE id = ANY($2) AND uploader = $3 AND message_id IS NULL RETURNING id, filename, mime_type, size",
+    )
+    .bind(message_id)
+    .bind(ids)
+    .bind(username)
+    .fetch_all(conn)
+    .await?;
+    attachments.sort_by_key(|attachment| attachment.id);
+    Ok(attachments)
+}
+
+#[post("/attachments")]
+pub async fn upload_attachment(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let username = claims.email.clone();
+    let max_size = config::get().uploads.max_attachment_size;
+
+    let mut field = match payload.next().await {
+        Some(Ok(field)) => field,
+        Some(Err(_)) => return Ok(HttpResponse::BadRequest().json("Failed to read file")),
+        None => return Ok(HttpResponse::BadRequest().json("No file received")),
+    };
+
+    let filename = field
+        .content_disposition()
+        .and_then(|cd| cd.get_filename())
+        .map(sanitize)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "attachment".to_string());
+
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => return Ok(HttpResponse::BadRequest().json("Failed to read file")),
+        };
+        if data.len() + chunk.len() > max_size {
+            return Ok(HttpResponse::PayloadTooLarge().json(format!(
+                "Attachments can be at most {} MiB",
+                max_size / 1024 / 1024
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    if data.is_empty() {
+        return Ok(HttpResponse::BadRequest().json("No file received"));
+    }
+
+    let mime_type = match sniff_mime_type(&data) {
+        Some(mime_type) => mime_type,
+        None => return Ok(HttpResponse::UnsupportedMediaType().json("Unsupported file type")),
+    };
+
+    let sha256 = hex::encode(Sha256::digest(&data));
+    let path = attachment_path(&sha256);
+    if !path.exists() {
+        if let Err(e) = tokio::fs::create_dir_all(config::get().uploads.attachments_dir()).await {
+            eprintln!("Error creating attachments directory: {}", e);
+            return Ok(HttpResponse::InternalServerError().json("Failed to save file"));
+        }
+        // write under a temporary name first so a half written file is never
+        // picked up as the content of its hash
+        let tmp_path = path.with_extension(format!("tmp-{}", rand::random::<u64>()));
+        let written = match tokio::fs::write(&tmp_path, &data).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            eprintln!("Error saving attachment: {}", e);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Ok(HttpResponse::InternalServerError().json("Failed to save file"));
+        }
+    }
+
+    match sqlx::query_as::<_, Attachment>(
+        "INSERT INTO attachments (sha256, uploader, filename, mime_type, size) VALUES ($1, $2, $3, $4, $5) RETURNING id, filename, mime_type, size",
+    )
+    .bind(&sha256)
+    .bind(&username)
+    .bind(&filename)
+    .bind(&mime_type)
+    .bind(data.len() as i64)
+    .fetch_one(&state.db_pool)
+    .await
+    {
+        Ok(attachment) => Ok(HttpResponse::Created().json(attachment)),
+        Err(e) => {
+            eprintln!("Error saving attachment: {}", e);
+            Ok(HttpResponse::InternalServerError().json("Failed to save file"))
+        }
+    }
+}
+
+#[get("/attachments/{attachment_id}")]
+pub async fn get_attachment(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let username = claims.email.clone();
+    let attachment_id = path.into_inner();
+
+    // the uploader can fetch it before sending, afterwards only the members
+    // of the chat the message is in
+    let stored = match sqlx::query_as::<_, StoredAttachment>(
+        r#"
+        SELECT a.sha256, a.filename, a.mime_type FROM attachments a
+        LEFT JOIN messages m ON m.id = a.message_id
+        WHERE a.id = $1 AND (
+            a.uploader = $2
+            OR EXISTS(
+                SELECT 1 FROM chat_members cm
+                WHERE cm.chat_id = m.chat_id AND cm.username = $2
+            )
+        )
+        "#,
+    )
+    .bind(attachment_id)
+    .bind(&username)
+    .fetch_optional(&state.db_pool)
+    .await
+    {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return Ok(HttpResponse::NotFound().json("Attachment not found")),
+        Err(e) => {
+            eprintln!("Error fetching attachment: {}", e);
+            return Ok(HttpResponse::InternalServerError().json("Error fetching attachment"));
+        }
+    };
+
+    let mime_type = stored
+        .mime_type
+        .parse::<mime::Mime>()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let disposition = if INLINE_MIME_PREFIXES
+        .iter()
+        .any(|prefix| stored.mime_type.starts_with(prefix))
+    {
+        DispositionType::Inline
+    } else {
+        DispositionType::Attachment
+    };
+
+    let file = NamedFile::open_async(attachment_path(&stored.sha256))
+        .await?
+        .set_content_type(mime_type)
+        .set_content_disposition(ContentDisposition {
+            disposition,
+            parameters: vec![DispositionParam::Filename(stored.filename)],
+        });
+
+    let mut response = file.into_response(&req);
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        header::HeaderValue::from_static("nosniff"),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mime_type_comes_from_the_content() {
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0];
+        assert_eq!(sniff_mime_type(&png).as_deref(), Some("image/png"));
+        assert_eq!(
+            sniff_mime_type(b"just some notes").as_deref(),
+            Some("text/plain")
+        );
+        // a windows executable is rejected whatever it is named
+        assert_eq!(sniff_mime_type(b"MZ\x90\x00\x03\x00\x00\x00\xff\xfe"), None);
+    }
+}
@@ -1,77 +1,74 @@
 use crate::RegexValidator;
+use crate::config;
+use crate::mailer::MailService;
 use crate::middlewares::{
-    generate_token, generate_verify_email_token, verify_email_confirmation_token, verify_token,
+    generate_mfa_pending_token, generate_password_reset_token, generate_verify_email_token,
+    verify_email_confirmation_token, verify_mfa_pending_token, verify_password_reset_token,
+    verify_token,
 };
+use crate::rate_limit::{RateLimits, too_many_requests};
+use crate::routes::chat::AppState;
+use crate::routes::presence;
+use crate::routes::sessions::{self, REFRESH_COOKIE, expired_cookies};
+use crate::routes::two_factor;
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{
-    HttpRequest, HttpResponse, Responder,
-    cookie::{self, Cookie, SameSite},
-    delete, get, post, web,
-};
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
 use bcrypt::{DEFAULT_COST, hash, verify};
+use chrono::{DateTime, Utc};
 use futures_util::StreamExt;
-use lettre::message::Mailbox;
-use lettre::message::header::ContentType;
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
 use sanitize_filename::sanitize;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{FromRow, PgPool};
+use std::fs::File;
 use std::io::Write;
-use std::{env, fs::File};
-use time::Duration;
-
-fn create_cookie(token: String) -> Cookie<'static> {
-    Cookie::build("token", token)
-        .path("/")
-        .secure(true)
-        .same_site(SameSite::Lax)
-        .http_only(true)
-        .max_age(Duration::days(1))
-        .finish()
-}
+use std::sync::Arc;
 
 fn verify_cookie(req: HttpRequest) -> Option<String> {
     req.cookie("token").map(|c| c.value().to_string())
 }
 
-pub fn send_email(email: String, username: String, url: String) -> Result<(), String> {
-    let from_address = env::var("SMTP_USER")
-        .map_err(|e| format!("Failed to load SMTP_USER: {}", e))?
-        .parse()
-        .map_err(|e| format!("Invalid sender email format: {}", e))?;
-
-    let to_address = email
-        .parse()
-        .map_err(|e| format!("Invalid recipient email format: {}", e))?;
-
-    let email_message = Message::builder()
-        .from(Mailbox::new(Some("Kutter".to_owned()), from_address))
-        .to(Mailbox::new(Some(username.clone()), to_address))
-        .subject("Verify your account!")
-        .header(ContentType::TEXT_HTML)
-        .body(format!(
-            "Hey {}, <a href=\"https://kutter.ryterm.xyz/verify_email?token={}\">click here</a> to verify your email :3",
-            username, url
-        ))
-        .map_err(|e| format!("Failed to build email: {}", e))?;
-
-    let creds = Credentials::new(
-        env::var("SMTP_USER").map_err(|e| format!("Failed to load SMTP_USER: {}", e))?,
-        env::var("SMTP_PSSWRD").map_err(|e| format!("Failed to load SMTP_PSSWRD: {}", e))?,
-    );
-
-    let mailer = SmtpTransport::relay("smtp.gmail.com")
-        .map_err(|e| format!("Failed to create mailer: {}", e))?
-        .credentials(creds)
-        .build();
-
-    mailer
-        .send(&email_message)
-        .map_err(|e| format!("Failed to send email: {}", e))?;
-
-    Ok(())
+/// Failed logins (password or second factor) in a row before the account
+/// is locked, every further failure doubles the lock up to the maximum.
+const LOCKOUT_THRESHOLD: i32 = 5;
+const LOCKOUT_BASE_SECS: f64 = 30.0;
+const LOCKOUT_MAX_SECS: f64 = 3600.0;
+
+fn locked_out(user: &User) -> Option<HttpResponse> {
+    let remaining = (user.locked_until? - Utc::now()).to_std().ok()?;
+    Some(too_many_requests(
+        remaining,
+        "too many failed logins, try again later",
+    ))
+}
+
+async fn record_failed_login(pool: &PgPool, username: &str) {
+    // the exponent is capped so the interval can't overflow, the lock is
+    // capped by LEAST anyway
+    if let Err(e) = sqlx::query(
+        r#"
+        UPDATE users SET
+            failed_logins = failed_logins + 1,
+            locked_until = CASE
+                WHEN failed_logins + 1 >= $2 THEN NOW() + make_interval(secs => LEAST(
+                    $3 * power(2, LEAST(failed_logins + 1 - $2, 16)),
+                    $4
+                ))
+                ELSE locked_until
+            END
+        WHERE username = $1
+        "#,
+    )
+    .bind(username)
+    .bind(LOCKOUT_THRESHOLD)
+    .bind(LOCKOUT_BASE_SECS)
+    .bind(LOCKOUT_MAX_SECS)
+    .execute(pool)
+    .await
+    {
+        eprintln!("Error recording failed login: {}", e);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -82,6 +79,9 @@ struct User {
     verified: bool,
     profile_picture: Option<String>,
     biography: Option<String>,
+    session_version: i32,
+    totp_enabled: bool,
+    locked_until: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -102,11 +102,23 @@ struct VerificationData {
     token: String,
 }
 
+#[derive(Deserialize)]
+struct ForgotPasswordForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    token: String,
+    password: String,
+}
+
 #[post("/register")]
 pub async fn register(
     pool: web::Data<PgPool>,
     req: web::Json<RegisterForm>,
     validator: web::Data<RegexValidator>,
+    mail: web::Data<MailService>,
 ) -> impl Responder {
     let username = req.username.clone();
     let email = req.email.clone();
@@ -150,7 +162,7 @@ pub async fn register(
         }
     };
 
-    let url = generate_verify_email_token(username.clone(), email.clone());
+    let token = generate_verify_email_token(username.clone(), email.clone());
 
     let email_exists = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
         .bind(&email)
@@ -184,7 +196,9 @@ pub async fn register(
 
     match insert_result {
         Ok(user) => {
-            if let Err(_) = send_email(email, username, url) {
+            let verification = mail.verification_email(&email, &username, &token);
+            if let Err(e) = mail.send(&verification).await {
+                eprintln!("Error sending verification email: {}", e);
                 return HttpResponse::InternalServerError().json(json!({
                     "status": "error",
                     "message": "failed to send verification email",
@@ -204,10 +218,19 @@ pub async fn register(
 }
 
 #[post("/login")]
-pub async fn login(pool: web::Data<PgPool>, req: web::Json<LoginForm>) -> impl Responder {
+pub async fn login(
+    pool: web::Data<PgPool>,
+    http_req: HttpRequest,
+    req: web::Json<LoginForm>,
+    rate_limits: web::Data<RateLimits>,
+) -> impl Responder {
     let email = req.email.clone();
     let password = req.password.clone();
 
+    if let Err(retry_after) = rate_limits.logins.check(&email.to_lowercase()) {
+        return too_many_requests(retry_after, "too many login attempts, try again later");
+    }
+
     let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
         .bind(&email)
         .fetch_optional(pool.get_ref())
@@ -232,6 +255,10 @@ pub async fn login(pool: web::Data<PgPool>, req: web::Json<LoginForm>) -> impl R
         }
     };
 
+    if let Some(response) = locked_out(&user) {
+        return response;
+    }
+
     let password_valid = match verify(&password, &user.password) {
         Ok(valid) => valid,
         Err(_) => {
@@ -244,44 +271,149 @@ pub async fn login(pool: web::Data<PgPool>, req: web::Json<LoginForm>) -> impl R
 
     match password_valid {
         true => {
-            let token = generate_token(user.email.clone(), user.username.clone());
-            let cookie = create_cookie(token);
-            let is_verified = match sqlx::query_as::<_, User>(
+            let is_verified = sqlx::query_as::<_, User>(
                 "SELECT * FROM users WHERE email = $1 AND verified = true",
             )
             .bind(&email)
             .fetch_one(pool.as_ref())
             .await
-            {
-                Ok(_) => true,
-                Err(_) => false,
-            };
+            .is_ok();
 
-            if is_verified {
-                return HttpResponse::Ok().cookie(cookie).json(json!({
-                    "status": "success",
-                    "message": "user logged in",
-                    "user": {
-                        "username": user.username,
-                        "email": user.email
-                    }
-                }));
-            } else {
+            if !is_verified {
                 return HttpResponse::Unauthorized().json(json!({
                     "status": "error",
                     "message": "email not verified",
                 }));
             }
+
+            // the password alone isn't enough, `/login/2fa` finishes the login
+            if user.totp_enabled {
+                return HttpResponse::Ok().json(json!({
+                    "status": "mfa_required",
+                    "message": "two-factor code required",
+                    "mfa_token": generate_mfa_pending_token(user.email),
+                }));
+            }
+
+            start_session(pool.get_ref(), &http_req, &user).await
         }
         false => {
-            return HttpResponse::Unauthorized().json(json!({
+            record_failed_login(pool.get_ref(), &user.username).await;
+            HttpResponse::Unauthorized().json(json!({
                 "status": "error",
                 "message": "invalid password",
+            }))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginTwoFactorForm {
+    mfa_token: String,
+    code: String,
+}
+
+#[post("/login/2fa")]
+pub async fn login_two_factor(
+    pool: web::Data<PgPool>,
+    http_req: HttpRequest,
+    req: web::Json<LoginTwoFactorForm>,
+    rate_limits: web::Data<RateLimits>,
+) -> impl Responder {
+    let pending = match verify_mfa_pending_token(&req.mfa_token) {
+        Ok(pending) => pending,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid or expired token",
             }));
         }
+    };
+
+    if let Err(retry_after) = rate_limits.logins.check(&pending.sub.to_lowercase()) {
+        return too_many_requests(retry_after, "too many login attempts, try again later");
+    }
+
+    let user =
+        match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1 AND verified = true")
+            .bind(&pending.sub)
+            .fetch_optional(pool.get_ref())
+            .await
+        {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                return HttpResponse::Unauthorized().json(json!({
+                    "status": "error",
+                    "message": "user not found",
+                }));
+            }
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to get user",
+                }));
+            }
+        };
+
+    if let Some(response) = locked_out(&user) {
+        return response;
+    }
+
+    match two_factor::verify_second_factor(pool.get_ref(), &user.username, &req.code).await {
+        Ok(true) => start_session(pool.get_ref(), &http_req, &user).await,
+        Ok(false) => {
+            record_failed_login(pool.get_ref(), &user.username).await;
+            HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid code",
+            }))
+        }
+        Err(e) => {
+            eprintln!("Error checking second factor: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to verify code",
+            }))
+        }
     }
 }
 
+async fn start_session(pool: &PgPool, http_req: &HttpRequest, user: &User) -> HttpResponse {
+    if let Err(e) = sqlx::query(
+        "UPDATE users SET failed_logins = 0, locked_until = NULL WHERE username = $1 AND failed_logins > 0",
+    )
+    .bind(&user.username)
+    .execute(pool)
+    .await
+    {
+        eprintln!("Error clearing failed logins: {}", e);
+    }
+
+    let cookies = match sessions::start_session(pool, http_req, &user.username, &user.email).await {
+        Ok(cookies) => cookies,
+        Err(e) => {
+            eprintln!("Error creating session: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to create session",
+            }));
+        }
+    };
+
+    let mut response = HttpResponse::Ok();
+    for cookie in cookies {
+        response.cookie(cookie);
+    }
+    response.json(json!({
+        "status": "success",
+        "message": "user logged in",
+        "user": {
+            "username": user.username,
+            "email": user.email
+        }
+    }))
+}
+
 #[post("/upload_avatar")]
 pub async fn upload_avatar(
     req: HttpRequest,
@@ -298,7 +430,7 @@ pub async fn upload_avatar(
         }
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(pool.get_ref(), token).await {
         Ok(c) => c,
         Err(_) => {
             return HttpResponse::Unauthorized().json(json!({
@@ -319,7 +451,8 @@ pub async fn upload_avatar(
             }
         };
 
-        if let Err(_) = std::fs::create_dir_all("./uploads") {
+        let uploads = &config::get().uploads;
+        if std::fs::create_dir_all(&uploads.dir).is_err() {
             return HttpResponse::InternalServerError().json(json!({
                 "status": "error",
                 "message": "failed to create upload directory"
@@ -328,7 +461,7 @@ pub async fn upload_avatar(
 
         let username = sanitize(&claims.email);
         let filename = format!("{}.png", username);
-        let filepath = format!("./uploads/{}", filename);
+        let filepath = uploads.dir.join(&filename);
 
         let mut f = match File::create(&filepath) {
             Ok(file) => file,
@@ -340,6 +473,7 @@ pub async fn upload_avatar(
             }
         };
 
+        let mut size = 0;
         while let Some(chunk) = field.next().await {
             let data = match chunk {
                 Ok(c) => c,
@@ -347,7 +481,19 @@ pub async fn upload_avatar(
                     continue;
                 }
             };
-            if let Err(_) = f.write_all(&data) {
+            size += data.len();
+            if size > uploads.max_avatar_size {
+                drop(f);
+                let _ = std::fs::remove_file(&filepath);
+                return HttpResponse::PayloadTooLarge().json(json!({
+                    "status": "error",
+                    "message": format!(
+                        "avatars can be at most {} MiB",
+                        uploads.max_avatar_size / 1024 / 1024
+                    )
+                }));
+            }
+            if f.write_all(&data).is_err() {
                 return HttpResponse::InternalServerError().json(json!({
                     "status": "error",
                     "message": "failed to save file"
@@ -390,8 +536,24 @@ pub async fn upload_avatar(
     }
 }
 
+/// Serves avatars, only the top level of the uploads dir is public since
+/// attachments live in a sub directory and go through `get_attachment`.
+#[get("/uploads/{filename}")]
+pub async fn get_upload(path: web::Path<String>) -> actix_web::Result<NamedFile> {
+    let filepath = config::get().uploads.dir.join(sanitize(path.into_inner()));
+    if !filepath.is_file() {
+        return Err(actix_web::error::ErrorNotFound("file not found"));
+    }
+    Ok(NamedFile::open_async(filepath).await?)
+}
+
 #[get("/verify")]
-pub async fn verify_user(req: HttpRequest, pool: web::Data<PgPool>) -> impl Responder {
+pub async fn verify_user(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    rate_limits: web::Data<RateLimits>,
+    mail: web::Data<MailService>,
+) -> impl Responder {
     let token = match verify_cookie(req) {
         Some(token) => token,
         None => {
@@ -402,7 +564,7 @@ pub async fn verify_user(req: HttpRequest, pool: web::Data<PgPool>) -> impl Resp
         }
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(pool.get_ref(), token).await {
         Ok(claims) => claims,
         Err(_) => {
             return HttpResponse::Ok().json(json!({
@@ -428,8 +590,13 @@ pub async fn verify_user(req: HttpRequest, pool: web::Data<PgPool>) -> impl Resp
             }
         })),
         _ => {
-            let url = generate_verify_email_token(claims.email.clone(), claims.sub.clone());
-            let _ = send_email(claims.sub, claims.email, url);
+            if rate_limits.emails.check(&claims.sub).is_ok() {
+                let token = generate_verify_email_token(claims.email.clone(), claims.sub.clone());
+                let verification = mail.verification_email(&claims.sub, &claims.email, &token);
+                if let Err(e) = mail.send(&verification).await {
+                    eprintln!("Error sending verification email: {}", e);
+                }
+            }
             HttpResponse::BadRequest().json(json!({
                 "status": "error",
                 "message": "User doesn't exist or not verified."
@@ -493,14 +660,143 @@ pub async fn verify_email(
     }
 }
 
+#[post("/forgot_password")]
+pub async fn forgot_password(
+    pool: web::Data<PgPool>,
+    req: web::Json<ForgotPasswordForm>,
+    rate_limits: web::Data<RateLimits>,
+    mail: web::Data<MailService>,
+) -> impl Responder {
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(&req.email)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(user) => user,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to get user",
+            }));
+        }
+    };
+
+    // same answer whether or not the account exists, so this can't be used
+    // to find out which emails are registered, nor to flood an inbox
+    if let Some(user) = user
+        && rate_limits.emails.check(&user.email).is_ok()
+    {
+        let token = generate_password_reset_token(user.email.clone(), user.session_version);
+        let reset = mail.password_reset_email(&user.email, &user.username, &token);
+        if let Err(e) = mail.send(&reset).await {
+            eprintln!("Error sending password reset email: {}", e);
+        }
+    }
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "message": "if this email is registered, a reset link is on its way",
+    }))
+}
+
+#[post("/reset_password")]
+pub async fn reset_password(
+    pool: web::Data<PgPool>,
+    req: web::Json<ResetPasswordForm>,
+    validator: web::Data<RegexValidator>,
+    chat_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    let reset = match verify_password_reset_token(req.token.clone()) {
+        Ok(reset) => reset,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "invalid or expired token",
+            }));
+        }
+    };
+
+    if !validator.validate_password(&req.password) {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "password must be at least 6 characters long, contain at least one uppercase letter, one number, and one special character",
+        }));
+    }
+
+    let password_hash = match hash(&req.password, DEFAULT_COST) {
+        Ok(hash) => hash,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to hash password",
+            }));
+        }
+    };
+
+    // matching on the token's version makes it single-use
+    let username = match sqlx::query_scalar::<_, String>(
+        "UPDATE users SET password = $1, session_version = session_version + 1
+        WHERE email = $2 AND session_version = $3
+        RETURNING username",
+    )
+    .bind(password_hash)
+    .bind(&reset.sub)
+    .bind(reset.ver)
+    .fetch_optional(pool.get_ref())
+    .await
+    {
+        Ok(Some(username)) => username,
+        Ok(None) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "invalid or expired token",
+            }));
+        }
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to reset password",
+            }));
+        }
+    };
+
+    // every device has to log in again with the new password
+    if let Err(e) = sessions::revoke_all(pool.get_ref(), &username).await {
+        eprintln!("Error revoking sessions: {}", e);
+    }
+    presence::disconnect_all(&chat_state, &username).await;
+
+    let mut response = HttpResponse::Ok();
+    for cookie in expired_cookies() {
+        response.cookie(cookie);
+    }
+    response.json(json!({
+        "status": "success",
+        "message": "password reset, please log in again",
+    }))
+}
+
 #[delete("/logout")]
-pub async fn logout() -> impl Responder {
-    let mut cookie = Cookie::new("token", "");
-    cookie.set_same_site(cookie::SameSite::Lax);
-    cookie.set_secure(true);
-    cookie.set_http_only(true);
-    cookie.set_max_age(Duration::seconds(0));
-    HttpResponse::Ok().cookie(cookie).json(json!({
+pub async fn logout(
+    pool: web::Data<PgPool>,
+    chat_state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+) -> impl Responder {
+    // the refresh token identifies the session even once the access token
+    // has expired
+    if let Some(refresh_token) = req.cookie(REFRESH_COOKIE) {
+        match sessions::revoke_by_refresh_token(pool.get_ref(), refresh_token.value()).await {
+            Ok(Some((id, username))) => chat_state.hub.disconnect_login(&username, id).await,
+            Ok(None) => {}
+            Err(e) => eprintln!("Error revoking session: {}", e),
+        }
+    }
+
+    let mut response = HttpResponse::Ok();
+    for cookie in expired_cookies() {
+        response.cookie(cookie);
+    }
+    response.json(json!({
         "status": "success",
         "message": "user logged out",
     }))
@@ -1,16 +1,25 @@
+use crate::config;
+use crate::event_log::{self, Replay};
+use crate::heartbeat::Heartbeat;
+use crate::hub::{Hub, SessionId};
 use crate::middlewares::verify_token;
+use crate::protocol::{self, ErrorCode, Reply};
+use crate::rate_limit::RateLimits;
+use crate::routes::attachments::{self, Attachment};
+use crate::routes::friend::{is_blocked, is_blocked_in_chat};
+use crate::routes::presence::{self, Presence, PresenceTracker, SetStatus};
+use crate::routes::socket::{self, Event, Namespace};
 use actix_web::{Error, HttpRequest, HttpResponse, get, web};
-use actix_ws::{Message, Session};
+use actix_ws::Session;
 use chrono::{DateTime, Utc};
-use futures_util::StreamExt as _;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::{
-    select,
-    sync::{RwLock, broadcast},
-};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::Instant;
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct ChatMessage {
@@ -22,21 +31,63 @@ pub struct ChatMessage {
     pub replied_message: Option<String>,
     pub time: DateTime<Utc>,
     pub edited: bool,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+/// Every reaction of one emoji on a message.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    pub usernames: Vec<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct MessageReaction {
+    message_id: i32,
+    #[sqlx(flatten)]
+    reaction: ReactionCount,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
 pub struct Chat {
     pub id: i32,
-    pub first_user_name: String,
-    pub second_user_name: String,
+    pub name: Option<String>,
+    pub is_group: bool,
+    pub owner: Option<String>,
+    pub members: Vec<String>,
     pub last_update: DateTime<Utc>,
 }
 
+/// A row of `/chats`, the chat plus the read state of the caller.
+#[derive(Debug, Serialize, FromRow)]
+pub struct ChatSummary {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub chat: Chat,
+    pub last_read_message_id: Option<i32>,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ReadState {
+    pub username: String,
+    pub last_read_message_id: Option<i32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewMessage {
     pub message: String,
+    pub chat_id: Option<i32>,
     pub chat_partner: Option<String>,
     pub reply: Option<i32>,
+    #[serde(default)]
+    pub attachments: Vec<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,9 +102,85 @@ pub struct NewChat {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    pub action: String,
-    pub payload: serde_json::Value,
+pub struct CreateGroup {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameGroup {
+    pub chat_id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaveGroup {
+    pub chat_id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KickMember {
+    pub chat_id: i32,
+    pub username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkRead {
+    pub chat_id: i32,
+    pub message_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionPayload {
+    pub message_id: i32,
+    pub emoji: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypingPayload {
+    pub chat_id: i32,
+}
+
+/// Sent by a reconnecting client with the last `seq` it saw, or without one
+/// to only learn where the log is at.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resume {
+    pub last_seq: Option<i64>,
+}
+
+/// Everything a client can ask of `/ws`, framed by `protocol::parse`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", content = "payload", rename_all = "snake_case")]
+pub enum ChatRequest {
+    NewMessage(NewMessage),
+    EditMessage(EditMessage),
+    DeleteMessage(DeleteMessageRequest),
+    ChangeBio(ChangeBio),
+    NewChat(NewChat),
+    CreateGroup(CreateGroup),
+    RenameGroup(RenameGroup),
+    LeaveGroup(LeaveGroup),
+    KickMember(KickMember),
+    AddReaction(ReactionPayload),
+    RemoveReaction(ReactionPayload),
+    TypingStart(TypingPayload),
+    TypingStop(TypingPayload),
+    SetStatus(SetStatus),
+    MarkRead(MarkRead),
+    Resume(Resume),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesQuery {
+    pub before: Option<i32>,
+    pub after: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesPage {
+    pub messages: Vec<ChatMessage>,
+    pub has_more: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,120 +199,194 @@ pub struct ChangeBio {
     pub biography: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "action", rename_all = "snake_case")]
 pub enum OutgoingMessage {
     NewMessage(ChatMessage),
     EditMessage(ChatMessage),
-    Delete { message_id: i32 },
+    Delete {
+        message_id: i32,
+        chat_id: i32,
+    },
     NewChat(Chat),
+    ChatUpdated(Chat),
+    MemberRemoved {
+        chat_id: i32,
+        username: String,
+    },
+    Read {
+        chat_id: i32,
+        username: String,
+        message_id: i32,
+    },
+    Typing {
+        chat_id: i32,
+        username: String,
+        typing: bool,
+    },
+    ReactionUpdate {
+        chat_id: i32,
+        message_id: i32,
+        reactions: Vec<ReactionCount>,
+    },
+    Presence(Presence),
     ChangeBio(Bio),
 }
 
-#[derive(Debug, Clone)]
-pub struct UserSession {
-    pub email: String,
-    pub username: String,
-    pub user_chats: Vec<i32>,
-    pub tx: broadcast::Sender<OutgoingMessage>,
+/// What the hub carries to chat sockets. `seq` is set on events written to
+/// the recipient's event log, see `AppState::publish_logged`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(flatten)]
+    pub event: OutgoingMessage,
+}
+
+impl From<OutgoingMessage> for ChatEvent {
+    fn from(event: OutgoingMessage) -> Self {
+        Self { seq: None, event }
+    }
+}
+
+pub const MAX_GROUP_NAME_LEN: usize = 100;
+pub const MAX_GROUP_MEMBERS: usize = 50;
+pub const DEFAULT_MESSAGES_LIMIT: i64 = 50;
+pub const MAX_MESSAGES_LIMIT: i64 = 100;
+pub const MAX_REACTION_LEN: usize = 32;
+
+/// Typing indicators are relayed at most once per `TYPING_THROTTLE` and are
+/// cleared when no `typing_start` came in for `TYPING_TIMEOUT`.
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a new chat session holds live events back for the client's
+/// `resume`, legacy clients that never send one get them after that.
+pub const RESUME_WAIT: Duration = Duration::from_secs(2);
+
+struct TypingState {
+    last_relayed: Instant,
+    expires_at: Instant,
 }
 
 pub struct AppState {
     pub db_pool: PgPool,
-    pub tx: broadcast::Sender<OutgoingMessage>,
-    pub user_sessions: Arc<RwLock<HashMap<String, UserSession>>>,
+    pub hub: Arc<Hub<Event>>,
+    pub presence: PresenceTracker,
+    typing: Mutex<HashMap<(i32, String), TypingState>>,
 }
 
-pub async fn create_table(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS messages (
-            id SERIAL PRIMARY KEY,
-            chat_id INTEGER NOT NULL REFERENCES chats(id),
-            email VARCHAR(255) NOT NULL REFERENCES users(email),
-            username VARCHAR(255) NOT NULL REFERENCES users(username),
-            message TEXT NOT NULL,
-            replied_user TEXT,
-            replied_message TEXT,
-            time TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
-            edited BOOLEAN NOT NULL DEFAULT FALSE
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
+const CHAT_COLUMNS: &str = r#"
+    c.id, c.name, c.is_group, c.owner, c.last_update,
+    ARRAY(
+        SELECT m.username::TEXT FROM chat_members m
+        WHERE m.chat_id = c.id
+        ORDER BY m.joined_at, m.username
+    ) AS members
+"#;
+
+fn direct_key(first: &str, second: &str) -> String {
+    if first <= second {
+        format!("{}:{}", first, second)
+    } else {
+        format!("{}:{}", second, first)
+    }
 }
 
-pub async fn chats(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS chats (
-            id SERIAL PRIMARY KEY,
-            first_user_name VARCHAR(255) NOT NULL REFERENCES users(username),
-            second_user_name VARCHAR(255) NOT NULL REFERENCES users(username),
-            last_update TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
-            CONSTRAINT unique_chat_pair UNIQUE (first_user_name, second_user_name)
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+pub async fn fetch_chat(pool: &PgPool, chat_id: i32) -> Result<Option<Chat>, sqlx::Error> {
+    sqlx::query_as::<_, Chat>(&format!(
+        "SELECT {} FROM chats c WHERE c.id = $1",
+        CHAT_COLUMNS
+    ))
+    .bind(chat_id)
+    .fetch_optional(pool)
+    .await
+}
 
-    sqlx::query(
-        r#"
-        CREATE OR REPLACE FUNCTION enforce_chat_order() RETURNS TRIGGER AS $$
-        BEGIN
-            IF NEW.first_user_name > NEW.second_user_name THEN
-                DECLARE
-                    temp VARCHAR(255);
-                BEGIN
-                    temp := NEW.first_user_name;
-                    NEW.first_user_name := NEW.second_user_name;
-                    NEW.second_user_name := temp;
-                END;
-            END IF;
-            RETURN NEW;
-        END;
-        $$ LANGUAGE plpgsql
-        "#,
+pub async fn is_member(pool: &PgPool, chat_id: i32, username: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM chat_members WHERE chat_id = $1 AND username = $2)",
     )
-    .execute(pool)
-    .await?;
+    .bind(chat_id)
+    .bind(username)
+    .fetch_one(pool)
+    .await
+}
 
-    sqlx::query(
-        r#"
-        DROP TRIGGER IF EXISTS enforce_chat_order_trigger ON chats
-        "#,
+async fn create_chat(
+    pool: &PgPool,
+    name: Option<&str>,
+    owner: Option<&str>,
+    direct_key: Option<&str>,
+    members: &[String],
+) -> Result<Chat, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let chat_id = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO chats (name, is_group, owner, direct_key) VALUES ($1, $2, $3, $4) RETURNING id",
     )
-    .execute(pool)
+    .bind(name)
+    .bind(direct_key.is_none())
+    .bind(owner)
+    .bind(direct_key)
+    .fetch_one(&mut *transaction)
     .await?;
 
     sqlx::query(
-        r#"
-        CREATE TRIGGER enforce_chat_order_trigger
-        BEFORE INSERT OR UPDATE ON chats
-        FOR EACH ROW EXECUTE FUNCTION enforce_chat_order()
-        "#,
+        "INSERT INTO chat_members (chat_id, username) SELECT $1, UNNEST($2::TEXT[]) ON CONFLICT DO NOTHING",
     )
-    .execute(pool)
+    .bind(chat_id)
+    .bind(members)
+    .execute(&mut *transaction)
     .await?;
 
-    Ok(())
+    transaction.commit().await?;
+
+    fetch_chat(pool, chat_id)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
 }
 
+/// Returns the 1:1 chat between two users, creating it when needed. The bool
+/// is true when the chat was just created.
+async fn find_or_create_direct_chat(
+    pool: &PgPool,
+    first: &str,
+    second: &str,
+) -> Result<(Chat, bool), sqlx::Error> {
+    let key = direct_key(first, second);
+
+    let existing = sqlx::query_scalar::<_, i32>("SELECT id FROM chats WHERE direct_key = $1")
+        .bind(&key)
+        .fetch_optional(pool)
+        .await?;
+
+    if let Some(chat_id) = existing
+        && let Some(chat) = fetch_chat(pool, chat_id).await?
+    {
+        return Ok((chat, false));
+    }
+
+    let members = vec![first.to_string(), second.to_string()];
+    let chat = create_chat(pool, None, None, Some(&key), &members).await?;
+    Ok((chat, true))
+}
+
+/// Legacy chat socket, `/socket` carries the same events under the `chat`,
+/// `presence` and `profile` namespaces.
 #[get("/ws")]
 pub async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
     state: web::Data<Arc<AppState>>,
+    rate_limits: web::Data<RateLimits>,
 ) -> Result<HttpResponse, Error> {
     let token = match req.cookie("token") {
         Some(token) => token.value().to_string(),
         None => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(&state.db_pool, token).await {
         Ok(claims) => claims,
         Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
@@ -193,637 +394,1242 @@ pub async fn ws_handler(
     let email = claims.sub.clone();
     let username = claims.email.clone();
 
-    let user_chats = match sqlx::query_scalar::<_, i32>(
-        "SELECT id FROM chats WHERE first_user_name = $1 OR second_user_name = $1",
-    )
-    .bind(&username)
-    .fetch_all(&state.db_pool)
-    .await
-    {
-        Ok(chats) => chats,
-        Err(e) => {
-            eprintln!("Error fetching user chats: {}", e);
-            HttpResponse::BadRequest().json("Error fetching user chats");
-            vec![]
-        }
+    let version = match protocol::negotiate(&req) {
+        Ok(version) => version,
+        Err(response) => return Ok(response),
     };
 
-    let (response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    let (mut response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    protocol::accept(&mut response, version);
+    let mut heartbeat = Heartbeat::new(session.clone(), &config::get().websocket, claims.exp);
 
-    let db_pool = state.db_pool.clone();
-    let second_db_pool = state.db_pool.clone();
-    let tx = state.tx.clone();
-    let mut rx = tx.subscribe();
-    let user_sessions = state.user_sessions.clone();
-    let broadcast_user_sessions = user_sessions.clone();
+    let (connection, outbox) =
+        ChatConnection::open(state.get_ref().clone(), username.clone(), email, claims.sid).await;
+    let message_session = session.clone();
 
-    {
-        let mut sessions = user_sessions.write().await;
-        sessions.insert(
-            email.clone(),
-            UserSession {
-                email: email.clone(),
-                username: username.clone(),
-                user_chats: user_chats.clone(),
-                tx: tx.clone(),
-            },
-        );
+    actix_rt::spawn(async move {
+        while let Some(text) = heartbeat.next_text(&mut msg_stream).await {
+            let request = match protocol::parse::<ChatRequest>(&text, version) {
+                Ok(request) => request,
+                Err(rejection) => {
+                    Reply::reject(message_session.clone(), version, rejection).await;
+                    continue;
+                }
+            };
+            let mut reply = Reply::new(message_session.clone(), version, request.id);
+            if rate_limits
+                .check_ws_action(&username, &request.name)
+                .is_err()
+            {
+                reply
+                    .fail(
+                        ErrorCode::RateLimited,
+                        "You're doing that too fast, slow down",
+                    )
+                    .await;
+                continue;
+            }
+            connection.handle(request.action, &mut reply).await;
+            reply.ack().await;
+        }
+        println!("(chat.rs): session closed and removed.");
+
+        connection.close().await;
+    });
+
+    actix_rt::spawn(outbox.run(session, false));
+
+    Ok(response)
+}
+
+/// A chat session as request handlers see it, shared by `/ws` and `/socket`.
+pub struct ChatConnection {
+    state: Arc<AppState>,
+    username: String,
+    email: String,
+    session_id: SessionId,
+    resume_tx: mpsc::UnboundedSender<Option<i64>>,
+}
+
+/// The sending half of a chat session: what the hub routes to it and the
+/// replays it asks for with `resume`.
+pub struct ChatOutbox {
+    pool: PgPool,
+    username: String,
+    events: mpsc::UnboundedReceiver<Event>,
+    resumes: mpsc::UnboundedReceiver<Option<i64>>,
+    // events up to here are replayed on `resume`, later ones arrive live
+    connect_seq: i64,
+}
+
+impl ChatConnection {
+    /// Registers the session with the hub and presence, `login` being the
+    /// `sessions` row its access token was issued for.
+    pub async fn open(
+        state: Arc<AppState>,
+        username: String,
+        email: String,
+        login: i32,
+    ) -> (Self, ChatOutbox) {
+        let user_chats = match sqlx::query_scalar::<_, i32>(
+            "SELECT chat_id FROM chat_members WHERE username = $1",
+        )
+        .bind(&username)
+        .fetch_all(&state.db_pool)
+        .await
+        {
+            Ok(chats) => chats,
+            Err(e) => {
+                eprintln!("Error fetching user chats: {}", e);
+                vec![]
+            }
+        };
+
+        let (session_id, events) = presence::connect(&state, &username, login, &user_chats).await;
+
+        let connect_seq = match event_log::last_seq(&state.db_pool, &username).await {
+            Ok(seq) => seq,
+            Err(e) => {
+                eprintln!("Error fetching last event seq: {}", e);
+                0
+            }
+        };
+        let (resume_tx, resumes) = mpsc::unbounded_channel();
+
+        let outbox = ChatOutbox {
+            pool: state.db_pool.clone(),
+            username: username.clone(),
+            events,
+            resumes,
+            connect_seq,
+        };
+        let connection = Self {
+            state,
+            username,
+            email,
+            session_id,
+            resume_tx,
+        };
+        (connection, outbox)
     }
 
-    let mut broadcast_session = session.clone();
-    let mut message_session = session;
+    pub async fn close(self) {
+        presence::disconnect(&self.state, &self.username, self.session_id).await;
+    }
 
-    let broadcast_email = email.clone();
-    let broadcast_username = username.clone();
+    /// Carries out one request, errors go to `reply`.
+    pub async fn handle(&self, request: ChatRequest, reply: &mut Reply) {
+        let state = &self.state;
+        let db_pool = &state.db_pool;
+        let (username, email) = (&self.username, &self.email);
+
+        match request {
+            ChatRequest::NewMessage(mut new_msg) => {
+                new_msg.attachments.sort_unstable();
+                new_msg.attachments.dedup();
+                if new_msg.attachments.len() > config::get().uploads.max_attachments_per_message {
+                    reply.error("Too many attachments").await;
+                    return;
+                }
 
-    actix_rt::spawn(async move {
-        while let Some(Ok(msg)) = msg_stream.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if let Ok(ws_msg) = serde_json::from_str::<WebSocketMessage>(&text) {
-                        match ws_msg.action.as_str() {
-                            "new_message" => {
-                                if let Ok(new_msg) =
-                                    serde_json::from_value::<NewMessage>(ws_msg.payload)
-                                {
-                                    if let Some(chat_partner) = new_msg.chat_partner {
-                                        let chat_id = match sqlx::query_scalar::<_, i32>(
-                                            "SELECT id FROM chats WHERE (first_user_name = $1 AND second_user_name = $2) OR (first_user_name = $2 AND second_user_name = $1)"
-                                        )
-                                        .bind(&username)
-                                        .bind(&chat_partner)
-                                        .fetch_optional(&db_pool)
-                                        .await
-                                        {
-                                            Ok(Some(id)) => id,
-                                            Ok(None) => {
-                                                match sqlx::query_scalar(
-                                                    "INSERT INTO chats (first_user_name, second_user_name) VALUES ($1, $2) RETURNING id"
-                                                )
-                                                .bind(&username)
-                                                .bind(&chat_partner)
-                                                .fetch_one(&db_pool)
-                                                .await {
-                                                        Ok(id) => id,
-                                                        Err(e) => {
-                                                            eprintln!("Error creating chat: {}", e);
-                                                            ws_error_message(&mut message_session, "Error creating chat").await;
-                                                            return;
-                                                        }
-                                                    }
-                                            },
-                                            Err(e) => {
-                                                eprintln!("Error checking/creating chat: {}", e);
-                                                ws_error_message(&mut message_session, "Error checking/creating chat").await;
-                                                return;
-                                            }
-                                        };
-
-                                        if new_msg.reply.is_some() {
-                                            let replied_message_chat_id =
-                                                match sqlx::query_scalar::<_, i32>(
-                                                    "SELECT chat_id FROM messages WHERE id = $1",
-                                                )
-                                                .bind(&new_msg.reply)
-                                                .fetch_one(&db_pool)
-                                                .await
-                                                {
-                                                    Ok(replied_message_chat_id) => {
-                                                        replied_message_chat_id
-                                                    }
-                                                    Err(e) => {
-                                                        eprintln!(
-                                                            "Error selecting replied message chat id: {}",
-                                                            e
-                                                        );
-                                                        ws_error_message(&mut message_session, "Error selecting replied message chat id")
-                                                            .await;
-                                                        continue;
-                                                    }
-                                                };
-
-                                            if replied_message_chat_id == chat_id {
-                                                let replied_message = match sqlx::query_scalar::<
-                                                    _,
-                                                    String,
-                                                >(
-                                                    "SELECT message FROM messages WHERE id = $1",
-                                                )
-                                                .bind(&new_msg.reply)
-                                                .fetch_one(&db_pool)
-                                                .await
-                                                {
-                                                    Ok(replied_message) => replied_message,
-                                                    Err(e) => {
-                                                        eprintln!(
-                                                            "Error selecting replied message: {}",
-                                                            e
-                                                        );
-                                                        ws_error_message(
-                                                            &mut message_session,
-                                                            "Error selecting replied message",
-                                                        )
-                                                        .await;
-                                                        return;
-                                                    }
-                                                };
-
-                                                let replied_user = match sqlx::query_scalar::<
-                                                    _,
-                                                    String,
-                                                >(
-                                                    "SELECT username FROM messages WHERE id = $1",
-                                                )
-                                                .bind(&new_msg.reply)
-                                                .fetch_one(&db_pool)
-                                                .await
-                                                {
-                                                    Ok(replied_user) => replied_user,
-                                                    Err(e) => {
-                                                        eprintln!(
-                                                            "Error selecting replied user: {}",
-                                                            e
-                                                        );
-                                                        ws_error_message(
-                                                            &mut message_session,
-                                                            "Error selecting replied user",
-                                                        )
-                                                        .await;
-                                                        return;
-                                                    }
-                                                };
-
-                                                match sqlx::query_as::<_, ChatMessage>(
-                                                    "INSERT INTO messages (chat_id, email, username, message, replied_user, replied_message, time) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *"
-                                                )
-                                                .bind(&chat_id)
-                                                .bind(&email)
-                                                .bind(&username)
-                                                .bind(&new_msg.message)
-                                                .bind(&replied_user)
-                                                .bind(&replied_message)
-                                                .bind(Utc::now())
-                                                .fetch_one(&db_pool)
-                                                .await
-                                                {
-                                                    Ok(message) => {
-                                                        match sqlx::query(
-                                                            r#"
-                                                                UPDATE chats
-                                                                SET last_update = $1
-                                                                WHERE id = $2
-                                                            "#,
-                                                        )
-                                                        .bind(Utc::now())
-                                                        .bind(&chat_id)
-                                                        .execute(&db_pool)
-                                                        .await
-                                                        {
-                                                            Ok(_) => {},
-                                                            Err(e) => {
-                                                                eprintln!("Error updating chat: {}", e);
-                                                                ws_error_message(&mut message_session, "Error updating chat").await;
-                                                            }
-                                                        }
-                                                        let _ = tx.send(OutgoingMessage::NewMessage(message));
-                                                    }
-                                                    Err(e) => {
-                                                        println!("error sending message: {}", e);
-                                                        ws_error_message(&mut message_session, "Error sending message").await;
-                                                    }
-                                                }
-                                            } else {
-                                                ws_error_message(
-                                                    &mut message_session,
-                                                    "You can not reply a message from other chat",
-                                                )
-                                                .await;
-                                            }
-                                        } else {
-                                            match sqlx::query_as::<_, ChatMessage>(
-                                                "INSERT INTO messages (chat_id, email, username, message, time) VALUES ($1, $2, $3, $4, $5) RETURNING *"
-                                            )
-                                            .bind(&chat_id)
-                                            .bind(&email)
-                                            .bind(&username)
-                                            .bind(&new_msg.message)
-                                            .bind(Utc::now())
-                                            .fetch_one(&db_pool)
-                                            .await
-                                            {
-                                                Ok(message) => {
-                                                    match sqlx::query(
-                                                        r#"
-                                                            UPDATE chats
-                                                            SET last_update = $1
-                                                            WHERE id = $2
-                                                        "#,
-                                                    )
-                                                    .bind(Utc::now())
-                                                    .bind(&chat_id)
-                                                    .execute(&db_pool)
-                                                    .await
-                                                    {
-                                                        Ok(_) => {},
-                                                        Err(e) => {
-                                                            eprintln!("Error updating chat: {}", e);
-                                                            ws_error_message(&mut message_session, "Error updating chat").await;
-                                                        }
-                                                    }
-                                                    let _ = tx.send(OutgoingMessage::NewMessage(message));
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("error sending message: {}", e);
-                                                    ws_error_message(&mut message_session, "Error sending message").await;
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
+                let chat_id = match (new_msg.chat_id, &new_msg.chat_partner) {
+                    (Some(chat_id), _) => match is_member(db_pool, chat_id, username).await {
+                        Ok(true) => chat_id,
+                        Ok(false) => {
+                            reply.error("You are not a member of this chat").await;
+                            return;
+                        }
+                        Err(e) => {
+                            eprintln!("Error checking chat membership: {}", e);
+                            reply.error("Error checking chat membership").await;
+                            return;
+                        }
+                    },
+                    (None, Some(chat_partner)) => {
+                        match is_blocked(db_pool, username, chat_partner).await {
+                            Ok(false) => {}
+                            Ok(true) => {
+                                reply.error("You can't message this user").await;
+                                return;
                             }
-                            "edit_message" => {
-                                if let Ok(edit_message) =
-                                    serde_json::from_value::<EditMessage>(ws_msg.payload)
-                                {
-                                    match sqlx::query_scalar::<_, bool>(
-                                        "SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1 AND username = $2)"
-                                    )
-                                    .bind(&edit_message.message_id)
-                                    .bind(&username)
-                                    .fetch_optional(&db_pool)
-                                    .await
-                                    {
-                                        Ok(_) => {
-                                            match sqlx::query(
-                                                "UPDATE messages SET message = $1, edited = true WHERE id = $2"
-                                            )
-                                            .bind(&edit_message.message)
-                                            .bind(&edit_message.message_id)
-                                            .execute(&db_pool)
-                                            .await
-                                            {
-                                                Ok(_) => {
-                                                    match sqlx::query_as::<_, ChatMessage> (
-                                                        "SELECT * FROM messages WHERE id = $1"
-                                                    )
-                                                    .bind(&edit_message.message_id)
-                                                    .fetch_one(&db_pool)
-                                                    .await
-                                                    {
-                                                        Ok(message) => {
-                                                            let _ = tx.send(OutgoingMessage::EditMessage(message));
-                                                        }
-                                                        Err(e) => {
-                                                            eprintln!("error sending message: {}", e);
-                                                            ws_error_message(&mut message_session, "Error sending message").await;
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("error editing message: {}", e);
-                                                    ws_error_message(&mut message_session, "Error editing message").await;
-                                                }
-                                            }
-                                        },
-                                        Err(_) => {
-                                            Some(false);
-                                        }
-                                    };
-                                }
+                            Err(e) => {
+                                eprintln!("Error checking blocks: {}", e);
+                                reply.error("Error checking blocks").await;
+                                return;
                             }
-                            "change_bio" => {
-                                if let Ok(change_bio) =
-                                    serde_json::from_value::<ChangeBio>(ws_msg.payload)
-                                {
-                                    if let Some(biography) = change_bio.biography {
-                                        match sqlx::query(
-                                            "UPDATE users SET biography = $1 WHERE username = $2",
-                                        )
-                                        .bind(&biography)
-                                        .bind(&username)
-                                        .execute(&db_pool)
-                                        .await
-                                        {
-                                            Ok(_) => {
-                                                match sqlx::query_as::<_, Bio>(
-                                                    "SELECT * FROM users WHERE username = $1",
-                                                )
-                                                .bind(&username)
-                                                .fetch_one(&db_pool)
-                                                .await
-                                                {
-                                                    Ok(message) => {
-                                                        let _ = tx.send(
-                                                            OutgoingMessage::ChangeBio(message),
-                                                        );
-                                                    }
-                                                    Err(e) => {
-                                                        eprintln!("error sending message: {}", e);
-                                                        ws_error_message(
-                                                            &mut message_session,
-                                                            "Error sending message",
-                                                        )
-                                                        .await;
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => {
-                                                eprintln!("error updating biography: {}", e);
-                                                ws_error_message(
-                                                    &mut message_session,
-                                                    "Error updating biography",
-                                                )
-                                                .await;
-                                            }
-                                        }
-                                    }
+                        }
+                        match find_or_create_direct_chat(db_pool, username, chat_partner).await {
+                            Ok((chat, created)) => {
+                                if created {
+                                    state.publish(OutgoingMessage::NewChat(chat.clone())).await;
                                 }
+                                chat.id
                             }
-                            "new_chat" => {
-                                if let Ok(new_chat) =
-                                    serde_json::from_value::<NewChat>(ws_msg.payload)
-                                {
-                                    if let Some(second_user_name) = new_chat.second_user_name {
-                                        let can_create_chat = match sqlx::query_scalar::<_, bool>(
-                                            "SELECT EXISTS(SELECT * FROM friends WHERE (sender_username = $1 AND receiver_username = $2) OR (sender_username = $2 AND receiver_username = $1))"
-                                        )
-                                        .bind(&username)
-                                        .bind(&second_user_name)
-                                        .fetch_optional(&db_pool)
-                                        .await {
-                                            Ok(can_create_chat) => can_create_chat,
-                                            Err(_) => {
-                                                ws_error_message(&mut message_session, "You can't send message").await;
-                                                Some(false)
-                                            }
-                                        };
-
-                                        if can_create_chat == Some(false) {
-                                            ws_error_message(
-                                                &mut message_session,
-                                                "You can't create chat",
-                                            )
-                                            .await;
-                                            return;
-                                        }
-
-                                        let existing_chat = sqlx::query_scalar::<_, i32>(
-                                            "SELECT id FROM chats WHERE
-                                            (first_user_name = LEAST($1, $2) AND second_user_name = GREATEST($1, $2))"
-                                        )
-                                        .bind(&username)
-                                        .bind(&second_user_name)
-                                        .fetch_optional(&db_pool)
-                                        .await;
-
-                                        if let Ok(Some(_id)) = existing_chat {
-                                            ws_error_message(
-                                                &mut message_session,
-                                                "Chat already exists",
-                                            )
-                                            .await;
-                                            return;
-                                        }
-
-                                        match sqlx::query_as::<_, Chat> (
-                                            "INSERT INTO chats (first_user_name, second_user_name) VALUES ($1, $2) RETURNING *"
-                                        )
-                                        .bind(&username)
-                                        .bind(&second_user_name)
-                                        .fetch_one(&db_pool)
-                                        .await
-                                        {
-                                            Ok(chat) => {
-                                                if let Err(e) = state.update_user_chats(&username).await {
-                                                    eprintln!("Failed to update user chats: {}", e);
-                                                    ws_error_message(&mut message_session, "Failed to update user chats").await;
-                                                }
-                                                if let Err(e) = state.update_user_chats(&second_user_name).await {
-                                                    eprintln!("Failed to update partner chats: {}", e);
-                                                    ws_error_message(&mut message_session, "Failed to update partner chats").await;
-                                                }
-                                                let _ = tx.send(OutgoingMessage::NewChat(chat));
-                                            },
-                                            Err(e) => {
-                                                eprintln!("error creating chat: {}", e);
-                                                ws_error_message(&mut message_session, "Error creating chat").await;
-                                            }
-                                        }
-                                    }
-                                }
+                            Err(e) => {
+                                eprintln!("Error checking/creating chat: {}", e);
+                                reply.error("Error checking/creating chat").await;
+                                return;
                             }
-                            "delete_message" => {
-                                if let Ok(delete_req) =
-                                    serde_json::from_value::<DeleteMessageRequest>(ws_msg.payload)
+                        }
+                    }
+                    (None, None) => {
+                        reply.error("chat_id or chat_partner is required").await;
+                        return;
+                    }
+                };
+
+                match is_blocked_in_chat(db_pool, chat_id, username).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        reply.error("You can't message this user").await;
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("Error checking blocks: {}", e);
+                        reply.error("Error checking blocks").await;
+                        return;
+                    }
+                }
+
+                let mut replied = None;
+                if new_msg.reply.is_some() {
+                    let replied_message_chat_id = match sqlx::query_scalar::<_, i32>(
+                        "SELECT chat_id FROM messages WHERE id = $1",
+                    )
+                    .bind(new_msg.reply)
+                    .fetch_one(db_pool)
+                    .await
+                    {
+                        Ok(replied_message_chat_id) => replied_message_chat_id,
+                        Err(e) => {
+                            eprintln!("Error selecting replied message chat id: {}", e);
+                            reply.error("Error selecting replied message chat id").await;
+                            return;
+                        }
+                    };
+
+                    if replied_message_chat_id != chat_id {
+                        reply
+                            .error("You can not reply a message from other chat")
+                            .await;
+                        return;
+                    }
+
+                    let replied_message = match sqlx::query_scalar::<_, String>(
+                        "SELECT message FROM messages WHERE id = $1",
+                    )
+                    .bind(new_msg.reply)
+                    .fetch_one(db_pool)
+                    .await
+                    {
+                        Ok(replied_message) => replied_message,
+                        Err(e) => {
+                            eprintln!("Error selecting replied message: {}", e);
+                            reply.error("Error selecting replied message").await;
+                            return;
+                        }
+                    };
+
+                    let replied_user = match sqlx::query_scalar::<_, String>(
+                        "SELECT username FROM messages WHERE id = $1",
+                    )
+                    .bind(new_msg.reply)
+                    .fetch_one(db_pool)
+                    .await
+                    {
+                        Ok(replied_user) => replied_user,
+                        Err(e) => {
+                            eprintln!("Error selecting replied user: {}", e);
+                            reply.error("Error selecting replied user").await;
+                            return;
+                        }
+                    };
+                    replied = Some((replied_user, replied_message));
+                }
+
+                match insert_message(db_pool, chat_id, email, username, &new_msg, replied).await {
+                    Ok(Some(message)) => {
+                        state.publish(OutgoingMessage::NewMessage(message)).await;
+                    }
+                    Ok(None) => reply.error("Invalid attachments").await,
+                    Err(e) => {
+                        eprintln!("error sending message: {}", e);
+                        reply.error("Error sending message").await;
+                    }
+                }
+            }
+            ChatRequest::EditMessage(edit_message) => {
+                match sqlx::query_scalar::<_, bool>(
+                    "SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1 AND username = $2)",
+                )
+                .bind(edit_message.message_id)
+                .bind(username)
+                .fetch_optional(db_pool)
+                .await
+                {
+                    Ok(Some(true)) => {
+                        match sqlx::query(
+                            "UPDATE messages SET message = $1, edited = true WHERE id = $2",
+                        )
+                        .bind(&edit_message.message)
+                        .bind(edit_message.message_id)
+                        .execute(db_pool)
+                        .await
+                        {
+                            Ok(_) => {
+                                match sqlx::query_as::<_, ChatMessage>(
+                                    "SELECT * FROM messages WHERE id = $1",
+                                )
+                                .bind(edit_message.message_id)
+                                .fetch_one(db_pool)
+                                .await
                                 {
-                                    match sqlx::query_as::<_, ChatMessage>(
-                                        "SELECT id, chat_id, email, username, message, replied_user, replied_message, time, edited FROM messages WHERE id = $1"
-                                    )
-                                    .bind(delete_req.id)
-                                    .fetch_optional(&db_pool)
-                                    .await {
-                                        Ok(Some(msg)) => {
-                                            if msg.username != username {
-                                                ws_error_message(&mut message_session, "You can only delete your own messages").await;
-                                                break;
-                                            }
-
-                                            match sqlx::query("DELETE FROM messages WHERE id = $1")
-                                                .bind(delete_req.id)
-                                                .execute(&db_pool)
-                                                .await {
-                                                Ok(_) => {
-                                                    let _ = tx.send(OutgoingMessage::Delete { message_id: delete_req.id });
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("Error deleting message: {}", e);
-                                                    ws_error_message(&mut message_session, "Error deleting message").await;
-                                                }
-                                            }
-                                        },
-                                        Ok(None) => {
-                                            ws_error_message(&mut message_session, "Message not found").await;
-                                        },
-                                        Err(e) => {
-                                            eprintln!("Error fetching message: {}", e);
-                                            ws_error_message(&mut message_session, "Error fetching message").await;
-                                        }
+                                    Ok(message) => {
+                                        state.publish(OutgoingMessage::EditMessage(message)).await;
+                                    }
+                                    Err(e) => {
+                                        eprintln!("error sending message: {}", e);
+                                        reply.error("Error sending message").await;
                                     }
                                 }
                             }
-                            _ => {
-                                eprintln!("Unknown action: {}", ws_msg.action);
-                                ws_error_message(&mut message_session, "Unknown action").await;
+                            Err(e) => {
+                                eprintln!("error editing message: {}", e);
+                                reply.error("Error editing message").await;
                             }
                         }
-                    } else {
-                        eprintln!("Failed to parse WebSocket message: {}", text);
                     }
-                }
-                Message::Close(_) => {
-                    {
-                        let mut sessions_write = user_sessions.write().await;
-                        sessions_write.remove(&email);
+                    _ => {
+                        reply.error("You can only edit your own messages").await;
                     }
-                    println!("(chat.rs): session closed and removed.");
-                    break;
-                }
-                _ => {
-                    {
-                        let mut sessions_write = user_sessions.write().await;
-                        sessions_write.remove(&email);
+                };
+            }
+            ChatRequest::ChangeBio(change_bio) => {
+                let Some(biography) = change_bio.biography else {
+                    reply.error("biography is required").await;
+                    return;
+                };
+
+                match sqlx::query("UPDATE users SET biography = $1 WHERE username = $2")
+                    .bind(&biography)
+                    .bind(username)
+                    .execute(db_pool)
+                    .await
+                {
+                    Ok(_) => {
+                        match sqlx::query_as::<_, Bio>("SELECT * FROM users WHERE username = $1")
+                            .bind(username)
+                            .fetch_one(db_pool)
+                            .await
+                        {
+                            Ok(message) => {
+                                state.publish(OutgoingMessage::ChangeBio(message)).await;
+                            }
+                            Err(e) => {
+                                eprintln!("error sending message: {}", e);
+                                reply.error("Error sending message").await;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("error updating biography: {}", e);
+                        reply.error("Error updating biography").await;
                     }
-                    println!("(chat.rs): session closed and removed.");
-                    break;
                 }
             }
-        }
-    });
+            ChatRequest::NewChat(new_chat) => {
+                let Some(second_user_name) = new_chat.second_user_name else {
+                    reply.error("second_user_name is required").await;
+                    return;
+                };
+
+                match is_blocked(db_pool, username, &second_user_name).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        reply.error("You can't create chat").await;
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("Error checking blocks: {}", e);
+                        reply.error("Error checking blocks").await;
+                        return;
+                    }
+                }
 
-    actix_rt::spawn(async move {
-        let mut session_alive = true;
-
-        while session_alive {
-            select! {
-                msg = rx.recv() => {
-                    match msg {
-                        Ok(msg) => {
-                            let session_still_alive = {
-                                let sessions = broadcast_user_sessions.read().await;
-                                sessions.contains_key(&broadcast_email)
-                            };
-
-                            if !session_still_alive {
-                                session_alive = false;
-                                continue;
-                            }
+                let can_create_chat = match sqlx::query_scalar::<_, bool>(
+                    "SELECT EXISTS(SELECT * FROM friends WHERE (sender_username = $1 AND receiver_username = $2) OR (sender_username = $2 AND receiver_username = $1))",
+                )
+                .bind(username)
+                .bind(&second_user_name)
+                .fetch_optional(db_pool)
+                .await
+                {
+                    Ok(can_create_chat) => can_create_chat,
+                    Err(_) => {
+                        reply.error("You can't send message").await;
+                        Some(false)
+                    }
+                };
 
-                            let current_user_chats = {
-                                let sessions = broadcast_user_sessions.read().await;
-                                if let Some(user_session) = sessions.get(&broadcast_email) {
-                                    user_session.user_chats.clone()
-                                } else {
-                                    continue;
-                                }
-                            };
-
-                            let should_send = match &msg {
-                                OutgoingMessage::NewMessage(chat_msg) => {
-                                    if let Some(chat_id) = chat_msg.chat_id {
-                                        if current_user_chats.contains(&chat_id) {
-                                            true
-                                        } else {
-                                            match sqlx::query_scalar::<_, bool>(
-                                                "SELECT EXISTS(SELECT * FROM chats WHERE id = $1 AND (first_user_name = $2 OR second_user_name = $2))"
-                                            )
-                                            .bind(chat_id)
-                                            .bind(&broadcast_email)
-                                            .fetch_one(&second_db_pool)
-                                            .await {
-                                                Ok(exists) => exists,
-                                                Err(_) => false
-                                            }
-                                        }
-                                    } else {
-                                        false
-                                    }
-                                }
-                                OutgoingMessage::Delete { message_id: _ } => true,
-                                OutgoingMessage::NewChat(chat) => {
-                                    chat.first_user_name == broadcast_email
-                                        || chat.second_user_name == broadcast_email
-                                }
-                                OutgoingMessage::EditMessage(chat_msg) => {
-                                    if let Some(chat_id) = chat_msg.chat_id {
-                                        if current_user_chats.contains(&chat_id) {
-                                            true
-                                        } else {
-                                            match sqlx::query_scalar::<_, bool>(
-                                                "SELECT EXISTS(SELECT * FROM chats WHERE id = $1 AND (first_user_name = $2 OR second_user_name = $2))"
-                                            )
-                                            .bind(chat_id)
-                                            .bind(&broadcast_email)
-                                            .fetch_one(&second_db_pool)
-                                            .await {
-                                                Ok(exists) => exists,
-                                                Err(_) => false
-                                            }
-                                        }
-                                    } else {
-                                        false
-                                    }
-                                }
-                                OutgoingMessage::ChangeBio(bio) => {
-                                    if bio.username == broadcast_username {
-                                        true
-                                    } else {
-                                        false
-                                    }
-                                }
-                            };
+                if can_create_chat == Some(false) {
+                    reply.error("You can't create chat").await;
+                    return;
+                }
 
-                            if should_send {
-                                if let Err(_) = broadcast_session
-                                    .text(serde_json::to_string(&msg).unwrap())
-                                    .await
-                                {
-                                    session_alive = false;
+                match find_or_create_direct_chat(db_pool, username, &second_user_name).await {
+                    Ok((_, false)) => {
+                        reply.error("Chat already exists").await;
+                    }
+                    Ok((chat, true)) => {
+                        state.publish(OutgoingMessage::NewChat(chat)).await;
+                    }
+                    Err(e) => {
+                        eprintln!("error creating chat: {}", e);
+                        reply.error("Error creating chat").await;
+                    }
+                }
+            }
+            ChatRequest::CreateGroup(create) => {
+                if let Err(e) = create_group(state, username, create).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::RenameGroup(rename) => {
+                if let Err(e) = rename_group(state, username, rename).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::LeaveGroup(leave) => {
+                if let Err(e) = remove_member(state, username, leave.chat_id, username).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::KickMember(kick) => {
+                if let Err(e) = remove_member(state, username, kick.chat_id, &kick.username).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::AddReaction(reaction) => {
+                if let Err(e) = react(state, username, reaction, true).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::RemoveReaction(reaction) => {
+                if let Err(e) = react(state, username, reaction, false).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::TypingStart(typing) => {
+                if let Err(e) = set_typing(state.clone(), username, typing.chat_id, true).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::TypingStop(typing) => {
+                if let Err(e) = set_typing(state.clone(), username, typing.chat_id, false).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::Resume(resume) => {
+                let _ = self.resume_tx.send(resume.last_seq);
+            }
+            ChatRequest::SetStatus(set) => {
+                if let Err(e) =
+                    presence::set_status(state, username, self.session_id, set.status).await
+                {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::MarkRead(read) => {
+                if let Err(e) = mark_read(state, username, read).await {
+                    reply.error(&e).await;
+                }
+            }
+            ChatRequest::DeleteMessage(delete_req) => {
+                match sqlx::query_as::<_, ChatMessage>(
+                    "SELECT id, chat_id, email, username, message, replied_user, replied_message, time, edited FROM messages WHERE id = $1",
+                )
+                .bind(delete_req.id)
+                .fetch_optional(db_pool)
+                .await
+                {
+                    Ok(Some(msg)) => {
+                        if msg.username != *username {
+                            reply.error("You can only delete your own messages").await;
+                            return;
+                        }
+
+                        match sqlx::query("DELETE FROM messages WHERE id = $1")
+                            .bind(delete_req.id)
+                            .execute(db_pool)
+                            .await
+                        {
+                            Ok(_) => {
+                                if let Some(chat_id) = msg.chat_id {
+                                    state
+                                        .publish(OutgoingMessage::Delete {
+                                            message_id: delete_req.id,
+                                            chat_id,
+                                        })
+                                        .await;
                                 }
                             }
+                            Err(e) => {
+                                eprintln!("Error deleting message: {}", e);
+                                reply.error("Error deleting message").await;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        reply.error("Message not found").await;
+                    }
+                    Err(e) => {
+                        eprintln!("Error fetching message: {}", e);
+                        reply.error("Error fetching message").await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ChatOutbox {
+    /// Writes to `session` until the socket or the hub session is gone.
+    /// `multiplexed` sockets get every namespace with prefixed actions, the
+    /// legacy `/ws` everything but friend events.
+    pub async fn run(mut self, mut session: Session, multiplexed: bool) {
+        // logged events up to `connect_seq` that went out live, a replay
+        // skips them
+        let mut sent_live = HashSet::new();
+        // live events wait for the first `resume`, or `RESUME_WAIT` for
+        // clients that don't send one, so they can't overtake its replay
+        let mut held = Some(Vec::new());
+        let resume_wait = tokio::time::sleep(RESUME_WAIT);
+        tokio::pin!(resume_wait);
+        loop {
+            tokio::select! {
+                event = self.events.recv() => {
+                    let Some(event) = event else { break };
+                    if !multiplexed && event.namespace() == Namespace::Friend {
+                        continue;
+                    }
+                    if let Some(held) = &mut held {
+                        held.push(event);
+                        continue;
+                    }
+                    if self
+                        .send_live(&mut session, event, &mut sent_live, multiplexed)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Some(last_seq) = self.resumes.recv() => {
+                    let replayed =
+                        match resume(&mut session, &self, last_seq, &sent_live, multiplexed).await {
+                            Ok(replayed) => replayed,
+                            Err(_) => return,
+                        };
+                    for event in held.take().unwrap_or_default() {
+                        // the replay already carried it
+                        if replayed
+                            && let Event::Chat(ChatEvent { seq: Some(seq), .. }) = event
+                            && seq <= self.connect_seq
+                        {
+                            continue;
                         }
-                        Err(_) => {
-                            session_alive = false;
+                        if self
+                            .send_live(&mut session, event, &mut sent_live, multiplexed)
+                            .await
+                            .is_err()
+                        {
+                            return;
                         }
                     }
                 }
+                _ = &mut resume_wait, if held.is_some() => {
+                    for event in held.take().unwrap_or_default() {
+                        if self
+                            .send_live(&mut session, event, &mut sent_live, multiplexed)
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        // the hub dropped this session, e.g. after a password reset
+        let _ = session.close(None).await;
+    }
+
+    async fn send_live(
+        &self,
+        session: &mut Session,
+        event: Event,
+        sent_live: &mut HashSet<i64>,
+        multiplexed: bool,
+    ) -> Result<(), actix_ws::Closed> {
+        if let Event::Resync = event {
+            return resync(session, self).await;
+        }
+        if let Event::Chat(ChatEvent { seq: Some(seq), .. }) = event
+            && seq <= self.connect_seq
+        {
+            sent_live.insert(seq);
+        }
+        session.text(event.frame(multiplexed)).await
+    }
+}
+
+/// Tells the client to refetch after the hub lost live events, with the
+/// `seq` to resume after next time.
+async fn resync(session: &mut Session, outbox: &ChatOutbox) -> Result<(), actix_ws::Closed> {
+    let last_seq = match event_log::last_seq(&outbox.pool, &outbox.username).await {
+        Ok(seq) => seq,
+        Err(e) => {
+            eprintln!("Error fetching last event seq: {}", e);
+            outbox.connect_seq
+        }
+    };
+    session
+        .text(serde_json::json!({"action": "resync", "last_seq": last_seq}).to_string())
+        .await
+}
 
-                _ = async {
-                    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
-                    loop {
-                        interval.tick().await;
-                        let sessions = broadcast_user_sessions.read().await;
-                        if !sessions.contains_key(&broadcast_email) {
-                            break;
+/// Answers a `resume`: replays the logged events after `last_seq` that the
+/// socket didn't get live, then reports the `seq` it is caught up to. The
+/// client is told to `resync` when the log can't cover the gap. Returns
+/// whether the log was replayed up to `connect_seq`.
+async fn resume(
+    session: &mut Session,
+    outbox: &ChatOutbox,
+    last_seq: Option<i64>,
+    sent_live: &HashSet<i64>,
+    multiplexed: bool,
+) -> Result<bool, actix_ws::Closed> {
+    let connect_seq = outbox.connect_seq;
+    let action = match last_seq {
+        None => "resumed",
+        Some(last_seq) => {
+            match event_log::replay(&outbox.pool, &outbox.username, last_seq, connect_seq).await {
+                Ok(Replay::Events(events)) => {
+                    for event in events.iter().filter(|e| !sent_live.contains(&e.seq)) {
+                        let mut frame = event.frame();
+                        if multiplexed {
+                            socket::namespace_action(&mut frame, Namespace::Chat);
                         }
+                        session.text(frame.to_string()).await?;
                     }
-                } => {
-                    session_alive = false;
+                    "resumed"
+                }
+                Ok(Replay::Gap) => "resync",
+                Err(e) => {
+                    eprintln!("Error replaying events: {}", e);
+                    "resync"
                 }
             }
         }
-    });
+    };
 
-    Ok(response)
+    session
+        .text(serde_json::json!({"action": action, "last_seq": connect_seq}).to_string())
+        .await?;
+    Ok(last_seq.is_some() && action == "resumed")
 }
 
 impl AppState {
-    pub fn new(db_pool: PgPool) -> Self {
-        let (tx, _) = broadcast::channel(1000);
+    pub fn new(db_pool: PgPool, hub: Arc<Hub<Event>>) -> Self {
         Self {
             db_pool,
-            tx,
-            user_sessions: Arc::new(RwLock::new(HashMap::new())),
+            hub,
+            presence: PresenceTracker::new(),
+            typing: Mutex::new(HashMap::new()),
         }
     }
 
-    pub async fn update_user_chats(&self, username: &str) -> Result<(), sqlx::Error> {
-        let updated_chats = sqlx::query_scalar::<_, i32>(
-            "SELECT id FROM chats WHERE first_user_name = $1 OR second_user_name = $1",
+    /// Delivers `event` to the sessions that should see it and keeps the
+    /// chat routing table of the hub in sync with membership changes.
+    pub async fn publish(&self, event: OutgoingMessage) {
+        match &event {
+            OutgoingMessage::NewMessage(chat_msg) | OutgoingMessage::EditMessage(chat_msg) => {
+                if let Some(chat_id) = chat_msg.chat_id {
+                    self.publish_to_chat_logged(chat_id, event).await;
+                }
+            }
+            OutgoingMessage::Delete { chat_id, .. } => {
+                let chat_id = *chat_id;
+                self.publish_to_chat_logged(chat_id, event).await;
+            }
+            OutgoingMessage::NewChat(chat) => {
+                let members = chat.members.clone();
+                for member in &members {
+                    self.hub.add_member(chat.id, member).await;
+                }
+                self.publish_logged(&members, event).await;
+            }
+            OutgoingMessage::ChatUpdated(chat) => {
+                let members = chat.members.clone();
+                self.hub.send_to_users(&members, event.into()).await;
+            }
+            OutgoingMessage::MemberRemoved { chat_id, username } => {
+                let (chat_id, username) = (*chat_id, username.clone());
+                self.hub.remove_member(chat_id, &username).await;
+                self.hub.send_to_chat(chat_id, event.clone().into()).await;
+                self.hub.send_to_user(&username, event.into()).await;
+            }
+            OutgoingMessage::Read { chat_id, .. }
+            | OutgoingMessage::ReactionUpdate { chat_id, .. } => {
+                let chat_id = *chat_id;
+                self.hub.send_to_chat(chat_id, event.into()).await;
+            }
+            OutgoingMessage::Typing {
+                chat_id, username, ..
+            } => {
+                let (chat_id, username) = (*chat_id, username.clone());
+                self.hub
+                    .send_to_chat_except(chat_id, &username, event.into())
+                    .await;
+            }
+            OutgoingMessage::Presence(presence) => {
+                match presence::friends_of(&self.db_pool, &presence.username).await {
+                    Ok(friends) => self.hub.send_to_users(&friends, event.into()).await,
+                    Err(e) => eprintln!("Error fetching friends: {}", e),
+                }
+            }
+            OutgoingMessage::ChangeBio(bio) => {
+                let username = bio.username.clone();
+                self.hub.send_to_user(&username, event.into()).await;
+            }
+        }
+    }
+
+    /// `publish_logged` to every member of `chat_id`, connected or not.
+    async fn publish_to_chat_logged(&self, chat_id: i32, event: OutgoingMessage) {
+        match sqlx::query_scalar::<_, String>(
+            "SELECT username FROM chat_members WHERE chat_id = $1",
         )
-        .bind(username)
+        .bind(chat_id)
         .fetch_all(&self.db_pool)
-        .await?;
+        .await
+        {
+            Ok(members) => self.publish_logged(&members, event).await,
+            Err(e) => {
+                eprintln!("Error fetching chat members: {}", e);
+                self.hub.send_to_chat(chat_id, event.into()).await;
+            }
+        }
+    }
 
-        let mut sessions = self.user_sessions.write().await;
-        for (_, session) in sessions.iter_mut() {
-            if session.username == username {
-                session.user_chats = updated_chats.clone();
-                break;
+    /// Writes `event` to the event log of `recipients` and delivers it with
+    /// the `seq` each of them got, so a client can `resume` after it. When
+    /// the log can't be written the event still goes out, without a `seq`.
+    async fn publish_logged(&self, recipients: &[String], event: OutgoingMessage) {
+        let value = match serde_json::to_value(&event) {
+            Ok(value) => value,
+            Err(e) => {
+                eprintln!("Error serializing event: {}", e);
+                return;
+            }
+        };
+
+        let mut tx = match self.db_pool.begin().await {
+            Ok(tx) => tx,
+            Err(e) => {
+                eprintln!("Error logging event: {}", e);
+                self.hub.send_to_users(recipients, event.into()).await;
+                return;
+            }
+        };
+        match event_log::append(&mut tx, recipients, &value).await {
+            Ok(logged) => {
+                let seqs = logged
+                    .into_iter()
+                    .map(|entry| (entry.username, entry.seq))
+                    .collect();
+                // the other nodes are notified by the commit itself, in the
+                // order the recipients' rows were locked, this one after it
+                if let Err(e) = self.hub.send_logged(tx, seqs, event.clone().into()).await {
+                    eprintln!("Error logging event: {}", e);
+                    self.hub.send_to_users(recipients, event.into()).await;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error logging event: {}", e);
+                self.hub.send_to_users(recipients, event.into()).await;
             }
         }
+    }
+}
+
+async fn create_group(state: &AppState, username: &str, create: CreateGroup) -> Result<(), String> {
+    let name = create.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_GROUP_NAME_LEN {
+        return Err(format!(
+            "Group name must be between 1 and {} characters",
+            MAX_GROUP_NAME_LEN
+        ));
+    }
 
-        Ok(())
+    let mut invited: Vec<String> = Vec::new();
+    for member in create.members {
+        if member != username && !invited.contains(&member) {
+            invited.push(member);
+        }
+    }
+
+    if invited.is_empty() {
+        return Err("A group needs at least one other member".to_string());
+    }
+    if invited.len() + 1 > MAX_GROUP_MEMBERS {
+        return Err(format!(
+            "A group can have at most {} members",
+            MAX_GROUP_MEMBERS
+        ));
     }
+
+    let friends = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT COUNT(DISTINCT CASE WHEN sender_username = $1 THEN receiver_username ELSE sender_username END)
+        FROM friends
+        WHERE status = 'accepted'
+            AND ((sender_username = $1 AND receiver_username = ANY($2))
+                OR (receiver_username = $1 AND sender_username = ANY($2)))
+        "#,
+    )
+    .bind(username)
+    .bind(&invited)
+    .fetch_one(&state.db_pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Error checking group members: {}", e);
+        "Error checking group members".to_string()
+    })?;
+
+    if friends as usize != invited.len() {
+        return Err("You can only add friends to a group".to_string());
+    }
+
+    let mut members = vec![username.to_string()];
+    members.extend(invited);
+
+    let chat = create_chat(&state.db_pool, Some(name), Some(username), None, &members)
+        .await
+        .map_err(|e| {
+            eprintln!("Error creating group: {}", e);
+            "Error creating group".to_string()
+        })?;
+    state.publish(OutgoingMessage::NewChat(chat)).await;
+    Ok(())
+}
+
+async fn group_for_member(state: &AppState, chat_id: i32, username: &str) -> Result<Chat, String> {
+    let chat = fetch_chat(&state.db_pool, chat_id)
+        .await
+        .map_err(|e| {
+            eprintln!("Error fetching chat: {}", e);
+            "Error fetching chat".to_string()
+        })?
+        .ok_or_else(|| "Chat not found".to_string())?;
+
+    if !chat.members.iter().any(|member| member == username) {
+        return Err("You are not a member of this chat".to_string());
+    }
+    if !chat.is_group {
+        return Err("This is not a group chat".to_string());
+    }
+    Ok(chat)
+}
+
+async fn rename_group(state: &AppState, username: &str, rename: RenameGroup) -> Result<(), String> {
+    let name = rename.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_GROUP_NAME_LEN {
+        return Err(format!(
+            "Group name must be between 1 and {} characters",
+            MAX_GROUP_NAME_LEN
+        ));
+    }
+
+    group_for_member(state, rename.chat_id, username).await?;
+
+    sqlx::query("UPDATE chats SET name = $1, last_update = $2 WHERE id = $3")
+        .bind(name)
+        .bind(Utc::now())
+        .bind(rename.chat_id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Error renaming group: {}", e);
+            "Error renaming group".to_string()
+        })?;
+
+    if let Ok(Some(chat)) = fetch_chat(&state.db_pool, rename.chat_id).await {
+        state.publish(OutgoingMessage::ChatUpdated(chat)).await;
+    }
+    Ok(())
+}
+
+/// Removes `target` from a group. Members can always remove themselves (leave),
+/// removing someone else (kick) is reserved to the group owner. When the owner
+/// leaves, ownership goes to the longest standing member.
+async fn remove_member(
+    state: &AppState,
+    username: &str,
+    chat_id: i32,
+    target: &str,
+) -> Result<(), String> {
+    let chat = group_for_member(state, chat_id, username).await?;
+
+    if target != username {
+        if chat.owner.as_deref() != Some(username) {
+            return Err("Only the group owner can kick members".to_string());
+        }
+        if !chat.members.iter().any(|member| member == target) {
+            return Err("User is not a member of this chat".to_string());
+        }
+    }
+
+    sqlx::query("DELETE FROM chat_members WHERE chat_id = $1 AND username = $2")
+        .bind(chat_id)
+        .bind(target)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Error removing group member: {}", e);
+            "Error removing group member".to_string()
+        })?;
+
+    let owner_left = chat.owner.as_deref() == Some(target);
+    if owner_left
+        && let Err(e) = sqlx::query(
+            "UPDATE chats SET owner = (SELECT username FROM chat_members WHERE chat_id = $1 ORDER BY joined_at, username LIMIT 1) WHERE id = $1",
+        )
+        .bind(chat_id)
+        .execute(&state.db_pool)
+        .await
+    {
+        eprintln!("Error transferring group ownership: {}", e);
+    }
+
+    state
+        .publish(OutgoingMessage::MemberRemoved {
+            chat_id,
+            username: target.to_string(),
+        })
+        .await;
+
+    if owner_left && let Ok(Some(chat)) = fetch_chat(&state.db_pool, chat_id).await {
+        state.publish(OutgoingMessage::ChatUpdated(chat)).await;
+    }
+    Ok(())
+}
+
+/// Handles `typing_start`/`typing_stop`. Nothing is persisted, the state only
+/// lives in `AppState::typing` and expires on its own.
+async fn set_typing(
+    state: Arc<AppState>,
+    username: &str,
+    chat_id: i32,
+    started: bool,
+) -> Result<(), String> {
+    if !state.hub.in_chat(chat_id, username).await {
+        return Err("You are not a member of this chat".to_string());
+    }
+
+    let key = (chat_id, username.to_string());
+    let now = Instant::now();
+
+    if !started {
+        let was_typing = state.typing.lock().await.remove(&key).is_some();
+        if was_typing {
+            state
+                .publish(OutgoingMessage::Typing {
+                    chat_id,
+                    username: username.to_string(),
+                    typing: false,
+                })
+                .await;
+        }
+        return Ok(());
+    }
+
+    {
+        let mut typing = state.typing.lock().await;
+        match typing.get_mut(&key) {
+            Some(entry) => {
+                entry.expires_at = now + TYPING_TIMEOUT;
+                if now.duration_since(entry.last_relayed) < TYPING_THROTTLE {
+                    return Ok(());
+                }
+                entry.last_relayed = now;
+            }
+            None => {
+                typing.insert(
+                    key.clone(),
+                    TypingState {
+                        last_relayed: now,
+                        expires_at: now + TYPING_TIMEOUT,
+                    },
+                );
+                actix_rt::spawn(expire_typing(state.clone(), key));
+            }
+        }
+    }
+
+    state
+        .publish(OutgoingMessage::Typing {
+            chat_id,
+            username: username.to_string(),
+            typing: true,
+        })
+        .await;
+    Ok(())
+}
+
+async fn expire_typing(state: Arc<AppState>, key: (i32, String)) {
+    loop {
+        let expires_at = match state.typing.lock().await.get(&key) {
+            Some(entry) => entry.expires_at,
+            None => return,
+        };
+        tokio::time::sleep_until(expires_at).await;
+
+        let mut typing = state.typing.lock().await;
+        match typing.get(&key) {
+            Some(entry) if entry.expires_at <= Instant::now() => {
+                typing.remove(&key);
+            }
+            Some(_) => continue,
+            None => return,
+        }
+        drop(typing);
+
+        let (chat_id, username) = key;
+        state
+            .publish(OutgoingMessage::Typing {
+                chat_id,
+                username,
+                typing: false,
+            })
+            .await;
+        return;
+    }
+}
+
+/// Moves the read marker of `username` in a chat forward, to `message_id` or
+/// to the latest message when none is given. Markers never move backwards.
+async fn mark_read(state: &AppState, username: &str, read: MarkRead) -> Result<(), String> {
+    match is_member(&state.db_pool, read.chat_id, username).await {
+        Ok(true) => {}
+        Ok(false) => return Err("You are not a member of this chat".to_string()),
+        Err(e) => {
+            eprintln!("Error checking chat membership: {}", e);
+            return Err("Error checking chat membership".to_string());
+        }
+    }
+
+    let message_id = sqlx::query_scalar::<_, Option<i32>>(
+        "SELECT MAX(id) FROM messages WHERE chat_id = $1 AND ($2::INTEGER IS NULL OR id = $2)",
+    )
+    .bind(read.chat_id)
+    .bind(read.message_id)
+    .fetch_one(&state.db_pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Error fetching message: {}", e);
+        "Error fetching message".to_string()
+    })?;
+
+    let message_id = match (message_id, read.message_id) {
+        (Some(message_id), _) => message_id,
+        (None, Some(_)) => return Err("Message not found".to_string()),
+        (None, None) => return Ok(()),
+    };
+
+    let updated = sqlx::query(
+        "UPDATE chat_members SET last_read_message_id = $3 WHERE chat_id = $1 AND username = $2 AND (last_read_message_id IS NULL OR last_read_message_id < $3)",
+    )
+    .bind(read.chat_id)
+    .bind(username)
+    .bind(message_id)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Error updating read marker: {}", e);
+        "Error updating read marker".to_string()
+    })?;
+
+    if updated.rows_affected() > 0 {
+        state
+            .publish(OutgoingMessage::Read {
+                chat_id: read.chat_id,
+                username: username.to_string(),
+                message_id,
+            })
+            .await;
+    }
+    Ok(())
+}
+
+/// Inserts `new_msg` with its attachments and bumps the chat's
+/// `last_update`, all or nothing. `None` when one of the attachments isn't
+/// an unattached upload of `username`, e.g. a concurrent message claimed it.
+async fn insert_message(
+    pool: &PgPool,
+    chat_id: i32,
+    email: &str,
+    username: &str,
+    new_msg: &NewMessage,
+    replied: Option<(String, String)>,
+) -> Result<Option<ChatMessage>, sqlx::Error> {
+    let (replied_user, replied_message) = replied.unzip();
+    let mut tx = pool.begin().await?;
+    let mut message = sqlx::query_as::<_, ChatMessage>(
+        "INSERT INTO messages (chat_id, email, username, message, replied_user, replied_message, time) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+    )
+    .bind(chat_id)
+    .bind(email)
+    .bind(username)
+    .bind(&new_msg.message)
+    .bind(replied_user)
+    .bind(replied_message)
+    .bind(Utc::now())
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if let Some(message_id) = message.id
+        && !new_msg.attachments.is_empty()
+    {
+        message.attachments =
+            attachments::attach(&mut tx, message_id, username, &new_msg.attachments).await?;
+        if message.attachments.len() != new_msg.attachments.len() {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+    }
+
+    sqlx::query("UPDATE chats SET last_update = $1 WHERE id = $2")
+        .bind(Utc::now())
+        .bind(chat_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Some(message))
+}
+
+/// Aggregated reactions of each of `message_ids`, emojis in the order they
+/// were first used.
+async fn fetch_reactions(
+    pool: &PgPool,
+    message_ids: &[i32],
+) -> Result<HashMap<i32, Vec<ReactionCount>>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, MessageReaction>(
+        r#"
+        SELECT message_id, emoji, COUNT(*) AS count,
+            ARRAY_AGG(username::TEXT ORDER BY created_at) AS usernames
+        FROM message_reactions
+        WHERE message_id = ANY($1)
+        GROUP BY message_id, emoji
+        ORDER BY message_id, MIN(created_at)
+        "#,
+    )
+    .bind(message_ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut reactions: HashMap<i32, Vec<ReactionCount>> = HashMap::new();
+    for row in rows {
+        reactions
+            .entry(row.message_id)
+            .or_default()
+            .push(row.reaction);
+    }
+    Ok(reactions)
+}
+
+/// One or more emoji: pictographs with their skin tone modifiers, variation
+/// selectors and tags, joined by ZWJ, as well as flags and keycaps.
+static REACTION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:[0-9#*]\x{FE0F}?\x{20E3}|\p{Regional_Indicator}{2}|\p{Extended_Pictographic}[\p{Emoji_Modifier}\x{FE0E}\x{FE0F}\x{E0020}-\x{E007F}]*(?:\x{200D}\p{Extended_Pictographic}[\p{Emoji_Modifier}\x{FE0E}\x{FE0F}]*)*)+$",
+    )
+    .unwrap()
+});
+
+fn valid_reaction(emoji: &str) -> bool {
+    emoji.len() <= MAX_REACTION_LEN && REACTION.is_match(emoji)
+}
+
+async fn react(
+    state: &AppState,
+    username: &str,
+    reaction: ReactionPayload,
+    add: bool,
+) -> Result<(), String> {
+    if add && !valid_reaction(&reaction.emoji) {
+        return Err("Invalid reaction".to_string());
+    }
+
+    let chat_id = match sqlx::query_scalar::<_, i32>("SELECT chat_id FROM messages WHERE id = $1")
+        .bind(reaction.message_id)
+        .fetch_optional(&state.db_pool)
+        .await
+    {
+        Ok(Some(chat_id)) => chat_id,
+        Ok(None) => return Err("Message not found".to_string()),
+        Err(e) => {
+            eprintln!("Error fetching message: {}", e);
+            return Err("Error fetching message".to_string());
+        }
+    };
+
+    match is_member(&state.db_pool, chat_id, username).await {
+        Ok(true) => {}
+        Ok(false) => return Err("You are not a member of this chat".to_string()),
+        Err(e) => {
+            eprintln!("Error checking chat membership: {}", e);
+            return Err("Error checking chat membership".to_string());
+        }
+    }
+
+    let query = if add {
+        "INSERT INTO message_reactions (message_id, username, emoji) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING"
+    } else {
+        "DELETE FROM message_reactions WHERE message_id = $1 AND username = $2 AND emoji = $3"
+    };
+    let changed = sqlx::query(query)
+        .bind(reaction.message_id)
+        .bind(username)
+        .bind(&reaction.emoji)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Error updating reaction: {}", e);
+            "Error updating reaction".to_string()
+        })?;
+
+    if changed.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    let reactions = fetch_reactions(&state.db_pool, &[reaction.message_id])
+        .await
+        .map_err(|e| {
+            eprintln!("Error fetching reactions: {}", e);
+            "Error fetching reactions".to_string()
+        })?
+        .remove(&reaction.message_id)
+        .unwrap_or_default();
+
+    state
+        .publish(OutgoingMessage::ReactionUpdate {
+            chat_id,
+            message_id: reaction.message_id,
+            reactions,
+        })
+        .await;
+    Ok(())
 }
 
 #[get("/chats")]
@@ -836,30 +1642,43 @@ pub async fn get_chats(
         None => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(&state.db_pool, token).await {
         Ok(claims) => claims,
         Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
     let username = claims.email.clone();
 
-    match sqlx::query_as::<_, Chat>(
-        "SELECT id, first_user_name, second_user_name, last_update FROM chats WHERE first_user_name = $1 OR second_user_name = $1 ORDER BY last_update DESC",
-    )
+    match sqlx::query_as::<_, ChatSummary>(&format!(
+        r#"
+        SELECT {},
+            me.last_read_message_id,
+            (
+                SELECT COUNT(*) FROM messages msg
+                WHERE msg.chat_id = c.id
+                    AND msg.username <> me.username
+                    AND msg.id > COALESCE(me.last_read_message_id, 0)
+            ) AS unread_count
+        FROM chats c
+        JOIN chat_members me ON me.chat_id = c.id AND me.username = $1
+        ORDER BY c.last_update DESC
+        "#,
+        CHAT_COLUMNS
+    ))
     .bind(&username)
     .fetch_all(&state.db_pool)
     .await
     {
-        Ok(chats) => return Ok(HttpResponse::Ok().json(chats)),
+        Ok(chats) => Ok(HttpResponse::Ok().json(chats)),
         Err(e) => {
             eprintln!("Error fetching chats: {}", e);
-            return Ok(HttpResponse::InternalServerError().json("Error fetching chats"));
+            Ok(HttpResponse::InternalServerError().json("Error fetching chats"))
         }
     }
 }
 
-#[get("/messages/{chat_id}")]
-pub async fn get_chat_messages(
+#[get("/chats/{chat_id}/reads")]
+pub async fn get_chat_reads(
     state: web::Data<Arc<AppState>>,
     req: HttpRequest,
     path: web::Path<i32>,
@@ -869,7 +1688,7 @@ pub async fn get_chat_messages(
         None => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(&state.db_pool, token).await {
         Ok(claims) => claims,
         Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
@@ -877,14 +1696,53 @@ pub async fn get_chat_messages(
     let username = claims.email.clone();
     let chat_id = path.into_inner();
 
-    let is_member = match sqlx::query_scalar::<_, bool>(
-        "SELECT EXISTS(SELECT 1 FROM chats WHERE id = $1 AND (first_user_name = $2 OR second_user_name = $2))",
+    match is_member(&state.db_pool, chat_id, &username).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(HttpResponse::Forbidden().json("You are not a member of this chat"));
+        }
+        Err(e) => {
+            eprintln!("Error checking chat membership: {}", e);
+            return Ok(HttpResponse::InternalServerError().json("Error checking chat membership"));
+        }
+    }
+
+    match sqlx::query_as::<_, ReadState>(
+        "SELECT username, last_read_message_id FROM chat_members WHERE chat_id = $1 ORDER BY username",
     )
     .bind(chat_id)
-    .bind(&username)
-    .fetch_one(&state.db_pool)
+    .fetch_all(&state.db_pool)
     .await
     {
+        Ok(reads) => Ok(HttpResponse::Ok().json(reads)),
+        Err(e) => {
+            eprintln!("Error fetching read state: {}", e);
+            Ok(HttpResponse::InternalServerError().json("Error fetching read state"))
+        }
+    }
+}
+
+#[get("/messages/{chat_id}")]
+pub async fn get_chat_messages(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    query: web::Query<MessagesQuery>,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let username = claims.email.clone();
+    let chat_id = path.into_inner();
+
+    let is_member = match is_member(&state.db_pool, chat_id, &username).await {
         Ok(exists) => exists,
         Err(e) => {
             eprintln!("Error checking chat membership: {}", e);
@@ -896,17 +1754,76 @@ pub async fn get_chat_messages(
         return Ok(HttpResponse::Forbidden().json("You are not a member of this chat"));
     }
 
-    match sqlx::query_as::<_, ChatMessage>(
-        "SELECT id, chat_id, username, message, replied_user, replied_message, time, edited FROM messages WHERE chat_id = $1 ORDER BY time ASC",
-    )
-    .bind(chat_id)
-    .fetch_all(&state.db_pool)
-    .await
-    {
-        Ok(messages) => return Ok(HttpResponse::Ok().json(messages)),
+    if query.before.is_some() && query.after.is_some() {
+        return Ok(HttpResponse::BadRequest().json("Use either before or after, not both"));
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_MESSAGES_LIMIT)
+        .clamp(1, MAX_MESSAGES_LIMIT);
+
+    // one extra row tells us whether there is another page
+    let result = match query.after {
+        Some(after) => {
+            sqlx::query_as::<_, ChatMessage>(
+                "SELECT id, chat_id, username, message, replied_user, replied_message, time, edited FROM messages WHERE chat_id = $1 AND id > $2 ORDER BY id ASC LIMIT $3",
+            )
+            .bind(chat_id)
+            .bind(after)
+            .bind(limit + 1)
+            .fetch_all(&state.db_pool)
+            .await
+        }
+        None => sqlx::query_as::<_, ChatMessage>(
+            "SELECT id, chat_id, username, message, replied_user, replied_message, time, edited FROM messages WHERE chat_id = $1 AND ($2::INTEGER IS NULL OR id < $2) ORDER BY id DESC LIMIT $3",
+        )
+        .bind(chat_id)
+        .bind(query.before)
+        .bind(limit + 1)
+        .fetch_all(&state.db_pool)
+        .await
+        .map(|mut messages| {
+            messages.reverse();
+            messages
+        }),
+    };
+
+    match result {
+        Ok(mut messages) => {
+            let has_more = messages.len() > limit as usize;
+            if has_more {
+                if query.after.is_some() {
+                    messages.pop();
+                } else {
+                    messages.remove(0);
+                }
+            }
+
+            let ids: Vec<i32> = messages.iter().filter_map(|message| message.id).collect();
+            let (mut reactions, mut files) = match tokio::try_join!(
+                fetch_reactions(&state.db_pool, &ids),
+                attachments::fetch_attachments(&state.db_pool, &ids),
+            ) {
+                Ok(extras) => extras,
+                Err(e) => {
+                    eprintln!("Error fetching reactions and attachments: {}", e);
+                    return Ok(HttpResponse::InternalServerError()
+                        .json("Error fetching reactions and attachments"));
+                }
+            };
+            for message in &mut messages {
+                if let Some(id) = message.id {
+                    message.reactions = reactions.remove(&id).unwrap_or_default();
+                    message.attachments = files.remove(&id).unwrap_or_default();
+                }
+            }
+
+            Ok(HttpResponse::Ok().json(MessagesPage { messages, has_more }))
+        }
         Err(e) => {
             eprintln!("Error fetching chat messages: {}", e);
-            return Ok(HttpResponse::InternalServerError().json("Error fetching chat messages"));
+            Ok(HttpResponse::InternalServerError().json("Error fetching chat messages"))
         }
     }
 }
@@ -922,7 +1839,7 @@ pub async fn get_user(
         None => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
-    let _claims = match verify_token(token) {
+    let _claims = match verify_token(&state.db_pool, token).await {
         Ok(claims) => claims,
         Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
@@ -934,20 +1851,118 @@ pub async fn get_user(
         .fetch_all(&state.db_pool)
         .await
     {
-        Ok(info) => return Ok(HttpResponse::Ok().json(info)),
+        Ok(info) => Ok(HttpResponse::Ok().json(info)),
         Err(e) => {
             eprintln!("error fetching user informations: {}", e);
-            return Ok(HttpResponse::InternalServerError().json("error fetching user informations"));
+            Ok(HttpResponse::InternalServerError().json("error fetching user informations"))
         }
     }
 }
 
-async fn ws_error_message(message_session: &mut Session, message: &str) {
-    let error_msg = WebSocketMessage {
-        action: "error".to_string(),
-        payload: serde_json::json!({"message": &message}),
-    };
-    if let Ok(error_json) = serde_json::to_string(&error_msg) {
-        let _ = message_session.text(error_json).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn reactions_must_be_short_symbols() {
+        assert!(valid_reaction("👍"));
+        assert!(valid_reaction("👩‍👩‍👧"));
+        assert!(valid_reaction("👍🏽"));
+        assert!(valid_reaction("❤️"));
+        assert!(valid_reaction("🇫🇷"));
+        assert!(valid_reaction("1️⃣"));
+        assert!(!valid_reaction(""));
+        assert!(!valid_reaction("lol"));
+        assert!(!valid_reaction("<>"));
+        assert!(!valid_reaction("!!!"));
+        assert!(!valid_reaction("—"));
+        assert!(!valid_reaction("\u{FE0F}"));
+        assert!(!valid_reaction("🎉 🎉"));
+        assert!(!valid_reaction(&"🎉".repeat(9)));
+    }
+
+    fn state() -> AppState {
+        AppState::new(db::pool_for_tests(), Arc::new(Hub::new()))
+    }
+
+    fn chat_event(event: Event) -> OutgoingMessage {
+        match event {
+            Event::Chat(event) => event.event,
+            event => panic!("expected a chat event, got {:?}", event),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn delete_is_only_delivered_to_chat_members() {
+        // the pool never connects, so the members come from the hub's
+        // routing table
+        let state = state();
+        let (_, mut member) = state.hub.connect("alice", 1, &[1]).await;
+        let (_, mut non_member) = state.hub.connect("mallory", 2, &[2]).await;
+
+        state
+            .publish(OutgoingMessage::Delete {
+                message_id: 10,
+                chat_id: 1,
+            })
+            .await;
+
+        assert!(matches!(
+            member.try_recv().map(chat_event),
+            Ok(OutgoingMessage::Delete {
+                message_id: 10,
+                chat_id: 1
+            })
+        ));
+        assert!(non_member.try_recv().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn typing_is_relayed_to_other_members_and_throttled() {
+        let state = Arc::new(state());
+        let (_, mut typer) = state.hub.connect("alice", 1, &[1]).await;
+        let (_, mut member) = state.hub.connect("bob", 1, &[1]).await;
+        let (_, mut non_member) = state.hub.connect("mallory", 1, &[2]).await;
+
+        set_typing(state.clone(), "alice", 1, true).await.unwrap();
+        set_typing(state.clone(), "alice", 1, true).await.unwrap();
+
+        assert!(matches!(
+            member.try_recv().map(chat_event),
+            Ok(OutgoingMessage::Typing { typing: true, .. })
+        ));
+        assert!(member.try_recv().is_err());
+        assert!(typer.try_recv().is_err());
+        assert!(non_member.try_recv().is_err());
+        assert!(set_typing(state.clone(), "mallory", 1, true).await.is_err());
+
+        set_typing(state.clone(), "alice", 1, false).await.unwrap();
+        assert!(matches!(
+            member.try_recv().map(chat_event),
+            Ok(OutgoingMessage::Typing { typing: false, .. })
+        ));
+    }
+
+    #[test]
+    fn delete_event_carries_chat_id() {
+        let event = OutgoingMessage::Delete {
+            message_id: 10,
+            chat_id: 1,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"action": "delete", "message_id": 10, "chat_id": 1})
+        );
+
+        let logged = ChatEvent {
+            seq: Some(7),
+            event,
+        };
+        assert_eq!(
+            serde_json::to_value(&logged).unwrap(),
+            serde_json::json!({"action": "delete", "message_id": 10, "chat_id": 1, "seq": 7})
+        );
     }
 }
@@ -1,29 +1,15 @@
+use crate::config;
+use crate::heartbeat::Heartbeat;
+use crate::hub::Hub;
 use crate::middlewares::verify_token;
+use crate::protocol::{self, ErrorCode, Reply};
+use crate::rate_limit::RateLimits;
+use crate::routes::socket::{Event, Namespace};
 use actix_web::{Error, HttpRequest, HttpResponse, get, web};
-use actix_ws::{Message, Session};
-use futures_util::StreamExt as _;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::select;
-use tokio::sync::{RwLock, broadcast};
-
-pub async fn friend_table(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-            CREATE TABLE IF NOT EXISTS friends (
-                id SERIAL PRIMARY KEY,
-                sender_username VARCHAR(255) NOT NULL REFERENCES users(username),
-                receiver_username VARCHAR(255) NOT NULL REFERENCES users(username),
-                status VARCHAR(255) NOT NULL
-            )
-            "#,
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
-}
 
 #[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
 pub struct Friends {
@@ -49,407 +35,564 @@ pub struct FriendRequestStatus {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CancelFriendRequest {
     pub friend_req_id: i32,
+    pub sender_username: String,
+    pub receiver_username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FriendTarget {
+    pub username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockUpdate {
+    pub blocker_username: String,
+    pub blocked_username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BlockedUser {
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelPayload {
+    pub friend_req_id: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    pub action: String,
-    pub payload: serde_json::Value,
+pub struct AcceptPayload {
+    pub friend_id: i32,
+}
+
+/// Everything a client can ask of `/ws/friend_req`, framed by
+/// `protocol::parse`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", content = "payload", rename_all = "snake_case")]
+pub enum IncomingFriendAction {
+    SendRequest(FriendRequestPayload),
+    Cancel(CancelPayload),
+    Accept(AcceptPayload),
+    Unfriend(FriendTarget),
+    Block(FriendTarget),
+    Unblock(FriendTarget),
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "action", rename_all = "snake_case")]
 pub enum FriendAction {
     SendRequest(Friends),
     Accept(FriendRequestStatus),
     Cancel(CancelFriendRequest),
-}
-
-#[derive(Debug, Clone)]
-pub struct UserSession {
-    pub email: String,
-    pub username: String,
-    pub tx: broadcast::Sender<FriendAction>,
+    Unfriend(CancelFriendRequest),
+    Block(BlockUpdate),
+    Unblock(BlockUpdate),
 }
 
 pub struct FriendAppState {
     pub db_pool: PgPool,
-    pub tx: broadcast::Sender<FriendAction>,
-    pub user_sessions: Arc<RwLock<HashMap<String, UserSession>>>,
+    pub hub: Arc<Hub<Event>>,
+}
+
+/// Whether either user blocked the other.
+pub async fn is_blocked(pool: &PgPool, first: &str, second: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM blocks WHERE (blocker_username = $1 AND blocked_username = $2) OR (blocker_username = $2 AND blocked_username = $1))",
+    )
+    .bind(first)
+    .bind(second)
+    .fetch_one(pool)
+    .await
+}
+
+/// Whether `username` and the other member of the 1:1 chat `chat_id` blocked
+/// each other, group chats are never blocked as a whole.
+pub async fn is_blocked_in_chat(
+    pool: &PgPool,
+    chat_id: i32,
+    username: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM chats c
+            JOIN chat_members m ON m.chat_id = c.id AND m.username <> $2
+            JOIN blocks b
+                ON (b.blocker_username = $2 AND b.blocked_username = m.username)
+                OR (b.blocker_username = m.username AND b.blocked_username = $2)
+            WHERE c.id = $1 AND NOT c.is_group
+        )
+        "#,
+    )
+    .bind(chat_id)
+    .bind(username)
+    .fetch_one(pool)
+    .await
 }
 
 // routes
+/// Legacy friend socket, `/socket` carries the same events under the
+/// `friend` namespace.
 #[get("/ws/friend_req")]
 pub async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
     state: web::Data<Arc<FriendAppState>>,
+    rate_limits: web::Data<RateLimits>,
 ) -> Result<HttpResponse, Error> {
     let token = match req.cookie("token") {
         Some(token) => token.value().to_string(),
         None => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(&state.db_pool, token).await {
         Ok(claims) => claims,
         Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
     let username = claims.email.clone();
-    let email = claims.sub.clone();
 
-    let (response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    let version = match protocol::negotiate(&req) {
+        Ok(version) => version,
+        Err(response) => return Ok(response),
+    };
+
+    let (mut response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    protocol::accept(&mut response, version);
+    let mut heartbeat = Heartbeat::new(session.clone(), &config::get().websocket, claims.exp);
 
-    let db_pool = state.db_pool.clone();
-    let second_spawn_db_pool = db_pool.clone();
-    let tx = state.tx.clone();
-    let mut rx = tx.subscribe();
+    let (session_id, mut rx) = state.hub.connect(&username, claims.sid, &[]).await;
 
     let mut broadcast_session = session.clone();
-    let mut message_session = session.clone();
+    let message_session = session;
 
-    let user_sessions = state.user_sessions.clone();
-    let broadcast_session_username = username.clone();
-    {
-        let mut sessions = user_sessions.write().await;
-        sessions.insert(
-            email.clone(),
-            UserSession {
-                email: email.clone(),
-                username: username.clone(),
-                tx: tx.clone(),
-            },
-        );
-    }
-    let broadcast_user_sessions = user_sessions.clone();
-    let broadcast_email = email.clone();
+    actix_rt::spawn(async move {
+        while let Some(text) = heartbeat.next_text(&mut msg_stream).await {
+            let request = match protocol::parse::<IncomingFriendAction>(&text, version) {
+                Ok(request) => request,
+                Err(rejection) => {
+                    Reply::reject(message_session.clone(), version, rejection).await;
+                    continue;
+                }
+            };
+            let mut reply = Reply::new(message_session.clone(), version, request.id);
+            if rate_limits
+                .check_ws_action(&username, &request.name)
+                .is_err()
+            {
+                reply
+                    .fail(
+                        ErrorCode::RateLimited,
+                        "You're doing that too fast, slow down",
+                    )
+                    .await;
+                continue;
+            }
+            handle(&state, &username, request.action, &mut reply).await;
+            reply.ack().await;
+        }
+        println!("(friend.rs): session closed and removed.");
+
+        state.hub.disconnect(&username, session_id).await;
+    });
 
     actix_rt::spawn(async move {
-        while let Some(Ok(msg)) = msg_stream.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if let Ok(ws_msg) = serde_json::from_str::<WebSocketMessage>(&text) {
-                        match ws_msg.action.as_str() {
-                            "send_request" => {
-                                if let Ok(req) =
-                                    serde_json::from_value::<FriendRequestPayload>(ws_msg.payload)
-                                {
-                                    let new_friend = Friends {
-                                        id: None,
-                                        sender_username: username.clone(),
-                                        receiver_username: req.receiver_username.clone(),
-                                        status: "pending".to_string(),
-                                    };
-
-                                    let user_exists = match sqlx::query_scalar::<_, bool>(
-                                        "SELECT EXISTS(SELECT * FROM users WHERE username = $1)",
-                                    )
-                                    .bind(&new_friend.receiver_username)
-                                    .fetch_one(&db_pool)
-                                    .await
-                                    {
-                                        Ok(user_exists) => user_exists,
-                                        Err(e) => {
-                                            eprintln!("Error checking if user exists: {}", e);
-                                            ws_error_message(
-                                                &mut message_session,
-                                                "Error checking if user exists",
-                                            )
-                                            .await;
-                                            return;
-                                        }
-                                    };
-
-                                    let already_sent = match sqlx::query_scalar::<_, bool>(
-                                            "SELECT EXISTS(SELECT * FROM friends WHERE (sender_username = $1 AND receiver_username = $2) OR (sender_username = $2 AND receiver_username = $1))",
-                                        )
-                                        .bind(&new_friend.sender_username)
-                                        .bind(&new_friend.receiver_username)
-                                        .fetch_one(&db_pool)
-                                        .await {
-                                            Ok(already_sent) => already_sent,
-                                            Err(e) => {
-                                                eprintln!("Error checking if friend request already exists: {}", e);
-                                                ws_error_message(&mut message_session, "Error checking if friend request already exists").await;
-                                                return;
-                                            }
-                                        };
-
-                                    let send_to_itself =
-                                        new_friend.sender_username == new_friend.receiver_username;
-
-                                    if send_to_itself {
-                                        ws_error_message(
-                                            &mut message_session,
-                                            "You can't send message to yourself",
-                                        )
-                                        .await;
-                                        return;
-                                    }
-
-                                    if already_sent {
-                                        ws_error_message(
-                                            &mut message_session,
-                                            "Friend request already sent or received",
-                                        )
-                                        .await;
-                                        return;
-                                    }
-
-                                    if !user_exists {
-                                        ws_error_message(&mut message_session, "User not found")
-                                            .await;
-                                        return;
-                                    }
-
-                                    match sqlx::query_as::<_, Friends>(
-                                            "INSERT INTO friends (sender_username, receiver_username, status) VALUES ($1, $2, 'pending') RETURNING *",
-                                        )
-                                        .bind(&new_friend.sender_username)
-                                        .bind(&new_friend.receiver_username)
-                                        .fetch_one(&db_pool)
-                                        .await
-                                        {
-                                            Ok(friend) => {
-                                                let _ = tx.send(FriendAction::SendRequest(friend));
-                                            }
-                                            Err(e) => {
-                                                eprintln!("Error creating friend request: {}", e);
-                                                ws_error_message(&mut message_session, "Error creating friend request").await;
-                                            }
-                                        }
-                                }
-                            }
-
-                            "cancel" => {
-                                if let Some(friend_req_id) =
-                                    ws_msg.payload.get("friend_req_id").and_then(|v| v.as_i64())
-                                {
-                                    let id_i32 = friend_req_id as i32;
-
-                                    let can_cancel = match sqlx::query_as::<_, Friends>(
-                                            "SELECT * FROM friends WHERE id = $1 AND (receiver_username = $2 OR sender_username = $2)"
-                                        )
-                                        .bind(&id_i32)
-                                        .bind(&username)
-                                        .fetch_all(&db_pool)
-                                        .await
-                                        {
-                                            Ok(_) => true,
-                                            Err(_) => false
-                                        };
-
-                                    if can_cancel {
-                                        match sqlx::query("DELETE FROM friends WHERE id = $1")
-                                            .bind(&id_i32)
-                                            .execute(&db_pool)
-                                            .await
-                                        {
-                                            Ok(_) => {
-                                                let _ = tx.send(FriendAction::Cancel(
-                                                    CancelFriendRequest {
-                                                        friend_req_id: id_i32,
-                                                    },
-                                                ));
-                                            }
-                                            Err(e) => {
-                                                println!("Error deleting friend: {}", e);
-                                                ws_error_message(
-                                                    &mut message_session,
-                                                    "Error deleting friend",
-                                                )
-                                                .await;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-
-                            "accept" => {
-                                if let Some(friend_id) =
-                                    ws_msg.payload.get("friend_id").and_then(|v| v.as_i64())
-                                {
-                                    let receiver = username.clone();
-                                    let is_receiver = match sqlx::query_scalar::<_, bool>(
-                                            "SELECT EXISTS(SELECT * FROM friends WHERE (id = $1 AND receiver_username = $2))"
-                                        )
-                                        .bind(friend_id as i32)
-                                        .bind(&receiver)
-                                        .fetch_one(&db_pool)
-                                        .await
-                                        {
-                                            Ok(is_receiver) => is_receiver,
-                                            Err(e) => {
-                                                eprintln!("Error checking if user is receiver: {}", e);
-                                                ws_error_message(&mut message_session, "Error checking if user is receiver").await;
-                                                return;
-                                            }
-                                        };
-
-                                    let sender: String = match sqlx::query_scalar(
-                                        "SELECT sender_username FROM friends WHERE id = $1",
-                                    )
-                                    .bind(friend_id as i32)
-                                    .fetch_one(&db_pool)
-                                    .await
-                                    {
-                                        Ok(sender) => sender,
-                                        Err(e) => {
-                                            eprintln!("Failed to get sender: {}", e);
-                                            ws_error_message(
-                                                &mut message_session,
-                                                "Failed to get sender",
-                                            )
-                                            .await;
-                                            return;
-                                        }
-                                    };
-
-                                    if !is_receiver {
-                                        ws_error_message(
-                                            &mut message_session,
-                                            "You can accept your own friend request",
-                                        )
-                                        .await;
-                                        return;
-                                    }
-
-                                    match sqlx::query_as::<_, Friends>(
-                                            "UPDATE friends SET status = 'accepted' WHERE id = $1 RETURNING *",
-                                        )
-                                        .bind(friend_id as i32)
-                                        .fetch_one(&db_pool)
-                                        .await
-                                        {
-                                            Ok(friend) => {
-                                                let status = FriendRequestStatus {
-                                                    id: friend.id.unwrap(),
-                                                    sender_username: sender,
-                                                    receiver_username: receiver,
-                                                    status: "accepted".to_string(),
-                                                };
-                                                let _ = tx.send(FriendAction::Accept(status));
-                                            }
-                                            Err(e) => {
-                                                eprintln!("Error accepting friend request: {}", e);
-                                                ws_error_message(&mut message_session, "Error accepting friend request").await;
-                                            }
-                                        }
-                                }
-                            }
-
-                            _ => {
-                                eprintln!("Unknown action: {}", ws_msg.action);
-                                ws_error_message(&mut message_session, "Unknown action").await;
-                            }
-                        }
-                    } else {
-                        eprintln!("Failed to parse WebSocket message: {}", text);
-                    }
+        while let Some(event) = rx.recv().await {
+            // the hub is shared with the chat sockets
+            if event.namespace() != Namespace::Friend {
+                continue;
+            }
+            if broadcast_session.text(event.frame(false)).await.is_err() {
+                return;
+            }
+        }
+        // the hub dropped this session, e.g. after a password reset
+        let _ = broadcast_session.close(None).await;
+    });
+    Ok(response)
+}
+
+/// Carries out one friend request, shared by `/ws/friend_req` and `/socket`.
+pub async fn handle(
+    state: &FriendAppState,
+    username: &str,
+    action: IncomingFriendAction,
+    reply: &mut Reply,
+) {
+    let db_pool = &state.db_pool;
+
+    match action {
+        IncomingFriendAction::SendRequest(req) => {
+            let new_friend = Friends {
+                id: None,
+                sender_username: username.to_string(),
+                receiver_username: req.receiver_username.to_string(),
+                status: "pending".to_string(),
+            };
+
+            let user_exists = match sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS(SELECT * FROM users WHERE username = $1)",
+            )
+            .bind(&new_friend.receiver_username)
+            .fetch_one(db_pool)
+            .await
+            {
+                Ok(user_exists) => user_exists,
+                Err(e) => {
+                    eprintln!("Error checking if user exists: {}", e);
+                    reply.error("Error checking if user exists").await;
+                    return;
                 }
-                Message::Close(_) => {
-                    {
-                        let mut sessions = user_sessions.write().await;
-                        sessions.remove(&email);
-                    }
-                    println!("(friend.rs): session closed and removed.");
-                    break;
+            };
+
+            let already_sent = match sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS(SELECT * FROM friends WHERE (sender_username = $1 AND receiver_username = $2) OR (sender_username = $2 AND receiver_username = $1))",
+            )
+            .bind(&new_friend.sender_username)
+            .bind(&new_friend.receiver_username)
+            .fetch_one(db_pool)
+            .await
+            {
+                Ok(already_sent) => already_sent,
+                Err(e) => {
+                    eprintln!("Error checking if friend request already exists: {}", e);
+                    reply
+                        .error("Error checking if friend request already exists")
+                        .await;
+                    return;
                 }
-                _ => {
-                    {
-                        let mut sessions = user_sessions.write().await;
-                        sessions.remove(&email);
-                    }
-                    println!("(friend.rs): session closed and removed.");
-                    break;
+            };
+
+            let send_to_itself = new_friend.sender_username == new_friend.receiver_username;
+
+            if send_to_itself {
+                reply.error("You can't send message to yourself").await;
+                return;
+            }
+
+            if already_sent {
+                reply.error("Friend request already sent or received").await;
+                return;
+            }
+
+            if !user_exists {
+                reply.error("User not found").await;
+                return;
+            }
+
+            match is_blocked(
+                db_pool,
+                &new_friend.sender_username,
+                &new_friend.receiver_username,
+            )
+            .await
+            {
+                Ok(false) => {}
+                Ok(true) => {
+                    reply
+                        .error("You can't send a friend request to this user")
+                        .await;
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Error checking blocks: {}", e);
+                    reply.error("Error checking blocks").await;
+                    return;
+                }
+            }
+
+            match sqlx::query_as::<_, Friends>(
+                "INSERT INTO friends (sender_username, receiver_username, status) VALUES ($1, $2, 'pending') RETURNING *",
+            )
+            .bind(&new_friend.sender_username)
+            .bind(&new_friend.receiver_username)
+            .fetch_one(db_pool)
+            .await
+            {
+                Ok(friend) => {
+                    state.publish(FriendAction::SendRequest(friend)).await;
+                }
+                Err(e) => {
+                    eprintln!("Error creating friend request: {}", e);
+                    reply.error("Error creating friend request").await;
                 }
             }
         }
-    });
 
-    actix_rt::spawn(async move {
-        let mut session_alive = true;
-
-        while session_alive {
-            select! {
-                msg = rx.recv() => {
-                    match msg {
-                        Ok(msg) => {
-                            let session_still_alive = {
-                                let sessions = broadcast_user_sessions.read().await;
-                                sessions.contains_key(&broadcast_email)
-                            };
-
-                            if !session_still_alive {
-                                session_alive = false;
-                                continue;
-                            }
-
-                            let should_send = match &msg {
-                                            FriendAction::SendRequest(friend) => {
-                                                broadcast_session_username == friend.receiver_username
-                                                || broadcast_session_username == friend.sender_username
-                                            }
-                                            FriendAction::Accept(status) => {
-                                                match sqlx::query_as::<_, Friends>(
-                                                    "SELECT * FROM friends WHERE id = $1 AND (sender_username = $2 OR receiver_username = $2)"
-                                                )
-                                                .bind(status.id)
-                                                .bind(&broadcast_session_username)
-                                                .fetch_optional(&second_spawn_db_pool)
-                                                .await {
-                                                    Ok(Some(_)) => true,
-                                                    Ok(None) => false,
-                                                    Err(_) => false
-                                                }
-                                            }
-                                            FriendAction::Cancel(_) => true
-                                        };
-
-                            if should_send {
-                                if let Err(_) = broadcast_session
-                                    .text(serde_json::to_string(&msg).unwrap())
-                                    .await
-                                {
-                                    session_alive = false;
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            session_alive = false;
-                        }
+        IncomingFriendAction::Cancel(cancel) => {
+            let id_i32 = cancel.friend_req_id;
+
+            let friend = match sqlx::query_as::<_, Friends>(
+                "SELECT * FROM friends WHERE id = $1 AND (receiver_username = $2 OR sender_username = $2)",
+            )
+            .bind(id_i32)
+            .bind(username)
+            .fetch_optional(db_pool)
+            .await
+            {
+                Ok(Some(friend)) => friend,
+                Ok(None) => {
+                    reply.error("Friend request not found").await;
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Error fetching friend request: {}", e);
+                    reply.error("Error fetching friend request").await;
+                    return;
+                }
+            };
+
+            match sqlx::query("DELETE FROM friends WHERE id = $1")
+                .bind(id_i32)
+                .execute(db_pool)
+                .await
+            {
+                Ok(_) => {
+                    state
+                        .publish(FriendAction::Cancel(CancelFriendRequest {
+                            friend_req_id: id_i32,
+                            sender_username: friend.sender_username,
+                            receiver_username: friend.receiver_username,
+                        }))
+                        .await;
+                }
+                Err(e) => {
+                    println!("Error deleting friend: {}", e);
+                    reply.error("Error deleting friend").await;
+                }
+            }
+        }
+
+        IncomingFriendAction::Accept(accept) => {
+            let friend_id = accept.friend_id;
+            let receiver = username.to_string();
+            let is_receiver = match sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS(SELECT * FROM friends WHERE (id = $1 AND receiver_username = $2))",
+            )
+            .bind(friend_id)
+            .bind(&receiver)
+            .fetch_one(db_pool)
+            .await
+            {
+                Ok(is_receiver) => is_receiver,
+                Err(e) => {
+                    eprintln!("Error checking if user is receiver: {}", e);
+                    reply.error("Error checking if user is receiver").await;
+                    return;
+                }
+            };
+
+            let sender: String =
+                match sqlx::query_scalar("SELECT sender_username FROM friends WHERE id = $1")
+                    .bind(friend_id)
+                    .fetch_one(db_pool)
+                    .await
+                {
+                    Ok(sender) => sender,
+                    Err(e) => {
+                        eprintln!("Failed to get sender: {}", e);
+                        reply.error("Failed to get sender").await;
+                        return;
                     }
+                };
+
+            if !is_receiver {
+                reply.error("You can accept your own friend request").await;
+                return;
+            }
+
+            match sqlx::query_as::<_, Friends>(
+                "UPDATE friends SET status = 'accepted' WHERE id = $1 RETURNING *",
+            )
+            .bind(friend_id)
+            .fetch_one(db_pool)
+            .await
+            {
+                Ok(friend) => {
+                    let status = FriendRequestStatus {
+                        id: friend.id.unwrap(),
+                        sender_username: sender,
+                        receiver_username: receiver,
+                        status: "accepted".to_string(),
+                    };
+                    state.publish(FriendAction::Accept(status)).await;
+                }
+                Err(e) => {
+                    eprintln!("Error accepting friend request: {}", e);
+                    reply.error("Error accepting friend request").await;
+                }
+            }
+        }
+
+        IncomingFriendAction::Unfriend(target) => {
+            match sqlx::query_as::<_, Friends>(
+                "DELETE FROM friends WHERE status = 'accepted' AND ((sender_username = $1 AND receiver_username = $2) OR (sender_username = $2 AND receiver_username = $1)) RETURNING *",
+            )
+            .bind(username)
+            .bind(&target.username)
+            .fetch_optional(db_pool)
+            .await
+            {
+                Ok(Some(friend)) => {
+                    state
+                        .publish(FriendAction::Unfriend(removed_friend(friend)))
+                        .await;
                 }
+                Ok(None) => {
+                    reply.error("You are not friends with this user").await;
+                }
+                Err(e) => {
+                    eprintln!("Error removing friend: {}", e);
+                    reply.error("Error removing friend").await;
+                }
+            }
+        }
+
+        IncomingFriendAction::Block(target) => {
+            if target.username == username {
+                reply.error("You can't block yourself").await;
+                return;
+            }
 
-                _ = async {
-                    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
-                    loop {
-                        interval.tick().await;
-                        let sessions = broadcast_user_sessions.read().await;
-                        if !sessions.contains_key(&broadcast_email) {
-                            break;
-                        }
+            match block_user(db_pool, username, &target.username).await {
+                Ok(Some(removed)) => {
+                    for friend in removed {
+                        let action = if friend.status == "accepted" {
+                            FriendAction::Unfriend(removed_friend(friend))
+                        } else {
+                            FriendAction::Cancel(removed_friend(friend))
+                        };
+                        state.publish(action).await;
                     }
-                } => {
-                    session_alive = false;
+                    state
+                        .publish(FriendAction::Block(BlockUpdate {
+                            blocker_username: username.to_string(),
+                            blocked_username: target.username,
+                        }))
+                        .await;
+                }
+                Ok(None) => {
+                    reply.error("User not found").await;
+                }
+                Err(e) => {
+                    eprintln!("Error blocking user: {}", e);
+                    reply.error("Error blocking user").await;
                 }
             }
         }
-    });
-    Ok(response)
+
+        IncomingFriendAction::Unblock(target) => {
+            match sqlx::query("DELETE FROM blocks WHERE blocker_username = $1 AND blocked_username = $2")
+                .bind(username)
+                .bind(&target.username)
+                .execute(db_pool)
+                .await
+            {
+                Ok(result) if result.rows_affected() > 0 => {
+                    state
+                        .publish(FriendAction::Unblock(BlockUpdate {
+                            blocker_username: username.to_string(),
+                            blocked_username: target.username,
+                        }))
+                        .await;
+                }
+                Ok(_) => {
+                    reply.error("User is not blocked").await;
+                }
+                Err(e) => {
+                    eprintln!("Error unblocking user: {}", e);
+                    reply.error("Error unblocking user").await;
+                }
+            }
+        }
+    }
 }
 
 impl FriendAppState {
-    pub fn new(db_pool: PgPool) -> Self {
-        let (tx, _) = broadcast::channel::<FriendAction>(20);
-        FriendAppState {
-            db_pool,
-            tx,
-            user_sessions: Arc::new(RwLock::new(HashMap::new())),
+    pub fn new(db_pool: PgPool, hub: Arc<Hub<Event>>) -> Self {
+        FriendAppState { db_pool, hub }
+    }
+
+    /// Delivers `action` to the sessions of the users it concerns.
+    pub async fn publish(&self, action: FriendAction) {
+        match &action {
+            FriendAction::SendRequest(friend) => {
+                let users = vec![
+                    friend.sender_username.clone(),
+                    friend.receiver_username.clone(),
+                ];
+                self.hub.send_to_users(&users, action.into()).await;
+            }
+            FriendAction::Accept(status) => {
+                let users = vec![
+                    status.sender_username.clone(),
+                    status.receiver_username.clone(),
+                ];
+                self.hub.send_to_users(&users, action.into()).await;
+            }
+            FriendAction::Cancel(cancel) | FriendAction::Unfriend(cancel) => {
+                let users = vec![
+                    cancel.sender_username.clone(),
+                    cancel.receiver_username.clone(),
+                ];
+                self.hub.send_to_users(&users, action.into()).await;
+            }
+            // the blocked user is not told, only the blocker's other devices
+            FriendAction::Block(update) | FriendAction::Unblock(update) => {
+                let blocker = update.blocker_username.clone();
+                self.hub.send_to_user(&blocker, action.into()).await;
+            }
         }
     }
 }
 
+fn removed_friend(friend: Friends) -> CancelFriendRequest {
+    CancelFriendRequest {
+        friend_req_id: friend.id.unwrap_or_default(),
+        sender_username: friend.sender_username,
+        receiver_username: friend.receiver_username,
+    }
+}
+
+/// Blocks `blocked` and drops any friendship or pending request between the
+/// two users, returning the removed rows or `None` when `blocked` doesn't exist.
+async fn block_user(
+    pool: &PgPool,
+    blocker: &str,
+    blocked: &str,
+) -> Result<Option<Vec<Friends>>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let user_exists =
+        sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM users WHERE username = $1)")
+            .bind(blocked)
+            .fetch_one(&mut *transaction)
+            .await?;
+    if !user_exists {
+        return Ok(None);
+    }
+
+    sqlx::query(
+        "INSERT INTO blocks (blocker_username, blocked_username) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(blocker)
+    .bind(blocked)
+    .execute(&mut *transaction)
+    .await?;
+
+    let removed = sqlx::query_as::<_, Friends>(
+        "DELETE FROM friends WHERE (sender_username = $1 AND receiver_username = $2) OR (sender_username = $2 AND receiver_username = $1) RETURNING *",
+    )
+    .bind(blocker)
+    .bind(blocked)
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(Some(removed))
+}
+
 #[get("/friend_req")]
 pub async fn get_friend_req(
     state: web::Data<Arc<FriendAppState>>,
@@ -460,7 +603,7 @@ pub async fn get_friend_req(
         None => return Ok(HttpResponse::Unauthorized().finish()),
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_token(&state.db_pool, token).await {
         Ok(claims) => claims,
         Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
@@ -474,20 +617,92 @@ pub async fn get_friend_req(
     .fetch_all(&state.db_pool)
     .await
     {
-        Ok(friends) => return Ok(HttpResponse::Ok().json(friends)),
+        Ok(friends) => Ok(HttpResponse::Ok().json(friends)),
         Err(e) => {
             println!("{:?}", e);
-            return Ok(HttpResponse::InternalServerError().json("Failed to fetch friend request"));
+            Ok(HttpResponse::InternalServerError().json("Failed to fetch friend request"))
         }
     }
 }
 
-async fn ws_error_message(message_session: &mut Session, message: &str) {
-    let error_msg = WebSocketMessage {
-        action: "error".to_string(),
-        payload: serde_json::json!({"message": &message}),
+#[get("/blocks")]
+pub async fn get_blocks(
+    state: web::Data<Arc<FriendAppState>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
     };
-    if let Ok(error_json) = serde_json::to_string(&error_msg) {
-        let _ = message_session.text(error_json).await;
+
+    match sqlx::query_as::<_, BlockedUser>(
+        "SELECT blocked_username AS username, created_at FROM blocks WHERE blocker_username = $1 ORDER BY created_at DESC",
+    )
+    .bind(&claims.email)
+    .fetch_all(&state.db_pool)
+    .await
+    {
+        Ok(blocked) => Ok(HttpResponse::Ok().json(blocked)),
+        Err(e) => {
+            eprintln!("Error fetching blocked users: {}", e);
+            Ok(HttpResponse::InternalServerError().json("Failed to fetch blocked users"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[actix_rt::test]
+    async fn cancel_is_only_delivered_to_participants() {
+        let state = FriendAppState::new(db::pool_for_tests(), Arc::new(Hub::new()));
+        let (_, mut sender) = state.hub.connect("alice", 1, &[]).await;
+        let (_, mut receiver) = state.hub.connect("bob", 1, &[]).await;
+        let (_, mut non_member) = state.hub.connect("mallory", 1, &[]).await;
+
+        state
+            .publish(FriendAction::Cancel(CancelFriendRequest {
+                friend_req_id: 3,
+                sender_username: "alice".to_string(),
+                receiver_username: "bob".to_string(),
+            }))
+            .await;
+
+        assert!(matches!(
+            sender.try_recv(),
+            Ok(Event::Friend(FriendAction::Cancel(_)))
+        ));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(Event::Friend(FriendAction::Cancel(_)))
+        ));
+        assert!(non_member.try_recv().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn block_is_not_delivered_to_the_blocked_user() {
+        let state = FriendAppState::new(db::pool_for_tests(), Arc::new(Hub::new()));
+        let (_, mut blocker) = state.hub.connect("alice", 1, &[]).await;
+        let (_, mut blocked) = state.hub.connect("mallory", 1, &[]).await;
+
+        state
+            .publish(FriendAction::Block(BlockUpdate {
+                blocker_username: "alice".to_string(),
+                blocked_username: "mallory".to_string(),
+            }))
+            .await;
+
+        assert!(matches!(
+            blocker.try_recv(),
+            Ok(Event::Friend(FriendAction::Block(_)))
+        ));
+        assert!(blocked.try_recv().is_err());
     }
 }
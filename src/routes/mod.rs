@@ -1,3 +1,9 @@
+pub mod attachments;
 pub mod auth;
 pub mod chat;
 pub mod friend;
+pub mod presence;
+pub mod search;
+pub mod sessions;
+pub mod socket;
+pub mod two_factor;
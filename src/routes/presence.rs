@@ -0,0 +1,450 @@
+use crate::hub::SessionId;
+use crate::middlewares::verify_token;
+use crate::routes::chat::{AppState, OutgoingMessage};
+use crate::routes::socket::Event;
+use actix_web::{Error, HttpRequest, HttpResponse, get, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgPool};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time;
+
+pub const MAX_PRESENCE_QUERY: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub username: String,
+    pub status: Status,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetStatus {
+    pub status: Status,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresenceQuery {
+    pub usernames: String,
+}
+
+#[derive(Debug, FromRow)]
+struct LastSeen {
+    username: String,
+    last_seen: Option<DateTime<Utc>>,
+    #[sqlx(flatten)]
+    count: SessionCount,
+}
+
+/// The chat sessions of each user on this node and the ones that reported
+/// themselves idle. Every node stores its counts in `presence_sessions`, a
+/// user is away when every one of their sessions is, online as soon as one
+/// of them is active on any node. Legacy friend sockets share the hub but
+/// don't count, since they never report idleness.
+#[derive(Default)]
+pub struct PresenceTracker {
+    sessions: Mutex<HashMap<String, HashSet<SessionId>>>,
+    away: Mutex<HashMap<String, HashSet<SessionId>>>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// How often a node checks in, and how long the others wait before they drop
+/// the sessions of one that stopped doing so.
+const CHECK_IN_INTERVAL: Duration = Duration::from_secs(10);
+const NODE_TIMEOUT_SECS: f64 = 30.0;
+
+/// Open chat sessions of a user and how many of them are idle, on one node or
+/// summed over all of them.
+#[derive(Debug, Default, FromRow)]
+struct SessionCount {
+    sessions: i64,
+    away: i64,
+}
+
+impl SessionCount {
+    fn status(&self) -> Status {
+        if self.sessions == 0 {
+            Status::Offline
+        } else if self.away >= self.sessions {
+            Status::Away
+        } else {
+            Status::Online
+        }
+    }
+}
+
+fn node_id(state: &AppState) -> i64 {
+    state.hub.node_id() as i64
+}
+
+/// Registers this node before it accepts sessions, then keeps checking in and
+/// drops the sessions of the nodes that went away.
+pub async fn start(state: Arc<AppState>) -> Result<(), sqlx::Error> {
+    check_in(&state).await?;
+    actix_rt::spawn(async move {
+        let mut interval = time::interval(CHECK_IN_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = check_in(&state).await {
+                eprintln!("Error checking in node: {}", e);
+            }
+            if let Err(e) = reap_nodes(&state).await {
+                eprintln!("Error dropping stopped nodes: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn check_in(state: &AppState) -> Result<(), sqlx::Error> {
+    let updated = sqlx::query("UPDATE nodes SET seen_at = CURRENT_TIMESTAMP WHERE id = $1")
+        .bind(node_id(state))
+        .execute(&state.db_pool)
+        .await?
+        .rows_affected();
+    if updated > 0 {
+        return Ok(());
+    }
+
+    sqlx::query("INSERT INTO nodes (id) VALUES ($1) ON CONFLICT (id) DO NOTHING")
+        .bind(node_id(state))
+        .execute(&state.db_pool)
+        .await?;
+    // the others took this node for dead and dropped its sessions
+    let usernames: Vec<String> = state
+        .presence
+        .sessions
+        .lock()
+        .await
+        .keys()
+        .cloned()
+        .collect();
+    for username in usernames {
+        sync(state, &username).await;
+    }
+    Ok(())
+}
+
+async fn reap_nodes(state: &AppState) -> Result<(), sqlx::Error> {
+    let mut usernames = sqlx::query_scalar::<_, String>(
+        r#"
+        WITH stopped AS (
+            DELETE FROM nodes
+            WHERE id <> $1 AND seen_at < CURRENT_TIMESTAMP - make_interval(secs => $2)
+            RETURNING id
+        )
+        DELETE FROM presence_sessions
+        WHERE node_id IN (SELECT id FROM stopped)
+        RETURNING username
+        "#,
+    )
+    .bind(node_id(state))
+    .bind(NODE_TIMEOUT_SECS)
+    .fetch_all(&state.db_pool)
+    .await?;
+    usernames.sort();
+    usernames.dedup();
+
+    for username in usernames {
+        let mut conn = state.db_pool.acquire().await?;
+        let status = cluster_count(&mut conn, &username).await?.status();
+        announce(state, &username, status, status == Status::Offline).await;
+    }
+    Ok(())
+}
+
+/// Registers a chat session and tells the user's friends when they come online.
+pub async fn connect(
+    state: &AppState,
+    username: &str,
+    login: i32,
+    chats: &[i32],
+) -> (SessionId, mpsc::UnboundedReceiver<Event>) {
+    let (session_id, rx) = state.hub.connect(username, login, chats).await;
+    state
+        .presence
+        .sessions
+        .lock()
+        .await
+        .entry(username.to_string())
+        .or_default()
+        .insert(session_id);
+    sync(state, username).await;
+    (session_id, rx)
+}
+
+pub async fn disconnect(state: &AppState, username: &str, session_id: SessionId) {
+    state.hub.disconnect(username, session_id).await;
+    {
+        let mut sessions = state.presence.sessions.lock().await;
+        if let Some(open) = sessions.get_mut(username) {
+            open.remove(&session_id);
+            if open.is_empty() {
+                sessions.remove(username);
+            }
+        }
+        let mut away = state.presence.away.lock().await;
+        if let Some(idle) = away.get_mut(username) {
+            idle.remove(&session_id);
+            if idle.is_empty() {
+                away.remove(username);
+            }
+        }
+    }
+    sync(state, username).await;
+}
+
+/// Closes every socket of `username`, used when their logins are revoked.
+pub async fn disconnect_all(state: &AppState, username: &str) {
+    state.hub.disconnect_user(username).await;
+    state.presence.sessions.lock().await.remove(username);
+    state.presence.away.lock().await.remove(username);
+    sync(state, username).await;
+}
+
+/// Handles the `set_status` action, only `online` and `away` can be set since
+/// `offline` simply means no session is open.
+pub async fn set_status(
+    state: &AppState,
+    username: &str,
+    session_id: SessionId,
+    status: Status,
+) -> Result<(), String> {
+    {
+        let mut away = state.presence.away.lock().await;
+        match status {
+            Status::Away => {
+                away.entry(username.to_string())
+                    .or_default()
+                    .insert(session_id);
+            }
+            Status::Online => {
+                if let Some(idle) = away.get_mut(username) {
+                    idle.remove(&session_id);
+                }
+            }
+            Status::Offline => return Err("Status must be online or away".to_string()),
+        }
+    }
+    sync(state, username).await;
+    Ok(())
+}
+
+/// Stores this node's count of `username`'s sessions and tells their friends
+/// when that changed their status across the nodes.
+async fn sync(state: &AppState, username: &str) {
+    match store_count(state, username).await {
+        Ok((before, status)) if before != status => {
+            let offline = before == Status::Offline || status == Status::Offline;
+            announce(state, username, status, offline).await;
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Error storing presence: {}", e),
+    }
+}
+
+/// The user's status before and after this node's count was stored.
+async fn store_count(state: &AppState, username: &str) -> Result<(Status, Status), sqlx::Error> {
+    let mut tx = state.db_pool.begin().await?;
+    // every node changing the user's count waits here, so each of them sees
+    // the status the previous one left
+    sqlx::query("SELECT 1 FROM users WHERE username = $1 FOR UPDATE")
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+    let before = cluster_count(&mut tx, username).await?.status();
+
+    let local = local_count(state, username).await;
+    if local.sessions == 0 {
+        sqlx::query("DELETE FROM presence_sessions WHERE node_id = $1 AND username = $2")
+            .bind(node_id(state))
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query(
+            r#"
+            INSERT INTO presence_sessions (node_id, username, sessions, away)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (node_id, username)
+            DO UPDATE SET sessions = EXCLUDED.sessions, away = EXCLUDED.away
+            "#,
+        )
+        .bind(node_id(state))
+        .bind(username)
+        .bind(local.sessions as i32)
+        .bind(local.away as i32)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let status = cluster_count(&mut tx, username).await?.status();
+    tx.commit().await?;
+    Ok((before, status))
+}
+
+async fn local_count(state: &AppState, username: &str) -> SessionCount {
+    let sessions = state.presence.sessions.lock().await;
+    let Some(open) = sessions.get(username) else {
+        return SessionCount::default();
+    };
+    let away = state.presence.away.lock().await;
+    let idle = away.get(username).map_or(0, |idle| {
+        idle.iter().filter(|session| open.contains(session)).count()
+    });
+    SessionCount {
+        sessions: open.len() as i64,
+        away: idle as i64,
+    }
+}
+
+async fn cluster_count(
+    conn: &mut PgConnection,
+    username: &str,
+) -> Result<SessionCount, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT COALESCE(SUM(sessions), 0)::BIGINT AS sessions, COALESCE(SUM(away), 0)::BIGINT AS away
+        FROM presence_sessions
+        WHERE username = $1
+        "#,
+    )
+    .bind(username)
+    .fetch_one(conn)
+    .await
+}
+
+async fn announce(state: &AppState, username: &str, status: Status, touch: bool) {
+    let mut last_seen = None;
+    if touch {
+        match touch_last_seen(&state.db_pool, username).await {
+            Ok(seen) => last_seen = seen,
+            Err(e) => eprintln!("Error updating last seen: {}", e),
+        }
+    }
+
+    state
+        .publish(OutgoingMessage::Presence(Presence {
+            username: username.to_string(),
+            status,
+            last_seen,
+        }))
+        .await;
+}
+
+async fn touch_last_seen(
+    pool: &PgPool,
+    username: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar("UPDATE users SET last_seen = $1 WHERE username = $2 RETURNING last_seen")
+        .bind(Utc::now())
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .map(Option::flatten)
+}
+
+pub async fn friends_of(pool: &PgPool, username: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT CASE WHEN sender_username = $1 THEN receiver_username ELSE sender_username END
+        FROM friends
+        WHERE status = 'accepted' AND (sender_username = $1 OR receiver_username = $1)
+        "#,
+    )
+    .bind(username)
+    .fetch_all(pool)
+    .await
+}
+
+/// `GET /presence?usernames=alice,bob`, only the caller and their friends are
+/// reported, other names are left out of the response.
+#[get("/presence")]
+pub async fn get_presence(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<PresenceQuery>,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let username = claims.email.clone();
+
+    let usernames: Vec<String> = query
+        .usernames
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    if usernames.len() > MAX_PRESENCE_QUERY {
+        return Ok(HttpResponse::BadRequest().json(format!(
+            "At most {} usernames can be queried at once",
+            MAX_PRESENCE_QUERY
+        )));
+    }
+
+    let rows = match sqlx::query_as::<_, LastSeen>(
+        r#"
+        SELECT u.username, u.last_seen,
+            COALESCE(SUM(p.sessions), 0)::BIGINT AS sessions,
+            COALESCE(SUM(p.away), 0)::BIGINT AS away
+        FROM users u
+        LEFT JOIN presence_sessions p ON p.username = u.username
+        WHERE u.username = ANY($1)
+            AND (u.username = $2 OR EXISTS(
+                SELECT 1 FROM friends f
+                WHERE f.status = 'accepted'
+                    AND ((f.sender_username = $2 AND f.receiver_username = u.username)
+                        OR (f.receiver_username = $2 AND f.sender_username = u.username))
+            ))
+        GROUP BY u.username, u.last_seen
+        "#,
+    )
+    .bind(&usernames)
+    .bind(&username)
+    .fetch_all(&state.db_pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Error fetching presence: {}", e);
+            return Ok(HttpResponse::InternalServerError().json("Error fetching presence"));
+        }
+    };
+
+    let presence: Vec<Presence> = rows
+        .into_iter()
+        .map(|row| Presence {
+            status: row.count.status(),
+            username: row.username,
+            last_seen: row.last_seen,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(presence))
+}
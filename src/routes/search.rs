@@ -0,0 +1,118 @@
+use crate::middlewares::verify_token;
+use crate::routes::chat::AppState;
+use actix_web::{Error, HttpRequest, HttpResponse, get, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+pub const MAX_SEARCH_QUERY_LEN: usize = 200;
+pub const DEFAULT_SEARCH_LIMIT: i64 = 20;
+pub const MAX_SEARCH_LIMIT: i64 = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub chat_id: Option<i32>,
+    pub author: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub before: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct SearchHit {
+    pub id: i32,
+    pub chat_id: i32,
+    pub username: String,
+    pub time: DateTime<Utc>,
+    /// HTML escaped excerpt of the message with the matches in `<mark>`.
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub results: Vec<SearchHit>,
+    pub has_more: bool,
+}
+
+/// Newest first, `before` is the id of the last hit of the previous page.
+#[get("/search")]
+pub async fn search_messages(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let username = claims.email.clone();
+
+    let q = query.q.trim();
+    if q.is_empty() || q.chars().count() > MAX_SEARCH_QUERY_LEN {
+        return Ok(HttpResponse::BadRequest().json(format!(
+            "q must be between 1 and {} characters",
+            MAX_SEARCH_QUERY_LEN
+        )));
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    // only chats the caller is a member of, the message is escaped before
+    // ts_headline adds its markup so the snippet is safe to render
+    let result = sqlx::query_as::<_, SearchHit>(
+        r#"
+        SELECT m.id, m.chat_id, m.username, m.time,
+            ts_headline(
+                'simple',
+                replace(replace(replace(m.message, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+                tsq,
+                'StartSel=<mark>, StopSel=</mark>, MaxWords=30, MinWords=10, MaxFragments=2'
+            ) AS snippet
+        FROM messages m
+        JOIN chat_members cm ON cm.chat_id = m.chat_id AND cm.username = $1
+        CROSS JOIN websearch_to_tsquery('simple', $2) AS tsq
+        WHERE m.search_vector @@ tsq
+            AND ($3::INTEGER IS NULL OR m.chat_id = $3)
+            AND ($4::TEXT IS NULL OR m.username = $4)
+            AND ($5::TIMESTAMPTZ IS NULL OR m.time >= $5)
+            AND ($6::TIMESTAMPTZ IS NULL OR m.time < $6)
+            AND ($7::INTEGER IS NULL OR m.id < $7)
+        ORDER BY m.id DESC
+        LIMIT $8
+        "#,
+    )
+    .bind(&username)
+    .bind(q)
+    .bind(query.chat_id)
+    .bind(&query.author)
+    .bind(query.from)
+    .bind(query.to)
+    .bind(query.before)
+    .bind(limit + 1)
+    .fetch_all(&state.db_pool)
+    .await;
+
+    match result {
+        Ok(mut results) => {
+            let has_more = results.len() > limit as usize;
+            results.truncate(limit as usize);
+            Ok(HttpResponse::Ok().json(SearchPage { results, has_more }))
+        }
+        Err(e) => {
+            eprintln!("Error searching messages: {}", e);
+            Ok(HttpResponse::InternalServerError().json("Error searching messages"))
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use crate::config;
+use crate::middlewares::{generate_token, verify_token};
+use crate::routes::chat::AppState;
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    cookie::{Cookie, SameSite},
+    delete, get,
+    http::header,
+    post, web,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use std::sync::Arc;
+use time::Duration;
+
+pub const REFRESH_COOKIE: &str = "refresh_token";
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct SessionInfo {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    #[sqlx(skip)]
+    pub current: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct RefreshedSession {
+    id: i32,
+    username: String,
+    email: String,
+}
+
+fn build_cookie(name: &'static str, value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .http_only(true)
+        .max_age(max_age)
+        .finish()
+}
+
+/// Cookies clearing both tokens of the browser.
+pub fn expired_cookies() -> [Cookie<'static>; 2] {
+    [
+        build_cookie("token", String::new(), Duration::seconds(0)),
+        build_cookie(REFRESH_COOKIE, String::new(), Duration::seconds(0)),
+    ]
+}
+
+/// Refresh tokens are random and only their hash is stored, a leaked
+/// sessions table can't be used to log in.
+fn hash_refresh_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn new_refresh_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+fn session_cookies(
+    session_id: i32,
+    username: String,
+    email: String,
+    refresh_token: String,
+) -> [Cookie<'static>; 2] {
+    // Claims keep their historical layout, `sub` is the email
+    let access_token = generate_token(email, username, session_id);
+    let auth = &config::get().auth;
+    [
+        build_cookie("token", access_token, auth.access_token_ttl()),
+        build_cookie(REFRESH_COOKIE, refresh_token, auth.refresh_token_ttl()),
+    ]
+}
+
+/// Opens a session for a successful login and returns its access and
+/// refresh token cookies.
+pub async fn start_session(
+    pool: &PgPool,
+    req: &HttpRequest,
+    username: &str,
+    email: &str,
+) -> Result<[Cookie<'static>; 2], sqlx::Error> {
+    let refresh_token = new_refresh_token();
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(255).collect::<String>());
+    let ip = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(str::to_string);
+
+    let session_id = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO sessions (username, refresh_token_hash, user_agent, ip, expires_at) VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5)) RETURNING id",
+    )
+    .bind(username)
+    .bind(hash_refresh_token(&refresh_token))
+    .bind(user_agent)
+    .bind(ip)
+    .bind(config::get().auth.refresh_token_ttl().as_seconds_f64())
+    .fetch_one(pool)
+    .await?;
+
+    Ok(session_cookies(
+        session_id,
+        username.to_string(),
+        email.to_string(),
+        refresh_token,
+    ))
+}
+
+/// Revokes the session a refresh token belongs to, returning its id and
+/// user, used by `logout`.
+pub async fn revoke_by_refresh_token(
+    pool: &PgPool,
+    refresh_token: &str,
+) -> Result<Option<(i32, String)>, sqlx::Error> {
+    sqlx::query_as::<_, (i32, String)>(
+        "UPDATE sessions SET revoked_at = NOW() WHERE refresh_token_hash = $1 AND revoked_at IS NULL RETURNING id, username",
+    )
+    .bind(hash_refresh_token(refresh_token))
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn revoke_all(pool: &PgPool, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE username = $1 AND revoked_at IS NULL",
+    )
+    .bind(username)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Trades a refresh token for a new access token, rotating the refresh token
+/// on the way. Presenting an already rotated token revokes the session since
+/// one of the two parties using it isn't the user.
+#[post("/refresh")]
+pub async fn refresh(
+    pool: web::Data<PgPool>,
+    chat_state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+) -> impl Responder {
+    let presented = match req.cookie(REFRESH_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated",
+            }));
+        }
+    };
+
+    let presented_hash = hash_refresh_token(&presented);
+    let refresh_token = new_refresh_token();
+
+    let refreshed = match sqlx::query_as::<_, RefreshedSession>(
+        r#"
+        WITH rotated AS (
+            UPDATE sessions
+            SET refresh_token_hash = $2,
+                previous_refresh_token_hash = $1,
+                last_used_at = NOW(),
+                expires_at = NOW() + make_interval(secs => $3)
+            WHERE refresh_token_hash = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING id, username
+        )
+        SELECT rotated.id, rotated.username, users.email
+        FROM rotated JOIN users ON users.username = rotated.username
+        "#,
+    )
+    .bind(&presented_hash)
+    .bind(hash_refresh_token(&refresh_token))
+    .bind(config::get().auth.refresh_token_ttl().as_seconds_f64())
+    .fetch_optional(pool.get_ref())
+    .await
+    {
+        Ok(refreshed) => refreshed,
+        Err(e) => {
+            eprintln!("Error refreshing session: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to refresh session",
+            }));
+        }
+    };
+
+    let Some(session) = refreshed else {
+        match sqlx::query_as::<_, (i32, String)>(
+            "UPDATE sessions SET revoked_at = NOW() WHERE previous_refresh_token_hash = $1 AND revoked_at IS NULL RETURNING id, username",
+        )
+        .bind(&presented_hash)
+        .fetch_optional(pool.get_ref())
+        .await
+        {
+            Ok(Some((id, username))) => {
+                eprintln!("Refresh token reuse detected, session revoked");
+                chat_state.hub.disconnect_login(&username, id).await;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Error revoking session: {}", e),
+        }
+
+        let mut response = HttpResponse::Unauthorized();
+        for cookie in expired_cookies() {
+            response.cookie(cookie);
+        }
+        return response.json(json!({
+            "status": "error",
+            "message": "invalid refresh token",
+        }));
+    };
+
+    let mut response = HttpResponse::Ok();
+    for cookie in session_cookies(session.id, session.username, session.email, refresh_token) {
+        response.cookie(cookie);
+    }
+    response.json(json!({
+        "status": "success",
+        "message": "session refreshed",
+    }))
+}
+
+#[get("/sessions")]
+pub async fn get_sessions(pool: web::Data<PgPool>, req: HttpRequest) -> impl Responder {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_token(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    match sqlx::query_as::<_, SessionInfo>(
+        "SELECT id, user_agent, ip, created_at, last_used_at FROM sessions WHERE username = $1 AND revoked_at IS NULL AND expires_at > NOW() ORDER BY last_used_at DESC",
+    )
+    .bind(&claims.email)
+    .fetch_all(pool.get_ref())
+    .await
+    {
+        Ok(mut sessions) => {
+            for session in &mut sessions {
+                session.current = session.id == claims.sid;
+            }
+            HttpResponse::Ok().json(sessions)
+        }
+        Err(e) => {
+            eprintln!("Error fetching sessions: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to fetch sessions"
+            }))
+        }
+    }
+}
+
+#[delete("/sessions/{session_id}")]
+pub async fn revoke_session(
+    pool: web::Data<PgPool>,
+    chat_state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+) -> impl Responder {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_token(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let session_id = path.into_inner();
+
+    match sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND username = $2 AND revoked_at IS NULL",
+    )
+    .bind(session_id)
+    .bind(&claims.email)
+    .execute(pool.get_ref())
+    .await
+    {
+        Ok(result) if result.rows_affected() == 0 => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "session not found"
+        })),
+        Ok(_) => {
+            // its sockets were authenticated at the handshake only
+            chat_state.hub.disconnect_login(&claims.email, session_id).await;
+
+            let mut response = HttpResponse::Ok();
+            if session_id == claims.sid {
+                for cookie in expired_cookies() {
+                    response.cookie(cookie);
+                }
+            }
+            response.json(json!({
+                "status": "success",
+                "message": "session revoked"
+            }))
+        }
+        Err(e) => {
+            eprintln!("Error revoking session: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to revoke session"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_refresh_token_hash_is_stored() {
+        let token = new_refresh_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, new_refresh_token());
+
+        let hash = hash_refresh_token(&token);
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, token);
+        assert_eq!(hash, hash_refresh_token(&token));
+    }
+}
@@ -0,0 +1,350 @@
+//! `/socket`, one connection carrying every kind of realtime event.
+//!
+//! Actions are namespaced, `chat.new_message`, `friend.accept`,
+//! `presence.set_status`, `profile.change_bio`, and so are the events sent
+//! back. The legacy `/ws` and `/ws/friend_req` sockets share the same hub
+//! and only see their own namespaces, with bare action names.
+
+use crate::config;
+use crate::heartbeat::Heartbeat;
+use crate::hub::HubEvent;
+use crate::middlewares::verify_token;
+use crate::protocol::{self, ErrorCode, Reply};
+use crate::rate_limit::RateLimits;
+use crate::routes::chat::{AppState, ChatConnection, ChatEvent, ChatRequest, OutgoingMessage};
+use crate::routes::friend::{self, FriendAction, FriendAppState, IncomingFriendAction};
+use actix_web::{Error, HttpRequest, HttpResponse, get, web};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    Chat,
+    Friend,
+    Presence,
+    Profile,
+}
+
+impl Namespace {
+    pub const ALL: [Namespace; 4] = [
+        Namespace::Chat,
+        Namespace::Friend,
+        Namespace::Presence,
+        Namespace::Profile,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Namespace::Chat => "chat",
+            Namespace::Friend => "friend",
+            Namespace::Presence => "presence",
+            Namespace::Profile => "profile",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|namespace| namespace.as_str() == name)
+    }
+}
+
+/// What the hub routes to sockets, and the bus carries between nodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Chat(ChatEvent),
+    Friend(FriendAction),
+    /// Live events may have been lost, the client has to refetch.
+    Resync,
+}
+
+impl Event {
+    pub fn namespace(&self) -> Namespace {
+        match self {
+            Event::Chat(event) => match event.event {
+                OutgoingMessage::Presence(_) => Namespace::Presence,
+                OutgoingMessage::ChangeBio(_) => Namespace::Profile,
+                _ => Namespace::Chat,
+            },
+            Event::Friend(_) => Namespace::Friend,
+            Event::Resync => Namespace::Chat,
+        }
+    }
+
+    /// The text frame sent for this event, with a namespaced action on
+    /// `/socket`.
+    pub fn frame(&self, namespaced: bool) -> String {
+        let frame = match self {
+            Event::Chat(event) => serde_json::to_value(event),
+            Event::Friend(action) => serde_json::to_value(action),
+            // not namespaced, like the answers to `resume`
+            Event::Resync => return json!({"action": "resync"}).to_string(),
+        };
+        let mut frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("Error serializing event: {}", e);
+                return String::new();
+            }
+        };
+        if namespaced {
+            namespace_action(&mut frame, self.namespace());
+        }
+        frame.to_string()
+    }
+}
+
+impl From<ChatEvent> for Event {
+    fn from(event: ChatEvent) -> Self {
+        Event::Chat(event)
+    }
+}
+
+impl From<OutgoingMessage> for Event {
+    fn from(event: OutgoingMessage) -> Self {
+        Event::Chat(event.into())
+    }
+}
+
+impl From<FriendAction> for Event {
+    fn from(action: FriendAction) -> Self {
+        Event::Friend(action)
+    }
+}
+
+/// Only chat events are logged, friend actions go out as they are.
+impl HubEvent for Event {
+    fn with_seq(&self, seq: i64) -> Self {
+        match self {
+            Event::Chat(event) => Event::Chat(ChatEvent {
+                seq: Some(seq),
+                event: event.event.clone(),
+            }),
+            Event::Friend(_) | Event::Resync => self.clone(),
+        }
+    }
+
+    fn resync() -> Self {
+        Event::Resync
+    }
+}
+
+/// Prefixes the `action` of `frame` with `namespace`. An action named after
+/// its namespace, the `presence` event, becomes `<namespace>.update`.
+pub fn namespace_action(frame: &mut Value, namespace: Namespace) {
+    let Some(action) = frame.get("action").and_then(Value::as_str) else {
+        return;
+    };
+    let action = if action == namespace.as_str() {
+        "update"
+    } else {
+        action
+    };
+    frame["action"] = json!(format!("{}.{}", namespace.as_str(), action));
+}
+
+/// Which namespace an incoming chat action belongs to on `/socket`.
+fn chat_request_namespace(request: &ChatRequest) -> Namespace {
+    match request {
+        ChatRequest::SetStatus(_) => Namespace::Presence,
+        ChatRequest::ChangeBio(_) => Namespace::Profile,
+        _ => Namespace::Chat,
+    }
+}
+
+/// Everything a client can ask of `/socket`, framed by `protocol::parse`.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "Tagged")]
+pub enum SocketRequest {
+    Chat(ChatRequest),
+    Friend(IncomingFriendAction),
+}
+
+#[derive(Deserialize)]
+struct Tagged {
+    action: String,
+    #[serde(default)]
+    payload: Value,
+}
+
+impl TryFrom<Tagged> for SocketRequest {
+    type Error = String;
+
+    fn try_from(tagged: Tagged) -> Result<Self, Self::Error> {
+        let (namespace, action) = tagged
+            .action
+            .split_once('.')
+            .and_then(|(namespace, action)| Some((Namespace::parse(namespace)?, action)))
+            .ok_or_else(|| format!("unknown action {}", tagged.action))?;
+        let request = json!({"action": action, "payload": tagged.payload});
+
+        if namespace == Namespace::Friend {
+            return serde_json::from_value(request)
+                .map(SocketRequest::Friend)
+                .map_err(|e| e.to_string());
+        }
+        let request = serde_json::from_value(request).map_err(|e| e.to_string())?;
+        if chat_request_namespace(&request) != namespace {
+            return Err(format!("unknown action {}", tagged.action));
+        }
+        Ok(SocketRequest::Chat(request))
+    }
+}
+
+#[get("/socket")]
+pub async fn ws_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    chat_state: web::Data<Arc<AppState>>,
+    friend_state: web::Data<Arc<FriendAppState>>,
+    rate_limits: web::Data<RateLimits>,
+) -> Result<HttpResponse, Error> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let claims = match verify_token(&chat_state.db_pool, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let email = claims.sub.clone();
+    let username = claims.email.clone();
+
+    // there are no legacy clients of this socket
+    let version = match protocol::negotiate(&req) {
+        Ok(protocol::LEGACY) => {
+            return Ok(HttpResponse::BadRequest().json(format!(
+                "/socket needs a protocol version, e.g. kutter.v{}",
+                protocol::CURRENT
+            )));
+        }
+        Ok(version) => version,
+        Err(response) => return Ok(response),
+    };
+
+    let (mut response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    protocol::accept(&mut response, version);
+    let mut heartbeat = Heartbeat::new(session.clone(), &config::get().websocket, claims.exp);
+
+    let (connection, outbox) = ChatConnection::open(
+        chat_state.get_ref().clone(),
+        username.clone(),
+        email,
+        claims.sid,
+    )
+    .await;
+    let message_session = session.clone();
+
+    actix_rt::spawn(async move {
+        while let Some(text) = heartbeat.next_text(&mut msg_stream).await {
+            let request = match protocol::parse::<SocketRequest>(&text, version) {
+                Ok(request) => request,
+                Err(rejection) => {
+                    Reply::reject(message_session.clone(), version, rejection).await;
+                    continue;
+                }
+            };
+            let mut reply = Reply::new(message_session.clone(), version, request.id);
+            if rate_limits
+                .check_ws_action(&username, &request.name)
+                .is_err()
+            {
+                reply
+                    .fail(
+                        ErrorCode::RateLimited,
+                        "You're doing that too fast, slow down",
+                    )
+                    .await;
+                continue;
+            }
+            match request.action {
+                SocketRequest::Chat(request) => {
+                    connection.handle(request, &mut reply).await;
+                }
+                SocketRequest::Friend(action) => {
+                    friend::handle(&friend_state, &username, action, &mut reply).await;
+                }
+            }
+            reply.ack().await;
+        }
+
+        connection.close().await;
+    });
+
+    actix_rt::spawn(outbox.run(session, true));
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::chat::DeleteMessageRequest;
+    use crate::routes::presence::{Presence, Status};
+
+    #[test]
+    fn actions_are_routed_by_namespace() {
+        let request = protocol::parse::<SocketRequest>(
+            r#"{"id": 1, "action": "chat.delete_message", "payload": {"id": 3}}"#,
+            1,
+        )
+        .unwrap();
+        assert_eq!(request.name, "chat.delete_message");
+        assert!(matches!(
+            request.action,
+            SocketRequest::Chat(ChatRequest::DeleteMessage(DeleteMessageRequest { id: 3 }))
+        ));
+
+        let request = protocol::parse::<SocketRequest>(
+            r#"{"id": 2, "action": "presence.set_status", "payload": {"status": "away"}}"#,
+            1,
+        )
+        .unwrap();
+        assert!(matches!(
+            request.action,
+            SocketRequest::Chat(ChatRequest::SetStatus(_))
+        ));
+
+        let request = protocol::parse::<SocketRequest>(
+            r#"{"id": 3, "action": "friend.unblock", "payload": {"username": "bob"}}"#,
+            1,
+        )
+        .unwrap();
+        assert!(matches!(
+            request.action,
+            SocketRequest::Friend(IncomingFriendAction::Unblock(_))
+        ));
+
+        // right action, wrong namespace
+        for action in ["chat.set_status", "delete_message", "group.new_chat"] {
+            let text = json!({"id": 4, "action": action, "payload": {"status": "away"}});
+            assert!(protocol::parse::<SocketRequest>(&text.to_string(), 1).is_err());
+        }
+    }
+
+    #[test]
+    fn events_are_framed_per_socket() {
+        let event = Event::from(OutgoingMessage::Delete {
+            message_id: 10,
+            chat_id: 1,
+        });
+        assert_eq!(event.namespace(), Namespace::Chat);
+        let frame: Value = serde_json::from_str(&event.frame(true)).unwrap();
+        assert_eq!(frame["action"], "chat.delete");
+        let frame: Value = serde_json::from_str(&event.frame(false)).unwrap();
+        assert_eq!(frame["action"], "delete");
+
+        let event = Event::from(OutgoingMessage::Presence(Presence {
+            username: "alice".to_string(),
+            status: Status::Away,
+            last_seen: None,
+        }));
+        assert_eq!(event.namespace(), Namespace::Presence);
+        let frame: Value = serde_json::from_str(&event.frame(true)).unwrap();
+        assert_eq!(frame["action"], "presence.update");
+    }
+}
@@ -0,0 +1,534 @@
+use crate::middlewares::verify_token;
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "Kutter";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+/// Codes from one step before or after the current one are accepted, phones
+/// are rarely more than a few seconds off.
+const TOTP_SKEW_STEPS: u64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+// no 0/o, 1/l/i, so codes survive being copied by hand
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    pub code: String,
+}
+
+#[derive(Debug, FromRow)]
+struct TotpUser {
+    email: String,
+    totp_secret: Option<String>,
+    totp_enabled: bool,
+}
+
+fn totp(secret: &str, email: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    // skew is handled by `matching_step` so the accepted step is known
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(ISSUER.to_string()),
+        email.to_string(),
+    )
+    .ok()
+}
+
+/// The time step `code` was generated for, if it is within the skew window.
+fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<u64> {
+    let current = now / TOTP_STEP;
+    (current.saturating_sub(TOTP_SKEW_STEPS)..=current + TOTP_SKEW_STEPS)
+        .find(|step| totp.check(code, step * TOTP_STEP))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalize_code(code).as_bytes()))
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::rng();
+    let code: String = (0..RECOVERY_CODE_LEN)
+        .map(|_| RECOVERY_CODE_ALPHABET[rng.random_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!(
+        "{}-{}",
+        &code[..RECOVERY_CODE_LEN / 2],
+        &code[RECOVERY_CODE_LEN / 2..]
+    )
+}
+
+async fn fetch_totp_user(pool: &PgPool, username: &str) -> Result<Option<TotpUser>, sqlx::Error> {
+    sqlx::query_as::<_, TotpUser>(
+        "SELECT email, totp_secret, totp_enabled FROM users WHERE username = $1",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Checks a TOTP code against `secret` and remembers its step, so the same
+/// code can't be replayed while it is still valid.
+async fn check_totp(
+    pool: &PgPool,
+    username: &str,
+    email: &str,
+    secret: &str,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let Some(step) = totp(secret, email).and_then(|totp| matching_step(&totp, code, now())) else {
+        return Ok(false);
+    };
+
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = $1 WHERE username = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+    )
+    .bind(step as i64)
+    .bind(username)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn use_recovery_code(pool: &PgPool, username: &str, code: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE recovery_codes SET used_at = NOW() WHERE id = (SELECT id FROM recovery_codes WHERE username = $1 AND code_hash = $2 AND used_at IS NULL LIMIT 1)",
+    )
+    .bind(username)
+    .bind(hash_recovery_code(code))
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Whether `code` is a current TOTP code or an unused recovery code of
+/// `username`, who must have two-factor enabled. Recovery codes are spent.
+pub async fn verify_second_factor(
+    pool: &PgPool,
+    username: &str,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let user = match fetch_totp_user(pool, username).await? {
+        Some(user) if user.totp_enabled => user,
+        _ => return Ok(false),
+    };
+    let Some(secret) = user.totp_secret else {
+        return Ok(false);
+    };
+
+    let code = code.trim();
+    if code.len() == TOTP_DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+        check_totp(pool, username, &user.email, &secret, code).await
+    } else {
+        use_recovery_code(pool, username, code).await
+    }
+}
+
+/// Replaces every recovery code of `username`, the plain codes are only
+/// ever returned here.
+async fn replace_recovery_codes(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query("DELETE FROM recovery_codes WHERE username = $1")
+        .bind(username)
+        .execute(&mut **tx)
+        .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    sqlx::query(
+        "INSERT INTO recovery_codes (username, code_hash) SELECT $1, UNNEST($2::CHAR(64)[])",
+    )
+    .bind(username)
+    .bind(&hashes)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(codes)
+}
+
+/// Starts enrolment: stores a fresh secret and returns it with its
+/// provisioning URI for the authenticator app. Two-factor stays off until
+/// `/2fa/enable` confirms a code.
+#[post("/2fa/setup")]
+pub async fn setup(pool: web::Data<PgPool>, req: HttpRequest) -> impl Responder {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_token(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let secret = Secret::Raw(rand::random::<[u8; 20]>().to_vec())
+        .to_encoded()
+        .to_string();
+    let Some(totp) = totp(&secret, &claims.sub) else {
+        return HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": "failed to create secret"
+        }));
+    };
+
+    match sqlx::query(
+        "UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE username = $2 AND totp_enabled = false",
+    )
+    .bind(&secret)
+    .bind(&claims.email)
+    .execute(pool.get_ref())
+    .await
+    {
+        Ok(result) if result.rows_affected() == 0 => HttpResponse::Conflict().json(json!({
+            "status": "error",
+            "message": "two-factor authentication is already enabled"
+        })),
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "secret": secret,
+            "uri": totp.get_url(),
+        })),
+        Err(e) => {
+            eprintln!("Error storing totp secret: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to create secret"
+            }))
+        }
+    }
+}
+
+/// Finishes enrolment with a code from the authenticator and hands out the
+/// recovery codes.
+#[post("/2fa/enable")]
+pub async fn enable(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    form: web::Json<CodeForm>,
+) -> impl Responder {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_token(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let username = claims.email.clone();
+
+    let secret = match fetch_totp_user(pool.get_ref(), &username).await {
+        Ok(Some(TotpUser {
+            totp_enabled: true, ..
+        })) => {
+            return HttpResponse::Conflict().json(json!({
+                "status": "error",
+                "message": "two-factor authentication is already enabled"
+            }));
+        }
+        Ok(Some(TotpUser {
+            totp_secret: Some(secret),
+            ..
+        })) => secret,
+        Ok(_) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "two-factor setup was not started"
+            }));
+        }
+        Err(e) => {
+            eprintln!("Error fetching user: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to enable two-factor authentication"
+            }));
+        }
+    };
+
+    match check_totp(
+        pool.get_ref(),
+        &username,
+        &claims.sub,
+        &secret,
+        form.code.trim(),
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "invalid code"
+            }));
+        }
+        Err(e) => {
+            eprintln!("Error checking totp code: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to enable two-factor authentication"
+            }));
+        }
+    }
+
+    let enabled = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE users SET totp_enabled = true WHERE username = $1")
+            .bind(&username)
+            .execute(&mut *tx)
+            .await?;
+        let codes = replace_recovery_codes(&mut tx, &username).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(codes)
+    }
+    .await;
+
+    match enabled {
+        Ok(recovery_codes) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "message": "two-factor authentication enabled",
+            "recovery_codes": recovery_codes,
+        })),
+        Err(e) => {
+            eprintln!("Error enabling two-factor authentication: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to enable two-factor authentication"
+            }))
+        }
+    }
+}
+
+#[post("/2fa/disable")]
+pub async fn disable(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    form: web::Json<CodeForm>,
+) -> impl Responder {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_token(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let username = claims.email.clone();
+
+    match verify_second_factor(pool.get_ref(), &username, &form.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "invalid code"
+            }));
+        }
+        Err(e) => {
+            eprintln!("Error checking second factor: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to disable two-factor authentication"
+            }));
+        }
+    }
+
+    let disabled = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            "UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL WHERE username = $1",
+        )
+        .bind(&username)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE username = $1")
+            .bind(&username)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+    .await;
+
+    match disabled {
+        Ok(()) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "message": "two-factor authentication disabled"
+        })),
+        Err(e) => {
+            eprintln!("Error disabling two-factor authentication: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to disable two-factor authentication"
+            }))
+        }
+    }
+}
+
+/// New recovery codes for when the old ones ran out or leaked, the previous
+/// ones stop working.
+#[post("/2fa/recovery_codes")]
+pub async fn regenerate_recovery_codes(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    form: web::Json<CodeForm>,
+) -> impl Responder {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_token(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let username = claims.email.clone();
+
+    match verify_second_factor(pool.get_ref(), &username, &form.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "invalid code"
+            }));
+        }
+        Err(e) => {
+            eprintln!("Error checking second factor: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to create recovery codes"
+            }));
+        }
+    }
+
+    let replaced = async {
+        let mut tx = pool.begin().await?;
+        let codes = replace_recovery_codes(&mut tx, &username).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(codes)
+    }
+    .await;
+
+    match replaced {
+        Ok(recovery_codes) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "recovery_codes": recovery_codes,
+        })),
+        Err(e) => {
+            eprintln!("Error creating recovery codes: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to create recovery codes"
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    #[test]
+    fn codes_are_accepted_within_one_step_of_skew() {
+        let totp = totp(SECRET, "a@kutter.dev").unwrap();
+        let now = 1_700_000_000;
+        let step = now / TOTP_STEP;
+
+        let current = totp.generate(now);
+        assert_eq!(matching_step(&totp, &current, now), Some(step));
+
+        let previous = totp.generate(now - TOTP_STEP);
+        assert_eq!(matching_step(&totp, &previous, now), Some(step - 1));
+
+        let stale = totp.generate(now - 2 * TOTP_STEP);
+        assert_eq!(matching_step(&totp, &stale, now), None);
+    }
+
+    #[test]
+    fn recovery_codes_match_however_they_are_typed() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+        assert_eq!(
+            hash_recovery_code(&code),
+            hash_recovery_code(&format!(" {} ", code.to_uppercase().replace('-', "")))
+        );
+        assert_ne!(
+            hash_recovery_code(&code),
+            hash_recovery_code(&generate_recovery_code())
+        );
+    }
+}
@@ -1,4 +1,10 @@
-import { createSuccessAlert, createErrorAlert } from "./index.js";
+import {
+  createSuccessAlert,
+  createErrorAlert,
+  authFetch,
+  refreshSession,
+  verifySession,
+} from "./index.js";
 import "https://cdnjs.cloudflare.com/ajax/libs/hammer.js/2.0.8/hammer.min.js";
 
 const DOM_ELEMENTS = {
@@ -30,6 +36,8 @@ const DOM_ELEMENTS = {
   inputContainer: document.getElementById("input_container"),
   sendMessageInput: document.getElementById("send_message_input"),
   sendMessageButton: document.getElementById("send_message_button"),
+  attachmentInput: document.getElementById("attachment_input"),
+  attachmentButton: document.getElementById("attachment_button"),
   changedMessageContainer: document.getElementById("changed_message_container"),
   chatsButton: document.getElementById("chats_button"),
   friendsButton: document.getElementById("friends_button"),
@@ -37,6 +45,9 @@ const DOM_ELEMENTS = {
   right_side_wrapper: document.getElementById("right_side_wrapper"),
 };
 
+// websocket protocol version, see src/protocol.rs
+const WS_PROTOCOL = "kutter.v1";
+
 const APP_STATE = {
   currentTab: null,
   currentReply: null,
@@ -44,6 +55,18 @@ const APP_STATE = {
   currentChatPartner: null,
   currentChatId: null,
   currentEdit: null,
+  oldestMessageId: null,
+  hasMoreMessages: false,
+  loadingMessages: false,
+  lastTypingSent: 0,
+  // highest event `seq` seen on the chat socket, sent back on reconnect
+  lastEventSeq: null,
+  // ids of requests sent over a socket that weren't acked yet
+  lastRequestId: 0,
+  pendingRequests: new Map(),
+  presence: new Map(),
+  reactions: new Map(),
+  pendingAttachments: [],
   hasToast: null,
   renderedChats: new Set(),
   renderedMessages: new Set(),
@@ -58,10 +81,8 @@ const APP_STATE = {
       isActive: null,
     },
   },
-  sockets: {
-    friendReq: null,
-    chat: null,
-  },
+  // the one `/socket` connection, see src/routes/socket.rs
+  socket: null,
 };
 
 const Utils = {
@@ -171,10 +192,30 @@ const Utils = {
     });
     return isToday ? `${time}` : `${fullDate} at ${time}`;
   },
+  // the server acks every request by its id, or answers with an error
+  send: (socket, message) => {
+    const id = ++APP_STATE.lastRequestId;
+    APP_STATE.pendingRequests.set(id, message.action);
+    socket.send(JSON.stringify({ id, ...message }));
+  },
+  // true when `data` answered one of our requests
+  handleReply: (data) => {
+    if (data.action === "ack") {
+      APP_STATE.pendingRequests.delete(data.id);
+      return true;
+    }
+    if (data.action === "error") {
+      APP_STATE.pendingRequests.delete(data.id);
+      Utils.verifyToast();
+      createErrorAlert(data.message);
+      return true;
+    }
+    return false;
+  },
   checkPfpExists: async (username) => {
     const pfpPath = `/uploads/${username}.png`;
     try {
-      const response = await fetch(pfpPath, { method: "HEAD" });
+      const response = await authFetch(pfpPath, { method: "HEAD" });
       return response.ok
         ? pfpPath
         : "/uploads/40237818034128031427800137284873941207891342780912374098.jpg";
@@ -186,8 +227,7 @@ const Utils = {
 
 const User = {
   init: async () => {
-    const response = await fetch("/verify");
-    const data = await response.json();
+    const data = await verifySession();
 
     if (data.status !== "success") {
       Utils.verifyToast();
@@ -215,7 +255,7 @@ const User = {
       APP_STATE.modalBase.Details.isActive = true;
     }
 
-    const response = await fetch(`/users/${user}`);
+    const response = await authFetch(`/users/${user}`);
     if (!response.ok) return;
 
     const data = await response.json();
@@ -290,13 +330,13 @@ const User = {
 
   changeBio: (biography) => {
     const wsMessage = {
-      action: "change_bio",
+      action: "profile.change_bio",
       payload: {
         biography: biography,
       },
     };
 
-    APP_STATE.sockets.chat.send(JSON.stringify(wsMessage));
+    Utils.send(APP_STATE.socket, wsMessage);
   },
 
   setupModal: (user, biography) => {
@@ -316,7 +356,7 @@ const User = {
     DOM_ELEMENTS.userConfigModalLogoutButton.addEventListener(
       "click",
       async () => {
-        await fetch("/logout", {
+        await authFetch("/logout", {
           method: "DELETE",
         });
         createSuccessAlert("Logout successfully");
@@ -401,7 +441,7 @@ const User = {
       const formData = new FormData();
       formData.append("file", DOM_ELEMENTS.fileInput.files[0]);
 
-      const response = await fetch("/upload_avatar", {
+      const response = await authFetch("/upload_avatar", {
         method: "POST",
         body: formData,
       });
@@ -415,20 +455,6 @@ const User = {
 
 const Friends = {
   init: async () => {
-    if (APP_STATE.sockets.friendReq) {
-      APP_STATE.sockets.friendReq.close();
-    }
-
-    const protocol = window.location.protocol === "https:" ? "wss:" : "ws:";
-    const wsUrl = `${protocol}//${window.location.host}/ws/friend_req`;
-
-    APP_STATE.sockets.friendReq = new WebSocket(wsUrl);
-    APP_STATE.sockets.friendReq.onopen = () =>
-      console.log("Friend WebSocket connected");
-    APP_STATE.sockets.friendReq.onmessage = Friends.handleMessage;
-    APP_STATE.sockets.friendReq.onclose = () =>
-      setTimeout(() => Friends.init(), 3000);
-
     await Friends.loadFriendRequests();
     DOM_ELEMENTS.friendReqButton.addEventListener(
       "click",
@@ -437,7 +463,7 @@ const Friends = {
   },
 
   loadFriendRequests: async () => {
-    const response = await fetch("/friend_req");
+    const response = await authFetch("/friend_req");
     if (!response.ok) return;
 
     const data = await response.json();
@@ -497,11 +523,11 @@ const Friends = {
 
   acceptRequest: async (friendId) => {
     const wsMessage = {
-      action: "accept",
+      action: "friend.accept",
       payload: { friend_id: friendId },
     };
     try {
-      APP_STATE.sockets.friendReq.send(JSON.stringify(wsMessage));
+      Utils.send(APP_STATE.socket, wsMessage);
     } catch (e) {
       Utils.verifyToast();
       createErrorAlert("Error sending friend request");
@@ -540,12 +566,12 @@ const Friends = {
 
   cancelFriendRequest: (friend_req_id) => {
     const wsMessage = {
-      action: "cancel",
+      action: "friend.cancel",
       payload: { friend_req_id: friend_req_id },
     };
 
     try {
-      APP_STATE.sockets.friendReq.send(JSON.stringify(wsMessage));
+      Utils.send(APP_STATE.socket, wsMessage);
     } catch (e) {
       Utils.verifyToast();
       createErrorAlert("Error sending friend request");
@@ -557,12 +583,12 @@ const Friends = {
     if (!receiverUsername) return;
 
     const wsMessage = {
-      action: "send_request",
+      action: "friend.send_request",
       payload: { receiver_username: receiverUsername },
     };
 
     try {
-      APP_STATE.sockets.friendReq.send(JSON.stringify(wsMessage));
+      Utils.send(APP_STATE.socket, wsMessage);
       DOM_ELEMENTS.friendReqInput.value = "";
     } catch (e) {
       Utils.verifyToast();
@@ -570,9 +596,9 @@ const Friends = {
     }
   },
 
-  handleMessage: (event) => {
+  // `data.action` without its `friend.` namespace
+  handleMessage: (data) => {
     try {
-      const data = JSON.parse(event.data);
       const user = APP_STATE.currentUser.username;
 
       switch (data.action) {
@@ -590,10 +616,19 @@ const Friends = {
           break;
 
         case "cancel":
+        case "unfriend":
           let friend = document.getElementById(`f${data.friend_req_id}`);
           friend?.remove();
           break;
 
+        case "block":
+          createSuccessAlert(`Blocked @${data.blocked_username}`);
+          break;
+
+        case "unblock":
+          createSuccessAlert(`Unblocked @${data.blocked_username}`);
+          break;
+
         case "accept":
           const isReceiver = user === data.receiver_username;
           Friends.acceptFriendRequest(
@@ -601,22 +636,17 @@ const Friends = {
             data.id
           );
           const wsMessage = {
-            action: "new_chat",
+            action: "chat.new_chat",
             payload: {
               second_user_name: isReceiver
                 ? data.sender_username
                 : data.receiver_username,
             },
           };
-          APP_STATE.sockets.chat.send(JSON.stringify(wsMessage));
+          Utils.send(APP_STATE.socket, wsMessage);
           setTimeout(() => Chat.loadChats(), 1500);
           createSuccessAlert("Friend request accepted");
           break;
-
-        case "error":
-          Utils.verifyToast();
-          createErrorAlert(data.payload.message);
-          break;
       }
     } catch (e) {
       console.error("Error processing friend message", e);
@@ -637,8 +667,8 @@ const Chat = {
       }
 
       if (
-        !APP_STATE.sockets.chat ||
-        APP_STATE.sockets.chat.readyState !== WebSocket.OPEN
+        !APP_STATE.socket ||
+        APP_STATE.socket.readyState !== WebSocket.OPEN
       ) {
         Utils.verifyToast();
         createErrorAlert("Connection not ready. Please wait...");
@@ -649,7 +679,7 @@ const Chat = {
       if (!message) return;
 
       const wsMessage = {
-        action: "edit_message",
+        action: "chat.edit_message",
         payload: {
           message_id: APP_STATE.currentEdit,
           message: message,
@@ -657,7 +687,7 @@ const Chat = {
       };
 
       try {
-        APP_STATE.sockets.chat.send(JSON.stringify(wsMessage));
+        Utils.send(APP_STATE.socket, wsMessage);
         DOM_ELEMENTS.sendMessageInput.textContent = "";
         if (APP_STATE.currentEdit !== null) {
           const edit = document.getElementById(`edit_${APP_STATE.currentEdit}`);
@@ -685,8 +715,8 @@ const Chat = {
       }
 
       if (
-        !APP_STATE.sockets.chat ||
-        APP_STATE.sockets.chat.readyState !== WebSocket.OPEN
+        !APP_STATE.socket ||
+        APP_STATE.socket.readyState !== WebSocket.OPEN
       ) {
         Utils.verifyToast();
         createErrorAlert("Connection not ready. Please wait...");
@@ -694,20 +724,27 @@ const Chat = {
       }
 
       const message = DOM_ELEMENTS.sendMessageInput.textContent.trim();
-      if (!message || !APP_STATE.currentChatPartner) return;
+      const attachments = APP_STATE.pendingAttachments.map(
+        (attachment) => attachment.id
+      );
+      if ((!message && !attachments.length) || !APP_STATE.currentChatPartner)
+        return;
 
       const wsMessage = {
-        action: "new_message",
+        action: "chat.new_message",
         payload: {
           message: message,
+          chat_id: APP_STATE.currentChatId,
           chat_partner: APP_STATE.currentChatPartner,
           reply: APP_STATE.currentReply,
+          attachments,
         },
       };
 
       try {
-        APP_STATE.sockets.chat.send(JSON.stringify(wsMessage));
+        Utils.send(APP_STATE.socket, wsMessage);
         DOM_ELEMENTS.sendMessageInput.textContent = "";
+        APP_STATE.pendingAttachments = [];
         if (APP_STATE.currentReply !== null) {
           const reply = document.getElementById(
             `reply_${APP_STATE.currentReply}`
@@ -728,6 +765,28 @@ const Chat = {
       }
     }
 
+    DOM_ELEMENTS.attachmentButton.onclick = () =>
+      DOM_ELEMENTS.attachmentInput.click();
+    DOM_ELEMENTS.attachmentInput.onchange = async () => {
+      for (const file of DOM_ELEMENTS.attachmentInput.files) {
+        const form = new FormData();
+        form.append("file", file);
+        const response = await authFetch("/attachments", {
+          method: "POST",
+          body: form,
+        });
+        const data = await response.json();
+        if (!response.ok) {
+          Utils.verifyToast();
+          createErrorAlert(data);
+          continue;
+        }
+        APP_STATE.pendingAttachments.push(data);
+        createSuccessAlert(`Attached ${data.filename}`);
+      }
+      DOM_ELEMENTS.attachmentInput.value = "";
+    };
+
     DOM_ELEMENTS.sendMessageButton.onclick = () => {
       if (!APP_STATE.currentEdit) {
         sendMessage();
@@ -749,24 +808,60 @@ const Chat = {
 
   setupWebSocket: async () => {
     return new Promise((resolve) => {
-      if (APP_STATE.sockets.chat) {
-        APP_STATE.sockets.chat.close();
+      if (APP_STATE.socket) {
+        APP_STATE.socket.close();
       }
 
       const protocol = window.location.protocol === "https:" ? "wss:" : "ws:";
-      const wsUrl = `${protocol}//${window.location.host}/ws`;
+      const wsUrl = `${protocol}//${window.location.host}/socket`;
 
-      APP_STATE.sockets.chat = new WebSocket(wsUrl);
+      APP_STATE.socket = new WebSocket(wsUrl, WS_PROTOCOL);
 
-      APP_STATE.sockets.chat.onopen = () => {
-        console.log("Chat WebSocket Connected");
+      APP_STATE.socket.onopen = () => {
+        console.log("WebSocket connected");
+        // replays whatever was missed while the socket was down
+        Utils.send(APP_STATE.socket, {
+          action: "chat.resume",
+          payload: { last_seq: APP_STATE.lastEventSeq },
+        });
         resolve();
       };
 
-      APP_STATE.sockets.chat.onmessage = (event) => {
+      document.onvisibilitychange = () => {
+        if (APP_STATE.socket.readyState !== WebSocket.OPEN) return;
+        Utils.send(APP_STATE.socket, {
+          action: "presence.set_status",
+          payload: { status: document.hidden ? "away" : "online" },
+        });
+      };
+
+      APP_STATE.socket.onmessage = (event) => {
         try {
           const data = JSON.parse(event.data);
-          if (data.action === "new_message") {
+          if (Utils.handleReply(data)) return;
+          // events are namespaced, e.g. `chat.new_message`, `presence.update`
+          const [namespace, name] = data.action.split(".", 2);
+          if (namespace === "friend") {
+            Friends.handleMessage({ ...data, action: name });
+            return;
+          }
+          if (name) data.action = name === "update" ? namespace : name;
+          if (typeof data.seq === "number") {
+            APP_STATE.lastEventSeq = Math.max(
+              APP_STATE.lastEventSeq ?? 0,
+              data.seq
+            );
+          }
+          if (data.action === "resumed") {
+            APP_STATE.lastEventSeq = Math.max(
+              APP_STATE.lastEventSeq ?? 0,
+              data.last_seq
+            );
+          } else if (data.action === "resync") {
+            // too much was missed to replay, start over from the API
+            APP_STATE.lastEventSeq = data.last_seq;
+            Chat.resync();
+          } else if (data.action === "new_message") {
             Chat.reorderChats(data.chat_id);
             if (data.chat_id === APP_STATE.currentChatId) {
               const messageId = `${data.id}_${data.username}`;
@@ -783,12 +878,18 @@ const Chat = {
                   data.replied_message,
                   data.time,
                   can_change,
-                  has_reply
+                  has_reply,
+                  false,
+                  false,
+                  data.reactions,
+                  data.attachments
                 );
                 APP_STATE.renderedMessages.add(messageId);
               }
+              Chat.markRead(data.chat_id);
             } else {
               createSuccessAlert(`New message from: @${data.username}`);
+              Chat.setUnread(data.chat_id, null);
             }
           } else if (data.action === "delete") {
             const message_to_delete = document.getElementById(
@@ -797,12 +898,18 @@ const Chat = {
             if (message_to_delete) {
               message_to_delete.remove();
             }
-          } else if (data.action === "new_chat") {
-            const otherUser =
-              APP_STATE.currentUser.username === data.first_user_name
-                ? data.second_user_name
-                : data.first_user_name;
+          } else if (
+            data.action === "new_chat" ||
+            data.action === "chat_updated"
+          ) {
             Chat.loadChats();
+          } else if (data.action === "member_removed") {
+            if (data.username === APP_STATE.currentUser.username) {
+              document.getElementById(`c${data.chat_id}`)?.remove();
+              APP_STATE.chats = APP_STATE.chats.filter(
+                (chat_obj) => chat_obj.id !== data.chat_id
+              );
+            }
           } else if (data.action === "edit_message") {
             if (data.chat_id === APP_STATE.currentChatId) {
               const message = document.getElementById(`raw_${data.id}`);
@@ -821,6 +928,12 @@ const Chat = {
                 messageInfo.appendChild(edit_warning);
               }
             }
+          } else if (data.action === "typing") {
+            Chat.showTyping(data);
+          } else if (data.action === "reaction_update") {
+            Chat.renderReactions(data.message_id, data.reactions);
+          } else if (data.action === "presence") {
+            APP_STATE.presence.set(data.username, data);
           } else if (data.action === "change_bio") {
             createSuccessAlert("Biography changed successfully");
           }
@@ -829,11 +942,15 @@ const Chat = {
         }
       };
 
-      APP_STATE.sockets.chat.onclose = () => {
-        setTimeout(() => Chat.setupWebSocket(), 3000);
+      APP_STATE.socket.onclose = () => {
+        // the socket is usually dropped because the access token expired
+        setTimeout(async () => {
+          await refreshSession();
+          Chat.setupWebSocket();
+        }, 3000);
       };
 
-      APP_STATE.sockets.chat.onerror = (error) => {
+      APP_STATE.socket.onerror = (error) => {
         console.error("WebSocket error:", error);
         Utils.verifyToast();
         createErrorAlert("Connection error. Reconnecting...");
@@ -842,6 +959,17 @@ const Chat = {
     });
   },
 
+  resync: async () => {
+    await Chat.loadChats();
+    if (APP_STATE.currentChatId === null) return;
+
+    DOM_ELEMENTS.chatContainer.innerHTML = "";
+    APP_STATE.renderedMessages.clear();
+    APP_STATE.oldestMessageId = null;
+    APP_STATE.hasMoreMessages = false;
+    await Chat.loadMessages(APP_STATE.currentChatId, false);
+  },
+
   reorderChats: (chat_id) => {
     const old_chat = document.getElementById(`c${chat_id}`);
     const chat = APP_STATE.chats.find((chat_obj) => chat_obj.id === chat_id);
@@ -852,7 +980,7 @@ const Chat = {
   },
 
   loadChats: async () => {
-    const response = await fetch("/chats");
+    const response = await authFetch("/chats");
     if (!response.ok) {
       Utils.verifyToast();
       createErrorAlert("Error fetching chats");
@@ -863,17 +991,106 @@ const Chat = {
 
     for (const chat of data) {
       const chatId = `c${chat.id}`;
-      const otherUser =
-        APP_STATE.currentUser.username === chat.first_user_name
-          ? chat.second_user_name
-          : chat.first_user_name;
+      const otherUser = chat.is_group
+        ? chat.name
+        : chat.members.find(
+            (member) => member !== APP_STATE.currentUser.username
+          ) ?? APP_STATE.currentUser.username;
 
       const pfpUrl = await Utils.checkPfpExists(otherUser);
       Chat.createChat(otherUser, pfpUrl, chat.id, true);
+      Chat.setUnread(chat.id, chat.unread_count);
       APP_STATE.renderedChats.add(chatId);
     }
   },
 
+  sendTyping: () => {
+    const now = Date.now();
+    if (
+      !APP_STATE.currentChatId ||
+      now - APP_STATE.lastTypingSent < 2000 ||
+      !APP_STATE.socket ||
+      APP_STATE.socket.readyState !== WebSocket.OPEN
+    )
+      return;
+    APP_STATE.lastTypingSent = now;
+    Utils.send(APP_STATE.socket, {
+      action: "chat.typing_start",
+      payload: { chat_id: APP_STATE.currentChatId },
+    });
+  },
+
+  showTyping: (data) => {
+    if (data.chat_id !== APP_STATE.currentChatId) return;
+    const base = DOM_ELEMENTS.topbarUsername.textContent.split(" · ")[0];
+    DOM_ELEMENTS.topbarUsername.textContent = data.typing
+      ? `${base} · @${data.username} is typing...`
+      : base;
+  },
+
+  toggleReaction: (messageId, emoji) => {
+    const reaction = APP_STATE.reactions.get(messageId)?.find(
+      (reaction) => reaction.emoji === emoji
+    );
+    const reacted = reaction?.usernames.includes(
+      APP_STATE.currentUser.username
+    );
+    Utils.send(APP_STATE.socket, {
+      action: reacted ? "chat.remove_reaction" : "chat.add_reaction",
+      payload: { message_id: messageId, emoji },
+    });
+  },
+
+  renderReactions: (messageId, reactions) => {
+    APP_STATE.reactions.set(messageId, reactions);
+    const container = document.getElementById(`reactions_${messageId}`);
+    if (!container) return;
+    container.replaceChildren(
+      ...reactions.map((reaction) => {
+        const chip = document.createElement("p");
+        chip.classList.add("buttons");
+        chip.textContent = `${reaction.emoji} ${reaction.count}`;
+        chip.title = reaction.usernames.map((user) => `@${user}`).join(", ");
+        chip.addEventListener("click", () =>
+          Chat.toggleReaction(messageId, reaction.emoji)
+        );
+        return chip;
+      })
+    );
+  },
+
+  markRead: (chatId) => {
+    if (
+      !APP_STATE.socket ||
+      APP_STATE.socket.readyState !== WebSocket.OPEN
+    )
+      return;
+    Utils.send(APP_STATE.socket, {
+      action: "chat.mark_read",
+      payload: { chat_id: chatId },
+    });
+  },
+
+  // count === null increments the badge by one
+  setUnread: (chatId, count) => {
+    const chatDiv = document.getElementById(`c${chatId}`);
+    if (!chatDiv) return;
+
+    let badge = chatDiv.querySelector(".unread_count");
+    const current = badge ? parseInt(badge.textContent, 10) : 0;
+    const next = count === null ? current + 1 : count;
+    if (next <= 0) {
+      badge?.remove();
+      return;
+    }
+    if (!badge) {
+      badge = document.createElement("span");
+      badge.classList.add("unread_count");
+      chatDiv.appendChild(badge);
+    }
+    badge.textContent = `${next}`;
+  },
+
   createChat: (username, pfp, id, first_load) => {
     if (document.getElementById(`c${id}`)) return;
 
@@ -985,35 +1202,20 @@ const Chat = {
 
     sendMessageInput.addEventListener("input", () => {
       cleanUpInput();
+      Chat.sendTyping();
     });
 
-    const response = await fetch(`/messages/${chatId}`);
-    if (!response.ok) return;
+    APP_STATE.oldestMessageId = null;
+    APP_STATE.hasMoreMessages = false;
+    await Chat.loadMessages(chatId, false);
+    Chat.markRead(chatId);
+    Chat.setUnread(chatId, 0);
 
-    const messages = await response.json();
-    messages.forEach((message) => {
-      const messageId = `${message.id || message.timestamp}_${
-        message.username
-      }`;
-      const can_change =
-        APP_STATE.currentUser.username === message.username ? true : false;
-      const has_reply =
-        message.replied_user || message.replied_message ? true : false;
-      if (!APP_STATE.renderedMessages.has(messageId)) {
-        Chat.createMessage(
-          message.id,
-          message.username,
-          message.message,
-          message.replied_user,
-          message.replied_message,
-          message.time,
-          can_change,
-          has_reply,
-          message.edited
-        );
-        APP_STATE.renderedMessages.add(messageId);
+    DOM_ELEMENTS.chatContainer.onscroll = () => {
+      if (DOM_ELEMENTS.chatContainer.scrollTop < 50) {
+        Chat.loadMessages(chatId, true);
       }
-    });
+    };
 
     setTimeout(() => {
       DOM_ELEMENTS.chatContainer.scrollTop =
@@ -1021,6 +1223,66 @@ const Chat = {
     }, 100);
   },
 
+  loadMessages: async (chatId, older) => {
+    if (older && (!APP_STATE.hasMoreMessages || APP_STATE.loadingMessages)) {
+      return;
+    }
+
+    APP_STATE.loadingMessages = true;
+    const query =
+      older && APP_STATE.oldestMessageId !== null
+        ? `?before=${APP_STATE.oldestMessageId}`
+        : "";
+
+    try {
+      const response = await authFetch(`/messages/${chatId}${query}`);
+      if (!response.ok || chatId !== APP_STATE.currentChatId) return;
+
+      const page = await response.json();
+      APP_STATE.hasMoreMessages = page.has_more;
+      if (page.messages.length > 0) {
+        APP_STATE.oldestMessageId = page.messages[0].id;
+      }
+
+      const previousHeight = DOM_ELEMENTS.chatContainer.scrollHeight;
+      // older pages are prepended one by one, newest first
+      const messages = older ? [...page.messages].reverse() : page.messages;
+      messages.forEach((message) => {
+        const messageId = `${message.id || message.timestamp}_${
+          message.username
+        }`;
+        const can_change =
+          APP_STATE.currentUser.username === message.username ? true : false;
+        const has_reply =
+          message.replied_user || message.replied_message ? true : false;
+        if (!APP_STATE.renderedMessages.has(messageId)) {
+          Chat.createMessage(
+            message.id,
+            message.username,
+            message.message,
+            message.replied_user,
+            message.replied_message,
+            message.time,
+            can_change,
+            has_reply,
+            message.edited,
+            older,
+            message.reactions,
+            message.attachments
+          );
+          APP_STATE.renderedMessages.add(messageId);
+        }
+      });
+
+      if (older) {
+        DOM_ELEMENTS.chatContainer.scrollTop =
+          DOM_ELEMENTS.chatContainer.scrollHeight - previousHeight;
+      }
+    } finally {
+      APP_STATE.loadingMessages = false;
+    }
+  },
+
   createMessage: (
     message_id,
     username,
@@ -1030,7 +1292,10 @@ const Chat = {
     timestamp,
     can_change,
     has_reply,
-    edited
+    edited,
+    prepend = false,
+    reactions = [],
+    attachments = []
   ) => {
     const messageContainer = document.createElement("div");
     messageContainer.classList.add("message_container");
@@ -1120,6 +1385,12 @@ const Chat = {
     });
     options.appendChild(reply_button);
 
+    const react_button = document.createElement("p");
+    react_button.classList.add("buttons");
+    react_button.textContent = "👍";
+    react_button.addEventListener("click", () => Chat.toggleReaction(message_id, "👍"));
+    options.appendChild(react_button);
+
     const edit_button = document.createElement("p");
     edit_button.classList.add("buttons");
     edit_button.textContent = "Edit";
@@ -1164,7 +1435,7 @@ const Chat = {
     });
 
     const deleteWsMessage = {
-      action: "delete_message",
+      action: "chat.delete_message",
       payload: {
         id: message_id,
       },
@@ -1174,7 +1445,7 @@ const Chat = {
     delete_button.classList.add("buttons");
     delete_button.textContent = "Delete";
     delete_button.addEventListener("click", () => {
-      APP_STATE.sockets.chat.send(JSON.stringify(deleteWsMessage));
+      Utils.send(APP_STATE.socket, deleteWsMessage);
     });
     if (can_change) {
       options.appendChild(edit_button);
@@ -1224,9 +1495,30 @@ const Chat = {
     rawMessage.id = `raw_${message_id}`;
     rawMessage.textContent = message;
     message_sub_container.appendChild(rawMessage);
+    attachments.forEach((attachment) => {
+      const url = `/attachments/${attachment.id}`;
+      if (attachment.mime_type.startsWith("image/")) {
+        const image = document.createElement("img");
+        image.classList.add("attachment");
+        image.src = url;
+        image.alt = attachment.filename;
+        message_sub_container.appendChild(image);
+      } else {
+        const link = document.createElement("a");
+        link.classList.add("attachment");
+        link.href = url;
+        link.textContent = attachment.filename;
+        message_sub_container.appendChild(link);
+      }
+    });
 
     rightSide.appendChild(message_sub_container);
 
+    const reactionsContainer = document.createElement("div");
+    reactionsContainer.classList.add("reactions");
+    reactionsContainer.id = `reactions_${message_id}`;
+    rightSide.appendChild(reactionsContainer);
+
     messageContainer.id = `${message_id}`;
 
     bottom.appendChild(rightSide);
@@ -1237,7 +1529,13 @@ const Chat = {
     messageContainer.addEventListener("mouseleave", () => {
       options.style.display = "none";
     });
+    if (prepend) {
+      DOM_ELEMENTS.chatContainer.prepend(messageContainer);
+      Chat.renderReactions(message_id, reactions);
+      return;
+    }
     DOM_ELEMENTS.chatContainer.appendChild(messageContainer);
+    Chat.renderReactions(message_id, reactions);
 
     setTimeout(() => {
       DOM_ELEMENTS.chatContainer.scrollTop =
@@ -1256,8 +1554,7 @@ const initApp = async () => {
 };
 
 window.addEventListener("beforeunload", () => {
-  if (APP_STATE.sockets.chat) APP_STATE.sockets.chat.close();
-  if (APP_STATE.sockets.friendReq) APP_STATE.sockets.friendReq.close();
+  if (APP_STATE.socket) APP_STATE.socket.close();
 });
 
 if (document.readyState === "loading") {
@@ -1,6 +1,7 @@
+import { verifySession } from "./index.js";
+
 document.addEventListener("DOMContentLoaded", async () => {
-  const response = await fetch("/verify");
-  const data = await response.json();
+  const data = await verifySession();
   if (data.status === "success") {
     window.location.href = "/me.html";
   }
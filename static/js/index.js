@@ -105,3 +105,37 @@ export function createSuccessAlert(message) {
     alertSpan.remove();
   }, 3000);
 }
+
+let refreshing = null;
+
+// Access tokens expire after a few minutes, the refresh token cookie trades
+// itself for a new one. Concurrent callers share the same request since the
+// refresh token is rotated on every use.
+export function refreshSession() {
+  if (!refreshing) {
+    refreshing = fetch("/refresh", { method: "POST" })
+      .then((response) => response.ok)
+      .catch(() => false)
+      .finally(() => {
+        refreshing = null;
+      });
+  }
+  return refreshing;
+}
+
+export async function authFetch(url, options = {}) {
+  const response = await fetch(url, options);
+  if (response.status !== 401 || !(await refreshSession())) {
+    return response;
+  }
+  return fetch(url, options);
+}
+
+// `/verify` answers 200 either way, so its status is checked instead
+export async function verifySession() {
+  const data = await (await fetch("/verify")).json();
+  if (data.status === "success" || !(await refreshSession())) {
+    return data;
+  }
+  return (await fetch("/verify")).json();
+}
@@ -2,25 +2,43 @@ import { createErrorAlert, createSuccessAlert } from "./index.js";
 
 const emailInput = document.getElementById("emailInput");
 const passwordInput = document.getElementById("passwordInput");
+const codeInput = document.getElementById("codeInput");
 const loginButton = document.getElementById("signButton");
 const modalBase = document.getElementById("modalBase");
 
+// set once the password was accepted for an account with two-factor enabled
+let mfaToken = null;
+
 loginButton.addEventListener("click", async (e) => {
   e.preventDefault();
   const email = emailInput.value;
   const password = passwordInput.value;
 
-  const response = await fetch("/login", {
-    method: "POST",
-    headers: {
-      "Content-Type": "application/json",
-    },
-    body: JSON.stringify({ email, password }),
-  });
+  const response = mfaToken
+    ? await fetch("/login/2fa", {
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ mfa_token: mfaToken, code: codeInput.value }),
+      })
+    : await fetch("/login", {
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ email, password }),
+      });
 
   const data = await response.json();
 
-  if (data.status === "success") {
+  if (data.status === "mfa_required") {
+    mfaToken = data.mfa_token;
+    emailInput.hidden = true;
+    passwordInput.hidden = true;
+    codeInput.hidden = false;
+    codeInput.focus();
+  } else if (data.status === "success") {
     createSuccessAlert("Log in successful!");
     setInterval(() => {
       window.location.href = "/me.html";
@@ -30,6 +48,14 @@ loginButton.addEventListener("click", async (e) => {
     modalBase.addEventListener("click", () => {
       modalBase.style.display = "none";
     });
+  } else if (data.message === "invalid or expired token") {
+    // the two-factor step took too long, start over with the password
+    mfaToken = null;
+    emailInput.hidden = false;
+    passwordInput.hidden = false;
+    codeInput.hidden = true;
+    codeInput.value = "";
+    createErrorAlert("Login expired, please try again");
   } else {
     createErrorAlert(data.message);
   }
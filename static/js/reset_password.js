@@ -0,0 +1,48 @@
+import { createErrorAlert, createSuccessAlert } from "./index.js";
+
+const emailInput = document.getElementById("emailInput");
+const passwordInput = document.getElementById("passwordInput");
+const resetButton = document.getElementById("resetButton");
+const token = new URLSearchParams(window.location.search).get("token");
+
+// without a token the page asks for the email, with one for the new password
+if (token) {
+  emailInput.remove();
+  resetButton.textContent = "Reset password";
+} else {
+  passwordInput.remove();
+}
+
+resetButton.addEventListener("click", async (e) => {
+  e.preventDefault();
+
+  const response = token
+    ? await fetch("/reset_password", {
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ token, password: passwordInput.value }),
+      })
+    : await fetch("/forgot_password", {
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ email: emailInput.value }),
+      });
+
+  const data = await response.json();
+
+  if (data.status !== "success") {
+    createErrorAlert(data.message);
+    return;
+  }
+
+  createSuccessAlert(data.message);
+  if (token) {
+    setTimeout(() => {
+      window.location.href = "/login.html";
+    }, 1000);
+  }
+});
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{PASSWORD, TestApp, cookie};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+#[actix_rt::test]
+async fn register_verify_and_login() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let email = "alice@kutter.test";
+
+    let response = app.register("alice").await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(app.emails_to(email).len(), 1);
+
+    let response = app.register("alice").await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = app.login(email, PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["message"], "email not verified");
+
+    let body: Value = app.verify(email).await.json().await.unwrap();
+    assert_eq!(body["status"], "success");
+
+    let response = app.login(email, "wrong password").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.login(email, PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let token = cookie(&response, "token").expect("no token cookie");
+    assert!(cookie(&response, "refresh_token").is_some());
+
+    let response = app.get_authed("/sessions", &token).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let sessions: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(sessions.len(), 1);
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn invalid_verification_tokens_are_rejected() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let body: Value = app
+        .client
+        .get(app.url("/verify_email?token=nope"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["message"], "invalid token");
+
+    let response = app.get_authed("/chats", "nope").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    app.cleanup().await;
+}
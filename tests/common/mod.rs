@@ -0,0 +1,445 @@
+//! Boots the whole app on a random port against a throwaway database.
+//!
+//! The database server comes from `TEST_DATABASE_URL` (or `DATABASE_URL`),
+//! every `TestApp` creates its own database on it and drops it in `cleanup`.
+//! Without either variable the tests are skipped.
+
+#![allow(dead_code)]
+
+use futures_util::future::BoxFuture;
+use futures_util::{SinkExt, StreamExt};
+use kutter::app::{self, AppData};
+use kutter::bus;
+use kutter::config::{
+    self, AuthConfig, BusBackend, BusConfig, Config, DatabaseConfig, RateLimitConfig, UploadConfig,
+    WebSocketConfig,
+};
+use kutter::hub::Hub;
+use kutter::mailer::{Email, MailService, Mailer};
+use kutter::rate_limit::{Limit, RateLimits};
+use kutter::routes::presence;
+use kutter::routes::socket::Event;
+use reqwest::header::{COOKIE, SEC_WEBSOCKET_PROTOCOL, SET_COOKIE};
+use serde_json::{Value, json};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Connection, PgConnection, PgPool};
+use std::net::{SocketAddr, TcpListener};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{self, http::HeaderValue};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub const PASSWORD: &str = "hunter22";
+
+/// How long a test waits for a websocket event before failing.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps every email instead of sending it.
+#[derive(Clone, Default)]
+struct RecordingMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl Mailer for RecordingMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.sent.lock().unwrap().push(email.clone());
+            Ok(())
+        })
+    }
+}
+
+fn server_url() -> Option<String> {
+    dotenv::dotenv().ok();
+    std::env::var("TEST_DATABASE_URL")
+        .or_else(|_| std::env::var("DATABASE_URL"))
+        .ok()
+}
+
+/// Every test of the binary shares the configuration, only the pool differs.
+fn init_config(url: &str) {
+    let unlimited = Limit::new(10_000, 1);
+    config::init(Config {
+        database: DatabaseConfig {
+            url: url.to_string(),
+            ..DatabaseConfig::default()
+        },
+        auth: AuthConfig {
+            jwt_secret: "integration-test-secret".to_string(),
+            ..AuthConfig::default()
+        },
+        uploads: UploadConfig {
+            dir: std::env::temp_dir().join("kutter-test-uploads"),
+            ..UploadConfig::default()
+        },
+        rate_limits: RateLimitConfig {
+            auth: unlimited,
+            logins: unlimited,
+            emails: unlimited,
+            ws_actions: unlimited,
+            ws_messages: unlimited,
+        },
+        // short enough for a test to wait out, clients that are read from
+        // answer pings on their own
+        websocket: WebSocketConfig {
+            ping_interval_secs: 1,
+            pong_timeout_secs: 3,
+        },
+        // the nodes of a test only see each other through the database
+        bus: BusConfig {
+            backend: BusBackend::Postgres,
+        },
+        ..Config::default()
+    });
+}
+
+pub struct TestApp {
+    pub addr: SocketAddr,
+    pub pool: PgPool,
+    pub client: reqwest::Client,
+    server: actix_web::dev::ServerHandle,
+    hub: Arc<Hub<Event>>,
+    sent: Arc<Mutex<Vec<Email>>>,
+    server_options: PgConnectOptions,
+    db_name: String,
+    // peers share the database of the app they were spawned from
+    owns_database: bool,
+}
+
+/// A registered, verified and logged in user.
+pub struct TestUser {
+    pub username: String,
+    pub email: String,
+    pub token: String,
+}
+
+impl TestApp {
+    /// `None` when no database server is configured.
+    pub async fn spawn() -> Option<Self> {
+        let Some(url) = server_url() else {
+            eprintln!("TEST_DATABASE_URL is not set, skipping");
+            return None;
+        };
+        init_config(&url);
+
+        let server_options = PgConnectOptions::from_str(&url).expect("invalid database url");
+        let db_name = format!("kutter_test_{:016x}", rand::random::<u64>());
+        let mut connection = PgConnection::connect_with(&server_options)
+            .await
+            .expect("Failed to connect to the test database server");
+        sqlx::query(&format!("CREATE DATABASE {}", db_name))
+            .execute(&mut connection)
+            .await
+            .expect("Failed to create the test database");
+        connection.close().await.ok();
+
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect_with(server_options.clone().database(&db_name))
+            .await
+            .expect("Failed to connect to the test database");
+        kutter::db::MIGRATOR
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations");
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let (addr, server, hub) = start_node(&pool, sent.clone()).await;
+
+        Some(Self {
+            addr,
+            pool,
+            client: reqwest::Client::new(),
+            server,
+            hub,
+            sent,
+            server_options,
+            db_name,
+            owns_database: true,
+        })
+    }
+
+    /// Another node on the same database, with its own server and hub.
+    pub async fn peer(&self) -> Self {
+        let (addr, server, hub) = start_node(&self.pool, self.sent.clone()).await;
+        Self {
+            addr,
+            pool: self.pool.clone(),
+            client: reqwest::Client::new(),
+            server,
+            hub,
+            sent: self.sent.clone(),
+            server_options: self.server_options.clone(),
+            db_name: self.db_name.clone(),
+            owns_database: false,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> reqwest::Response {
+        self.client
+            .post(self.url(path))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_authed(&self, path: &str, token: &str) -> reqwest::Response {
+        self.client
+            .get(self.url(path))
+            .header(COOKIE, format!("token={}", token))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn delete_authed(&self, path: &str, cookies: &str) -> reqwest::Response {
+        self.client
+            .delete(self.url(path))
+            .header(COOKIE, cookies)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub fn emails_to(&self, address: &str) -> Vec<Email> {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|email| email.to == address)
+            .cloned()
+            .collect()
+    }
+
+    /// The token of the last verification link sent to `address`.
+    pub fn verification_token(&self, address: &str) -> String {
+        let email = self
+            .emails_to(address)
+            .pop()
+            .expect("no verification email was sent");
+        let (_, rest) = email
+            .html
+            .split_once("verify_email?token=")
+            .expect("no verification link in the email");
+        rest.split('"').next().unwrap().to_string()
+    }
+
+    pub async fn register(&self, username: &str) -> reqwest::Response {
+        self.post(
+            "/register",
+            json!({
+                "username": username,
+                "email": format!("{}@kutter.test", username),
+                "password": PASSWORD,
+            }),
+        )
+        .await
+    }
+
+    pub async fn verify(&self, email: &str) -> reqwest::Response {
+        self.client
+            .get(self.url("/verify_email"))
+            .query(&[("token", self.verification_token(email))])
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn login(&self, email: &str, password: &str) -> reqwest::Response {
+        self.post("/login", json!({ "email": email, "password": password }))
+            .await
+    }
+
+    /// Registers, verifies and logs in `username`.
+    pub async fn user(&self, username: &str) -> TestUser {
+        let email = format!("{}@kutter.test", username);
+        assert!(self.register(username).await.status().is_success());
+        assert!(self.verify(&email).await.status().is_success());
+        let response = self.login(&email, PASSWORD).await;
+        assert!(response.status().is_success());
+        TestUser {
+            username: username.to_string(),
+            token: cookie(&response, "token").expect("no token cookie"),
+            email,
+        }
+    }
+
+    /// A legacy client, which doesn't negotiate a protocol version.
+    pub async fn ws(&self, path: &str, user: &TestUser) -> WsClient {
+        self.connect_ws(path, user, None).await
+    }
+
+    /// A client speaking `kutter.v<version>`, whose requests carry ids.
+    pub async fn ws_versioned(&self, path: &str, user: &TestUser, version: u32) -> WsClient {
+        self.connect_ws(path, user, Some(version)).await
+    }
+
+    async fn connect_ws(&self, path: &str, user: &TestUser, version: Option<u32>) -> WsClient {
+        let mut request = format!("ws://{}{}", self.addr, path)
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("token={}", user.token)).unwrap(),
+        );
+        if let Some(version) = version {
+            request.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_str(&format!("kutter.v{}", version)).unwrap(),
+            );
+        }
+        let (stream, response) = tokio_tungstenite::connect_async(request)
+            .await
+            .expect("Failed to open the websocket");
+        if let Some(version) = version {
+            assert_eq!(
+                response.headers()[SEC_WEBSOCKET_PROTOCOL],
+                format!("kutter.v{}", version).as_str()
+            );
+        }
+        WsClient {
+            stream,
+            versioned: version.is_some(),
+            last_id: 0,
+        }
+    }
+
+    /// Stops the server and the bus, then drops the database, peers leave it
+    /// to the app they were spawned from.
+    pub async fn cleanup(self) {
+        self.server.stop(false).await;
+        self.hub.close().await;
+        if !self.owns_database {
+            return;
+        }
+        self.pool.close().await;
+        let mut connection = PgConnection::connect_with(&self.server_options)
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", self.db_name))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    }
+}
+
+/// Serves the app on a random port, as one node of the cluster on `pool`.
+async fn start_node(
+    pool: &PgPool,
+    sent: Arc<Mutex<Vec<Email>>>,
+) -> (SocketAddr, actix_web::dev::ServerHandle, Arc<Hub<Event>>) {
+    let mail = MailService::new(Box::new(RecordingMailer { sent }), "http://localhost");
+    let rate_limits = RateLimits::new(&config::get().rate_limits);
+    let hub = Arc::new(Hub::with_bus(bus::from_config(&config::get().bus, pool)));
+    hub.relay()
+        .await
+        .expect("Failed to subscribe to the event bus");
+    let data = AppData::new(pool.clone(), hub.clone(), rate_limits, mail);
+    presence::start(data.chat_state.clone())
+        .await
+        .expect("Failed to register the node");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = actix_web::HttpServer::new(move || app::build_app(data.clone()))
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .unwrap()
+        .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+    (addr, handle, hub)
+}
+
+/// The value of the `name` cookie set by `response`.
+pub fn cookie(response: &reqwest::Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .filter_map(|header| header.split(';').next()?.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+pub struct WsClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    versioned: bool,
+    last_id: u64,
+}
+
+impl WsClient {
+    /// Sends a request, returning its id. Only versioned clients put the
+    /// id on the wire.
+    pub async fn send(&mut self, action: &str, payload: Value) -> u64 {
+        self.last_id += 1;
+        let mut frame = json!({ "action": action, "payload": payload });
+        if self.versioned {
+            frame["id"] = json!(self.last_id);
+        }
+        self.send_raw(&frame.to_string()).await;
+        self.last_id
+    }
+
+    pub async fn send_raw(&mut self, text: &str) {
+        self.stream
+            .send(tungstenite::Message::text(text))
+            .await
+            .unwrap();
+    }
+
+    /// The next JSON frame, failing the test after `RECV_TIMEOUT`.
+    pub async fn recv(&mut self) -> Value {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.stream.next())
+                .await
+                .expect("timed out waiting for a websocket frame")
+                .expect("websocket closed")
+                .unwrap();
+            if let tungstenite::Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// Skips frames until the server closes the socket, failing the test
+    /// after `RECV_TIMEOUT`.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.stream.next())
+                .await
+                .expect("timed out waiting for the websocket to close");
+            match message {
+                None | Some(Err(_)) | Some(Ok(tungstenite::Message::Close(_))) => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// Fails the test if a JSON frame comes in within `wait`.
+    pub async fn expect_silence(&mut self, wait: Duration) {
+        if let Ok(frame) = tokio::time::timeout(wait, self.recv()).await {
+            panic!("unexpected websocket frame {}", frame);
+        }
+    }
+
+    /// Skips frames until one with `action` comes in, e.g. presence updates.
+    pub async fn recv_action(&mut self, action: &str) -> Value {
+        loop {
+            let frame = self.recv().await;
+            if frame["action"] == action {
+                return frame;
+            }
+        }
+    }
+}
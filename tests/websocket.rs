@@ -0,0 +1,626 @@
+mod common;
+
+use common::{PASSWORD, TestApp, TestUser, WsClient, cookie};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn messages_are_delivered_edited_and_deleted() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mallory = app.user("mallory").await;
+    let mut alice_ws = app.ws("/ws", &alice).await;
+    let mut bob_ws = app.ws("/ws", &bob).await;
+    let mut mallory_ws = app.ws("/ws", &mallory).await;
+
+    alice_ws
+        .send(
+            "new_message",
+            json!({ "message": "hi bob", "chat_partner": "bob" }),
+        )
+        .await;
+
+    let chat = bob_ws.recv_action("new_chat").await;
+    assert_eq!(chat["members"], json!(["alice", "bob"]));
+    let chat_id = chat["id"].as_i64().unwrap();
+
+    let received = bob_ws.recv_action("new_message").await;
+    assert_eq!(received["message"], "hi bob");
+    assert_eq!(received["username"], "alice");
+    assert_eq!(received["chat_id"], chat_id);
+    let sent = alice_ws.recv_action("new_message").await;
+    assert_eq!(sent["id"], received["id"]);
+    let message_id = received["id"].as_i64().unwrap();
+
+    alice_ws
+        .send(
+            "edit_message",
+            json!({ "message_id": message_id, "message": "hi bob!" }),
+        )
+        .await;
+    let edited = bob_ws.recv_action("edit_message").await;
+    assert_eq!(edited["message"], "hi bob!");
+    assert_eq!(edited["edited"], true);
+
+    // only the author can delete
+    bob_ws
+        .send("delete_message", json!({ "id": message_id }))
+        .await;
+    let error = bob_ws.recv_action("error").await;
+    assert_eq!(
+        error["payload"]["message"],
+        "You can only delete your own messages"
+    );
+
+    alice_ws
+        .send("delete_message", json!({ "id": message_id }))
+        .await;
+    let deleted = bob_ws.recv_action("delete").await;
+    assert_eq!(deleted["message_id"], message_id);
+    assert_eq!(deleted["chat_id"], chat_id);
+    // nothing of the chat reaches non-members
+    mallory_ws.expect_silence(Duration::from_millis(500)).await;
+
+    let response = app
+        .get_authed(&format!("/messages/{}", chat_id), &bob.token)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page: Value = response.json().await.unwrap();
+    assert_eq!(page["messages"], json!([]));
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn messages_claim_all_of_their_attachments_or_none() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    app.user("bob").await;
+    let mut alice_ws = app.ws_versioned("/ws", &alice, 1).await;
+    let upload = async |uploader: &str| -> i32 {
+        sqlx::query_scalar(
+            r#"
+            INSERT INTO attachments (sha256, uploader, filename, mime_type, size)
+            VALUES ($1, $2, 'notes.txt', 'text/plain', 5)
+            RETURNING id
+            "#,
+        )
+        .bind("0".repeat(64))
+        .bind(uploader)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap()
+    };
+    let (mine, bobs) = (upload("alice").await, upload("bob").await);
+    let send = async |ws: &mut WsClient, attachments: Vec<i32>| {
+        ws.send(
+            "new_message",
+            json!({ "message": "notes", "chat_partner": "bob", "attachments": attachments }),
+        )
+        .await
+    };
+
+    let id = send(&mut alice_ws, vec![mine, bobs]).await;
+    let error = alice_ws.recv_action("error").await;
+    assert_eq!(error["id"], id);
+    assert_eq!(error["message"], "Invalid attachments");
+    let messages: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(messages, 0);
+
+    let id = send(&mut alice_ws, vec![mine]).await;
+    assert_eq!(alice_ws.recv_action("ack").await["id"], id);
+    let message = alice_ws.recv_action("new_message").await;
+    assert_eq!(message["attachments"][0]["id"], mine);
+
+    // already part of a message
+    let id = send(&mut alice_ws, vec![mine]).await;
+    let error = alice_ws.recv_action("error").await;
+    assert_eq!(error["id"], id);
+    assert_eq!(error["message"], "Invalid attachments");
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn versioned_requests_are_acked_or_rejected_by_id() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    app.user("bob").await;
+    let mut alice_ws = app.ws_versioned("/ws", &alice, 1).await;
+
+    let id = alice_ws
+        .send(
+            "new_message",
+            json!({ "message": "hi bob", "chat_partner": "bob" }),
+        )
+        .await;
+    let ack = alice_ws.recv_action("ack").await;
+    assert_eq!(ack["id"], id);
+
+    let id = alice_ws
+        .send("delete_message", json!({ "id": 999_999 }))
+        .await;
+    let error = alice_ws.recv_action("error").await;
+    assert_eq!(error["id"], id);
+    assert_eq!(error["code"], "rejected");
+    assert_eq!(error["message"], "Message not found");
+
+    alice_ws
+        .send_raw(r#"{"id": "x", "action": "new_message", "payload": {}}"#)
+        .await;
+    let error = alice_ws.recv_action("error").await;
+    assert_eq!(error["id"], "x");
+    assert_eq!(error["code"], "bad_request");
+
+    // versioned clients must name their requests
+    alice_ws
+        .send_raw(r#"{"action": "mark_read", "payload": {"chat_id": 1}}"#)
+        .await;
+    let error = alice_ws.recv_action("error").await;
+    assert_eq!(error["id"], Value::Null);
+    assert_eq!(error["code"], "bad_request");
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn friend_requests_are_sent_and_accepted() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mut alice_ws = app.ws("/ws/friend_req", &alice).await;
+    let mut bob_ws = app.ws("/ws/friend_req", &bob).await;
+
+    alice_ws
+        .send("send_request", json!({ "receiver_username": "bob" }))
+        .await;
+    let request = bob_ws.recv_action("send_request").await;
+    assert_eq!(request["sender_username"], "alice");
+    assert_eq!(request["status"], "pending");
+    alice_ws.recv_action("send_request").await;
+
+    // a second request in either direction is refused
+    bob_ws
+        .send("send_request", json!({ "receiver_username": "alice" }))
+        .await;
+    let error = bob_ws.recv_action("error").await;
+    assert_eq!(
+        error["payload"]["message"],
+        "Friend request already sent or received"
+    );
+
+    bob_ws
+        .send("accept", json!({ "friend_id": request["id"] }))
+        .await;
+    let accepted = alice_ws.recv_action("accept").await;
+    assert_eq!(accepted["receiver_username"], "bob");
+    assert_eq!(accepted["status"], "accepted");
+
+    let friends: Vec<Value> = app
+        .get_authed("/friend_req", &alice.token)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(friends.len(), 1);
+    assert_eq!(friends[0]["status"], "accepted");
+
+    alice_ws
+        .send("unfriend", json!({ "username": "bob" }))
+        .await;
+    let removed = bob_ws.recv_action("unfriend").await;
+    assert_eq!(removed["friend_req_id"], request["id"]);
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn one_socket_carries_every_namespace() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mut alice_ws = app.ws_versioned("/socket", &alice, 1).await;
+    let mut bob_ws = app.ws_versioned("/socket", &bob, 1).await;
+    let mut bob_legacy = app.ws("/ws/friend_req", &bob).await;
+
+    let id = alice_ws
+        .send("friend.send_request", json!({ "receiver_username": "bob" }))
+        .await;
+    assert_eq!(alice_ws.recv_action("ack").await["id"], id);
+    let request = bob_ws.recv_action("friend.send_request").await;
+    assert_eq!(request["sender_username"], "alice");
+    // the legacy socket shares the hub and gets the bare action
+    let legacy = bob_legacy.recv().await;
+    assert_eq!(legacy["action"], "send_request");
+    assert_eq!(legacy["id"], request["id"]);
+
+    bob_ws
+        .send("friend.accept", json!({ "friend_id": request["id"] }))
+        .await;
+    alice_ws.recv_action("friend.accept").await;
+
+    alice_ws
+        .send(
+            "chat.new_message",
+            json!({ "message": "hi bob", "chat_partner": "bob" }),
+        )
+        .await;
+    assert_eq!(bob_ws.recv_action("chat.new_chat").await["seq"], 1);
+    let message = bob_ws.recv_action("chat.new_message").await;
+    assert_eq!(message["message"], "hi bob");
+
+    bob_ws
+        .send("presence.set_status", json!({ "status": "away" }))
+        .await;
+    let presence = alice_ws.recv_action("presence.update").await;
+    assert_eq!(presence["username"], "bob");
+    assert_eq!(presence["status"], "away");
+
+    // replays are namespaced too
+    drop(bob_ws);
+    alice_ws
+        .send(
+            "chat.new_message",
+            json!({ "message": "still there?", "chat_id": message["chat_id"] }),
+        )
+        .await;
+    alice_ws.recv_action("chat.new_message").await;
+    let mut bob_ws = app.ws_versioned("/socket", &bob, 1).await;
+    bob_ws.send("chat.resume", json!({ "last_seq": 2 })).await;
+    let replayed = bob_ws.recv_action("chat.new_message").await;
+    assert_eq!(replayed["message"], "still there?");
+    assert_eq!(replayed["seq"], 3);
+    assert_eq!(bob_ws.recv_action("resumed").await["last_seq"], 3);
+
+    // actions only exist in their own namespace
+    let id = bob_ws
+        .send("chat.set_status", json!({ "status": "online" }))
+        .await;
+    let error = bob_ws.recv_action("error").await;
+    assert_eq!(error["id"], id);
+    assert_eq!(error["code"], "bad_request");
+
+    // and the socket doesn't speak the legacy protocol
+    let response = app.get_authed("/socket", &bob.token).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn reconnecting_clients_resume_where_they_left_off() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mut alice_ws = app.ws("/ws", &alice).await;
+    let mut bob_ws = app.ws("/ws", &bob).await;
+
+    bob_ws.send("resume", json!({ "last_seq": null })).await;
+    assert_eq!(bob_ws.recv_action("resumed").await["last_seq"], 0);
+
+    alice_ws
+        .send(
+            "new_message",
+            json!({ "message": "first", "chat_partner": "bob" }),
+        )
+        .await;
+    assert_eq!(bob_ws.recv_action("new_chat").await["seq"], 1);
+    let first = bob_ws.recv_action("new_message").await;
+    assert_eq!(first["seq"], 2);
+    alice_ws.recv_action("new_message").await;
+    drop(bob_ws);
+
+    // bob is offline for these
+    alice_ws
+        .send(
+            "new_message",
+            json!({ "message": "second", "chat_id": first["chat_id"] }),
+        )
+        .await;
+    let second = alice_ws.recv_action("new_message").await;
+    alice_ws
+        .send(
+            "edit_message",
+            json!({ "message_id": second["id"], "message": "second!" }),
+        )
+        .await;
+    alice_ws
+        .send("delete_message", json!({ "id": first["id"] }))
+        .await;
+    alice_ws.recv_action("delete").await;
+
+    let mut bob_ws = app.ws("/ws", &bob).await;
+    // live, but it must not overtake the replay
+    alice_ws
+        .send(
+            "new_message",
+            json!({ "message": "third", "chat_id": first["chat_id"] }),
+        )
+        .await;
+    alice_ws.recv_action("new_message").await;
+    bob_ws.send("resume", json!({ "last_seq": 2 })).await;
+
+    let replayed = bob_ws.recv().await;
+    assert_eq!(replayed["action"], "new_message");
+    assert_eq!(replayed["message"], "second");
+    assert_eq!(replayed["seq"], 3);
+    let replayed = bob_ws.recv().await;
+    assert_eq!(replayed["action"], "edit_message");
+    assert_eq!(replayed["seq"], 4);
+    let replayed = bob_ws.recv().await;
+    assert_eq!(replayed["action"], "delete");
+    assert_eq!(replayed["message_id"], first["id"]);
+    assert_eq!(replayed["seq"], 5);
+    let resumed = bob_ws.recv().await;
+    assert_eq!(resumed["action"], "resumed");
+    assert_eq!(resumed["last_seq"], 5);
+    let live = bob_ws.recv().await;
+    assert_eq!(live["message"], "third");
+    assert_eq!(live["seq"], 6);
+
+    // anything older than the log asks for a full refetch
+    sqlx::query("DELETE FROM user_events WHERE username = 'bob' AND seq = 1")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    bob_ws.send("resume", json!({ "last_seq": 0 })).await;
+    assert_eq!(bob_ws.recv_action("resync").await["last_seq"], 5);
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn clients_that_stop_answering_pings_are_dropped() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mut alice_ws = app.ws_versioned("/socket", &alice, 1).await;
+    // never read from, so its pongs never go out
+    let _bob_ws = app.ws("/ws", &bob).await;
+
+    let status = async |user: &TestUser| -> Value {
+        let presence: Vec<Value> = app
+            .get_authed(
+                &format!("/presence?usernames={}", user.username),
+                &user.token,
+            )
+            .await
+            .json()
+            .await
+            .unwrap();
+        presence[0]["status"].clone()
+    };
+    assert_eq!(status(&bob).await, "online");
+
+    let mut dropped = false;
+    for _ in 0..20 {
+        // keeps alice's socket read, and so answering pings
+        alice_ws
+            .send("presence.set_status", json!({ "status": "online" }))
+            .await;
+        alice_ws.recv_action("ack").await;
+        if status(&bob).await == "offline" {
+            dropped = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+    assert!(dropped, "bob was never dropped");
+    assert_eq!(status(&alice).await, "online");
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn events_reach_sockets_on_every_node() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let node = app.peer().await;
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mut alice_ws = app.ws_versioned("/socket", &alice, 1).await;
+    let mut bob_ws = node.ws_versioned("/socket", &bob, 1).await;
+
+    alice_ws
+        .send("friend.send_request", json!({ "receiver_username": "bob" }))
+        .await;
+    let request = bob_ws.recv_action("friend.send_request").await;
+    assert_eq!(request["sender_username"], "alice");
+    bob_ws
+        .send("friend.accept", json!({ "friend_id": request["id"] }))
+        .await;
+    alice_ws.recv_action("friend.accept").await;
+
+    // the new chat reaches bob, and so does everything routed to it after
+    alice_ws
+        .send(
+            "chat.new_message",
+            json!({ "message": "hi bob", "chat_partner": "bob" }),
+        )
+        .await;
+    let chat = bob_ws.recv_action("chat.new_chat").await;
+    let message = bob_ws.recv_action("chat.new_message").await;
+    assert_eq!(message["message"], "hi bob");
+    assert_eq!(message["chat_id"], chat["id"]);
+
+    // too big for a NOTIFY payload
+    let long = "a".repeat(10_000);
+    alice_ws
+        .send(
+            "chat.new_message",
+            json!({ "message": long, "chat_id": chat["id"] }),
+        )
+        .await;
+    assert_eq!(
+        bob_ws.recv_action("chat.new_message").await["message"],
+        long
+    );
+
+    bob_ws
+        .send("presence.set_status", json!({ "status": "away" }))
+        .await;
+    let presence = alice_ws.recv_action("presence.update").await;
+    assert_eq!(presence["username"], "bob");
+    assert_eq!(presence["status"], "away");
+
+    node.cleanup().await;
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn presence_counts_sessions_on_every_node() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let node = app.peer().await;
+    let alice = app.user("alice").await;
+    let bob = app.user("bob").await;
+    let mut alice_ws = app.ws_versioned("/socket", &alice, 1).await;
+    let mut bob_ws = app.ws_versioned("/socket", &bob, 1).await;
+
+    alice_ws
+        .send("friend.send_request", json!({ "receiver_username": "bob" }))
+        .await;
+    let request = bob_ws.recv_action("friend.send_request").await;
+    let id = bob_ws
+        .send("friend.accept", json!({ "friend_id": request["id"] }))
+        .await;
+    assert_eq!(bob_ws.recv_action("ack").await["id"], id);
+    alice_ws.recv_action("friend.accept").await;
+
+    let status = async |app: &TestApp| -> Value {
+        let presence: Vec<Value> = app
+            .get_authed("/presence?usernames=bob", &alice.token)
+            .await
+            .json()
+            .await
+            .unwrap();
+        presence[0].clone()
+    };
+
+    // bob's phone keeps him online while his laptop is idle
+    let bob_phone = node.ws_versioned("/socket", &bob, 1).await;
+    let id = bob_ws
+        .send("presence.set_status", json!({ "status": "away" }))
+        .await;
+    assert_eq!(bob_ws.recv_action("ack").await["id"], id);
+    assert_eq!(status(&app).await["status"], "online");
+    assert_eq!(status(&node).await["status"], "online");
+    alice_ws.expect_silence(Duration::from_millis(300)).await;
+
+    drop(bob_phone);
+    let presence = alice_ws.recv_action("presence.update").await;
+    assert_eq!(presence["status"], "away");
+    assert_eq!(status(&node).await["status"], "away");
+
+    drop(bob_ws);
+    let presence = alice_ws.recv_action("presence.update").await;
+    assert_eq!(presence["status"], "offline");
+    assert!(presence["last_seen"].is_string());
+    assert_eq!(status(&node).await["status"], "offline");
+
+    node.cleanup().await;
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn revoked_logins_lose_their_sockets() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let login = async || {
+        let response = app.login(&alice.email, PASSWORD).await;
+        let device = TestUser {
+            username: alice.username.clone(),
+            email: alice.email.clone(),
+            token: cookie(&response, "token").unwrap(),
+        };
+        (device, cookie(&response, "refresh_token").unwrap())
+    };
+    let mut alice_ws = app.ws("/ws", &alice).await;
+    // reading makes it answer pings, logging in takes a while
+    let still_connected = async |ws: &mut WsClient| {
+        ws.send("resume", json!({ "last_seq": null })).await;
+        ws.recv_action("resumed").await;
+    };
+
+    let (phone, _) = login().await;
+    let mut phone_ws = app.ws_versioned("/socket", &phone, 1).await;
+    let sessions: Vec<Value> = app
+        .get_authed("/sessions", &phone.token)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let current = sessions.iter().find(|s| s["current"] == true).unwrap();
+    let response = app
+        .delete_authed(
+            &format!("/sessions/{}", current["id"]),
+            &format!("token={}", alice.token),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    phone_ws.expect_closed().await;
+    still_connected(&mut alice_ws).await;
+
+    let (laptop, refresh_token) = login().await;
+    let mut laptop_ws = app.ws("/ws", &laptop).await;
+    let response = app
+        .delete_authed("/logout", &format!("refresh_token={}", refresh_token))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    laptop_ws.expect_closed().await;
+
+    // the first login is still connected
+    still_connected(&mut alice_ws).await;
+
+    app.cleanup().await;
+}
+
+#[actix_rt::test]
+async fn clients_resync_when_the_bus_reconnects() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.user("alice").await;
+    let mut alice_ws = app.ws_versioned("/socket", &alice, 1).await;
+    alice_ws
+        .send("chat.resume", json!({ "last_seq": null }))
+        .await;
+    alice_ws.recv_action("resumed").await;
+
+    // whatever was published until the listener is back never reaches it
+    sqlx::query(
+        r#"
+        SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+        WHERE datname = current_database() AND query LIKE 'LISTEN%'
+        "#,
+    )
+    .execute(&app.pool)
+    .await
+    .unwrap();
+    assert_eq!(alice_ws.recv_action("resync").await["last_seq"], 0);
+
+    app.cleanup().await;
+}